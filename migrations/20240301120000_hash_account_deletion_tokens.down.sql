@@ -0,0 +1,15 @@
+-- Irreversible once tokens have been hashed: the raw tokens can't be recovered from their
+-- hash, and servers before this migration would never match them. Rather than leaving hashes
+-- in the restored id column, the migration fails while such rows exist.
+do $$
+begin
+    if exists (select 1 from account_deletions where length(token_hash) <> 150) then
+        raise exception 'account_deletions holds hashed cancel tokens, which cannot be restored'
+            using hint = 'delete these rows and reset users.requested_deletion of their '
+                'accounts first, which cancels the pending deletions';
+    end if;
+end
+$$;
+
+alter table account_deletions alter column token_hash type varchar(150);
+alter table account_deletions rename column token_hash to id;
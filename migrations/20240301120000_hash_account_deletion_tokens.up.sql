@@ -0,0 +1,5 @@
+-- Cancel tokens are now stored as a keyed hash (hex HMAC-SHA256, 64 characters).
+-- Rows created before this migration still hold the raw 150 characters token and are
+-- rehashed by the server at startup since the hashing key isn't available here.
+alter table account_deletions rename column id to token_hash;
+alter table account_deletions alter column token_hash type text;
@@ -1,37 +1,50 @@
+use crate::app_error::{RequestError, PROBLEM_JSON};
 use crate::logic::{AuthError, CreateUserError, FieldValidationError, UpdateUserError};
 use crate::session::UserSessionError;
 use actix_web::body::BoxBody;
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
 use anyhow::anyhow;
-use serde::Serialize;
+use std::backtrace::Backtrace;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
+use std::panic::Location;
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "snake_case")]
+#[derive(Debug)]
 pub enum AppErrorType {
     ValidationError(FieldValidationError),
     RegistrationError(CreateUserError),
     UpdateUserError(UpdateUserError),
     AuthError(AuthError),
     SessionError(UserSessionError),
+    RequestError(RequestError),
     Unknown(()),
 }
 
 pub struct AppError {
     pub error_type: AppErrorType,
     pub msg: Option<anyhow::Error>,
-    //todo: add trace to app_error ?
+    /// Where the error was converted into an `AppError`, usually a `?`
+    pub location: &'static Location<'static>,
+    /// Only captured for unexpected errors, the ones with a `msg`
+    pub backtrace: Option<Backtrace>,
 }
 
 impl AppError {
-    pub fn with_msg(msg: String) -> Self {
+    #[track_caller]
+    fn new(error_type: AppErrorType, msg: Option<anyhow::Error>) -> Self {
         Self {
-            error_type: AppErrorType::Unknown(()),
-            msg: Some(anyhow!(msg)),
+            error_type,
+            backtrace: msg.as_ref().map(|_| Backtrace::force_capture()),
+            msg,
+            location: Location::caller(),
         }
     }
+
+    #[track_caller]
+    pub fn with_msg(msg: String) -> Self {
+        Self::new(AppErrorType::Unknown(()), Some(anyhow!(msg)))
+    }
 }
 
 impl Debug for AppError {
@@ -39,6 +52,7 @@ impl Debug for AppError {
         f.debug_struct("AppError")
             .field("error_type", &self.error_type)
             .field("msg", &self.msg)
+            .field("location", &self.location)
             .finish()
     }
 }
@@ -50,8 +64,15 @@ impl Display for AppError {
 }
 
 impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        self.error_type.status_code()
+    }
+
+    /// Problem body without correlation id, which `report_errors` adds
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::build(StatusCode::BAD_REQUEST).json(&self.error_type)
+        HttpResponse::build(self.status_code())
+            .content_type(PROBLEM_JSON)
+            .json(self.problem(None))
     }
 }
 
@@ -59,20 +80,16 @@ impl<E> From<E> for AppError
 where
     E: Into<anyhow::Error> + Debug,
 {
+    #[track_caller]
     fn from(error: E) -> Self {
-        Self {
-            error_type: AppErrorType::Unknown(()),
-            msg: Some(error.into()),
-        }
+        Self::new(AppErrorType::Unknown(()), Some(error.into()))
     }
 }
 
 impl From<AppErrorType> for AppError {
+    #[track_caller]
     fn from(error_type: AppErrorType) -> Self {
-        Self {
-            error_type,
-            msg: None,
-        }
+        Self::new(error_type, None)
     }
 }
 
@@ -92,46 +109,43 @@ impl<E> From<E> for AppError where E: ToAppError {
 */
 
 impl From<FieldValidationError> for AppError {
+    #[track_caller]
     fn from(error: FieldValidationError) -> Self {
-        Self {
-            error_type: AppErrorType::ValidationError(error),
-            msg: None,
-        }
+        Self::new(AppErrorType::ValidationError(error), None)
     }
 }
 
 impl From<CreateUserError> for AppError {
+    #[track_caller]
     fn from(error: CreateUserError) -> Self {
-        Self {
-            error_type: AppErrorType::RegistrationError(error),
-            msg: None,
-        }
+        Self::new(AppErrorType::RegistrationError(error), None)
     }
 }
 
 impl From<UpdateUserError> for AppError {
+    #[track_caller]
     fn from(error: UpdateUserError) -> Self {
-        Self {
-            error_type: AppErrorType::UpdateUserError(error),
-            msg: None,
-        }
+        Self::new(AppErrorType::UpdateUserError(error), None)
     }
 }
 
 impl From<UserSessionError> for AppError {
+    #[track_caller]
     fn from(error: UserSessionError) -> Self {
-        Self {
-            error_type: AppErrorType::SessionError(error),
-            msg: None,
-        }
+        Self::new(AppErrorType::SessionError(error), None)
     }
 }
 
 impl From<AuthError> for AppError {
+    #[track_caller]
     fn from(error: AuthError) -> Self {
-        Self {
-            error_type: AppErrorType::AuthError(error),
-            msg: None,
-        }
+        Self::new(AppErrorType::AuthError(error), None)
+    }
+}
+
+impl From<RequestError> for AppError {
+    #[track_caller]
+    fn from(error: RequestError) -> Self {
+        Self::new(AppErrorType::RequestError(error), None)
     }
 }
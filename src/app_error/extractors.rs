@@ -0,0 +1,44 @@
+use crate::app_error::AppError;
+use actix_web::error::{JsonPayloadError, UrlencodedError};
+use actix_web::web::{FormConfig, JsonConfig, QueryConfig};
+
+/// Request rejected by an extractor, before reaching the handler
+#[derive(Debug)]
+pub enum RequestError {
+    /// Body which couldn't be read or deserialized
+    InvalidBody,
+    InvalidQuery,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+}
+
+/// Json bodies rejected with a problem body like the other errors
+pub fn json_config() -> JsonConfig {
+    JsonConfig::default().error_handler(|err, _| {
+        let error = match err {
+            JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+                RequestError::PayloadTooLarge
+            }
+            JsonPayloadError::ContentType => RequestError::UnsupportedMediaType,
+            _ => RequestError::InvalidBody,
+        };
+        AppError::from(error).into()
+    })
+}
+
+/// Url encoded bodies rejected with a problem body like the other errors
+pub fn form_config() -> FormConfig {
+    FormConfig::default().error_handler(|err, _| {
+        let error = match err {
+            UrlencodedError::Overflow { .. } => RequestError::PayloadTooLarge,
+            UrlencodedError::ContentType => RequestError::UnsupportedMediaType,
+            _ => RequestError::InvalidBody,
+        };
+        AppError::from(error).into()
+    })
+}
+
+/// Query strings rejected with a problem body like the other errors
+pub fn query_config() -> QueryConfig {
+    QueryConfig::default().error_handler(|_, _| AppError::from(RequestError::InvalidQuery).into())
+}
@@ -1,5 +1,11 @@
 mod error;
 mod error_propagation;
+mod extractors;
+mod problem;
+mod report;
 
 pub use error::*;
 pub use error_propagation::*;
+pub use extractors::*;
+pub use problem::*;
+pub use report::*;
@@ -0,0 +1,240 @@
+use crate::app_error::{AppError, AppErrorType, RequestError};
+use crate::logic::{
+    AuthError, CaptchaChallenge, CreateUserError, FieldValidationError, UpdateUserError,
+};
+use crate::session::UserSessionError;
+use actix_web::http::StatusCode;
+use serde::Serialize;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// RFC 7807 body of the error responses. `code` is stable and meant for clients, `detail` is
+/// meant for users. The message of the underlying error is never sent.
+#[derive(Serialize)]
+pub struct Problem<'a> {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub code: &'static str,
+    pub detail: &'static str,
+    /// Invalid form fields
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldProblem>,
+    /// Challenge to solve on the next login attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captcha: Option<&'a CaptchaChallenge>,
+    /// Id of the request in the server logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FieldProblem {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub detail: &'static str,
+}
+
+impl AppError {
+    pub fn problem(&self, correlation_id: Option<String>) -> Problem<'_> {
+        let status = self.error_type.status_code();
+        let (code, detail) = self.error_type.description();
+
+        Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            code,
+            detail,
+            errors: self
+                .error_type
+                .field()
+                .map(|field| FieldProblem {
+                    field,
+                    code,
+                    detail,
+                })
+                .into_iter()
+                .collect(),
+            captcha: match &self.error_type {
+                AppErrorType::AuthError(AuthError::CaptchaRequired(challenge)) => Some(challenge),
+                _ => None,
+            },
+            correlation_id,
+        }
+    }
+}
+
+impl Problem<'_> {
+    /// Body of the error responses which don't come from an `AppError` (unknown routes,
+    /// rate limited requests...), described by their status only
+    pub fn from_status(status: StatusCode, correlation_id: Option<String>) -> Self {
+        let (code, detail) = match status {
+            StatusCode::BAD_REQUEST => ("bad_request", "The request is invalid"),
+            StatusCode::UNAUTHORIZED => ("unauthorized", "Please log in to continue"),
+            StatusCode::FORBIDDEN => ("forbidden", "The request isn't allowed"),
+            StatusCode::NOT_FOUND => ("not_found", "The requested resource doesn't exist"),
+            StatusCode::METHOD_NOT_ALLOWED => (
+                "method_not_allowed",
+                "The method isn't allowed on this resource",
+            ),
+            StatusCode::CONFLICT => (
+                "conflict",
+                "The request conflicts with the state of the resource",
+            ),
+            StatusCode::PAYLOAD_TOO_LARGE => ("payload_too_large", "The request body is too large"),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => (
+                "unsupported_media_type",
+                "The request body format isn't supported",
+            ),
+            StatusCode::TOO_MANY_REQUESTS => {
+                ("too_many_requests", "Too many requests, please retry later")
+            }
+            s if s.is_client_error() => ("client_error", "The request couldn't be processed"),
+            _ => ("internal_error", "An unexpected error happened"),
+        };
+
+        Self {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            code,
+            detail,
+            errors: Vec::new(),
+            captcha: None,
+            correlation_id,
+        }
+    }
+}
+
+impl AppErrorType {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::ValidationError(FieldValidationError::EmailTaken)
+            | Self::ValidationError(FieldValidationError::UsernameTaken)
+            | Self::UpdateUserError(UpdateUserError::EmailTaken)
+            | Self::UpdateUserError(UpdateUserError::UsernameTaken) => StatusCode::CONFLICT,
+            Self::ValidationError(_) | Self::UpdateUserError(_) => StatusCode::BAD_REQUEST,
+            Self::AuthError(AuthError::TooManyAttempts) => StatusCode::TOO_MANY_REQUESTS,
+            Self::AuthError(_) | Self::SessionError(_) => StatusCode::UNAUTHORIZED,
+            Self::RequestError(RequestError::PayloadTooLarge) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RequestError(RequestError::UnsupportedMediaType) => {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            }
+            Self::RequestError(_) => StatusCode::BAD_REQUEST,
+            Self::RegistrationError(_) | Self::Unknown(()) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable code and user message
+    fn description(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::ValidationError(e) => match e {
+                FieldValidationError::EmailTaken => {
+                    ("email_taken", "This email address is already used")
+                }
+                FieldValidationError::UsernameTaken => {
+                    ("username_taken", "This username is already used")
+                }
+                FieldValidationError::InvalidPasswordFmt => (
+                    "invalid_password_fmt",
+                    "The password doesn't meet the requirements",
+                ),
+                FieldValidationError::InvalidUsernameFmt => (
+                    "invalid_username_fmt",
+                    "The username doesn't meet the requirements",
+                ),
+                FieldValidationError::InvalidCaptchaID => {
+                    ("invalid_captcha_id", "The captcha expired, please retry")
+                }
+                FieldValidationError::InvalidCaptchaAnswer => {
+                    ("invalid_captcha_answer", "The captcha answer is wrong")
+                }
+                FieldValidationError::InvalidEmailFmt => {
+                    ("invalid_email_fmt", "The email address is invalid")
+                }
+                FieldValidationError::InvalidUrlToken => {
+                    ("invalid_url_token", "The link is invalid or expired")
+                }
+                FieldValidationError::NotABee => ("not_a_bee", "The form is invalid"),
+            },
+            Self::RegistrationError(e) => match e {
+                CreateUserError::CaptchaGeneration => {
+                    ("captcha_generation", "The captcha couldn't be generated")
+                }
+            },
+            Self::UpdateUserError(e) => match e {
+                UpdateUserError::InvalidForm => ("invalid_form", "The form is invalid"),
+                UpdateUserError::InvalidConfirmationSentence => (
+                    "invalid_confirmation_sentence",
+                    "The confirmation sentence doesn't match",
+                ),
+                UpdateUserError::UsernameTaken => {
+                    ("username_taken", "This username is already used")
+                }
+                UpdateUserError::EmailTaken => {
+                    ("email_taken", "This email address is already used")
+                }
+                UpdateUserError::InvalidPassword => ("invalid_password", "The password is wrong"),
+            },
+            Self::AuthError(e) => match e {
+                AuthError::InvalidCredentials => {
+                    ("invalid_credentials", "The email or the password is wrong")
+                }
+                AuthError::InvalidPassword => ("invalid_password", "The password is wrong"),
+                AuthError::TooManyAttempts => (
+                    "too_many_attempts",
+                    "Too many failed attempts, please retry later",
+                ),
+                AuthError::CaptchaRequired(_) => {
+                    ("captcha_required", "Please solve the captcha to continue")
+                }
+            },
+            Self::SessionError(e) => match e {
+                UserSessionError::InvalidSessionCookie => {
+                    ("invalid_session_cookie", "Please log in to continue")
+                }
+            },
+            Self::RequestError(e) => match e {
+                RequestError::InvalidBody => ("invalid_body", "The request body is invalid"),
+                RequestError::InvalidQuery => ("invalid_query", "The query parameters are invalid"),
+                RequestError::PayloadTooLarge => {
+                    ("payload_too_large", "The request body is too large")
+                }
+                RequestError::UnsupportedMediaType => (
+                    "unsupported_media_type",
+                    "The request body format isn't supported",
+                ),
+            },
+            Self::Unknown(()) => ("internal_error", "An unexpected error happened"),
+        }
+    }
+
+    /// Form field the error is about
+    fn field(&self) -> Option<&'static str> {
+        match self {
+            Self::ValidationError(e) => match e {
+                FieldValidationError::EmailTaken | FieldValidationError::InvalidEmailFmt => {
+                    Some("email")
+                }
+                FieldValidationError::UsernameTaken | FieldValidationError::InvalidUsernameFmt => {
+                    Some("username")
+                }
+                FieldValidationError::InvalidPasswordFmt => Some("password"),
+                FieldValidationError::InvalidCaptchaID => Some("captcha_id"),
+                FieldValidationError::InvalidCaptchaAnswer => Some("captcha_answer"),
+                FieldValidationError::InvalidUrlToken => Some("token"),
+                FieldValidationError::NotABee => None,
+            },
+            Self::UpdateUserError(e) => match e {
+                UpdateUserError::InvalidForm => None,
+                UpdateUserError::InvalidConfirmationSentence => Some("confirmation_sentence"),
+                UpdateUserError::UsernameTaken => Some("username"),
+                UpdateUserError::EmailTaken => Some("email"),
+                UpdateUserError::InvalidPassword => Some("password"),
+            },
+            _ => None,
+        }
+    }
+}
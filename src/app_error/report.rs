@@ -0,0 +1,104 @@
+use crate::app_error::{AppError, Problem, PROBLEM_JSON};
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::{Error, HttpMessage};
+use actix_web_lab::middleware::Next;
+use tracing_actix_web::RequestId;
+use uuid::Uuid;
+
+pub const CORRELATION_ID_HEADER: HeaderName = HeaderName::from_static("x-correlation-id");
+
+/// Middleware giving every error response the id of its request, which every log line of the
+/// request carries, in the `x-correlation-id` header. `AppError`s are logged under that id,
+/// and their problem body includes it. Other errors (unknown routes, rate limited requests,
+/// actix errors) get a problem body describing their status, unless the handler gave them a
+/// body of its own.
+pub async fn report_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let correlation_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(ToString::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    if !res.status().is_client_error() && !res.status().is_server_error() {
+        return Ok(res);
+    }
+
+    res.headers_mut().insert(
+        CORRELATION_ID_HEADER,
+        HeaderValue::from_str(&correlation_id)?,
+    );
+    let error = res.response().error();
+    let problem = match error.and_then(|e| e.as_error::<AppError>()) {
+        Some(e) => {
+            e.report(&correlation_id);
+            serde_json::to_vec(&e.problem(Some(correlation_id))).ok()
+        }
+        None if error.is_some()
+            || matches!(
+                res.response().body().size(),
+                BodySize::None | BodySize::Sized(0)
+            ) =>
+        {
+            if let Some(e) = error {
+                tracing::info!(
+                    correlation_id,
+                    error.status = res.status().as_u16(),
+                    error.message = %e,
+                    "request rejected",
+                );
+            }
+            let problem = Problem::from_status(res.status(), Some(correlation_id));
+            serde_json::to_vec(&problem).ok()
+        }
+        None => None,
+    };
+
+    Ok(match problem {
+        Some(body) => {
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+            res.map_body(|_, _| BoxBody::new(body))
+        }
+        None => res,
+    })
+}
+
+impl AppError {
+    /// Log the error in the current (request) span. Unexpected errors are logged with their
+    /// chain of causes and backtrace.
+    pub fn report(&self, correlation_id: &str) {
+        let status = self.error_type.status_code().as_u16();
+        match &self.msg {
+            Some(msg) => {
+                let backtrace = self.backtrace.as_ref().map(ToString::to_string);
+                tracing::error!(
+                    correlation_id,
+                    error.status = status,
+                    error.location = %self.location,
+                    error.chain = %format!("{msg:#}"),
+                    error.backtrace = backtrace.unwrap_or_default(),
+                    "request failed with an unexpected error",
+                );
+            }
+            None if status >= 500 => tracing::error!(
+                correlation_id,
+                error.status = status,
+                error.location = %self.location,
+                error.error_type = ?self.error_type,
+                "request failed",
+            ),
+            None => tracing::info!(
+                correlation_id,
+                error.status = status,
+                error.location = %self.location,
+                error.error_type = ?self.error_type,
+                "request rejected",
+            ),
+        }
+    }
+}
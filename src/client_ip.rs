@@ -0,0 +1,115 @@
+use actix_web::http::header::{HeaderMap, HeaderName, FORWARDED, X_FORWARDED_FOR};
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use anyhow::anyhow;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Proxies allowed to forward the client ip, e.g. the load balancer.
+///
+/// When a request comes from a trusted proxy, the forwarded addresses (`Forwarded`, or else
+/// `X-Forwarded-For`, or else `X-Real-IP`) are walked from the closest hop, and the first
+/// address which isn't a trusted proxy is the client ip. Headers sent by other peers are
+/// ignored, they can be forged.
+#[derive(Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// CIDRs or single addresses
+    pub fn new(proxies: &[String]) -> anyhow::Result<Self> {
+        proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| anyhow!("Invalid trusted proxy {proxy}"))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer;
+        for hop in forwarded_chain(headers).iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            // Obfuscated or unknown hops can't be walked past
+            match parse_hop(hop) {
+                Some(ip) => client = ip,
+                None => break,
+            }
+        }
+
+        client
+    }
+}
+
+/// Forwarded addresses, from the client to the closest hop
+fn forwarded_chain(headers: &HeaderMap) -> Vec<String> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded: Vec<String> = values(FORWARDED)
+        .into_iter()
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for")
+                    .then(|| value.trim_matches('"').to_owned())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    let x_forwarded_for = values(X_FORWARDED_FOR);
+    if !x_forwarded_for.is_empty() {
+        return x_forwarded_for.into_iter().map(Into::into).collect();
+    }
+
+    values(HeaderName::from_static("x-real-ip"))
+        .into_iter()
+        .take(1)
+        .map(Into::into)
+        .collect()
+}
+
+/// `ip`, `ip:port`, `[ipv6]` or `[ipv6]:port`
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            hop.strip_prefix('[')?
+                .split_once(']')?
+                .0
+                .parse::<IpAddr>()
+                .ok()
+        })
+}
+
+/// Client ip of the request, forwarded by the `TrustedProxies` registered in the app data
+pub fn client_ip(req: &HttpRequest) -> String {
+    let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else {
+        return "unknown".into();
+    };
+
+    match req.app_data::<Data<TrustedProxies>>() {
+        Some(proxies) => proxies.client_ip(peer, req.headers()),
+        None => peer,
+    }
+    .to_string()
+}
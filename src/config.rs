@@ -1,26 +1,353 @@
-use config::{Config, ConfigError, File, FileFormat};
-use secrecy::Secret;
+use crate::client_ip::TrustedProxies;
+use config::{Config, ConfigError, Environment, File, FileFormat, Map, Value, ValueKind};
+use reqwest::Url;
+use secrecy::{ExposeSecret, Secret};
 use serde::Deserialize;
+use std::{fs, io};
 
 #[derive(Clone, Deserialize)]
 pub struct Settings {
     pub application_host: String,
     pub application_port: u16,
+    /// Url the clients reach the server at, e.g. behind a proxy
+    pub public_url: String,
+    /// CIDRs of the proxies allowed to forward the client ip (see `TrustedProxies`)
+    pub trusted_proxies: Vec<String>,
+    pub tls: TlsSettings,
+    pub metrics: MetricsSettings,
+    pub tracing: TracingSettings,
     pub postgres: PostgresSettings,
     pub redis: RedisSettings,
-    pub task1_email_confirm: Task1Settings,
+    pub task1_tokens: TokenTask1Settings,
     pub task1_captcha: Task1Settings,
+    pub task_supervisor: TaskSupervisorSettings,
+    pub leader_election: LeaderElectionSettings,
+    pub shutdown: ShutdownSettings,
+    pub session: SessionSettings,
+    pub captcha: CaptchaSettings,
+    pub login_throttle: LoginThrottleSettings,
+    pub rate_limits: Vec<RateLimitSettings>,
+    pub security: SecuritySettings,
 }
 
 impl Settings {
-    pub fn new(file_path: &str) -> Result<Self, ConfigError> {
-        Config::builder()
-            .add_source(File::new(file_path, FileFormat::Yaml))
-            .build()?
-            .try_deserialize()
+    pub const CONFIG_DIR: &'static str = "config";
+
+    /// Load the settings of the environment selected by `APP_ENV` (dev by default)
+    pub fn from_env() -> Result<Self, SettingsError> {
+        let environment = std::env::var("APP_ENV").unwrap_or_else(|_| "dev".into());
+        Self::new(&environment)
+    }
+
+    /// `config/base.yaml`, overridden by `config/{environment}.yaml` and then by the
+    /// `APP__<SECTION>__<KEY>` environment variables
+    pub fn new(environment: &str) -> Result<Self, SettingsError> {
+        Self::load(Self::CONFIG_DIR, environment, None)
+    }
+
+    /// `env_vars` replaces the process environment when given
+    pub fn load(
+        config_dir: &str,
+        environment: &str,
+        env_vars: Option<Map<String, String>>,
+    ) -> Result<Self, SettingsError> {
+        let mut builder = Config::builder()
+            .add_source(File::new(&format!("{config_dir}/base"), FileFormat::Yaml))
+            .add_source(File::new(
+                &format!("{config_dir}/{environment}"),
+                FileFormat::Yaml,
+            ))
+            .add_source(
+                Environment::with_prefix("APP")
+                    .separator("__")
+                    .source(env_vars),
+            );
+
+        // `<key>_file` entries are replaced by the content of the file
+        let table = builder.build_cloned()?.try_deserialize()?;
+        for (key, path) in secret_files(table, "") {
+            let secret = fs::read_to_string(&path).map_err(|err| SettingsError::SecretFile {
+                key: key.clone(),
+                path,
+                err,
+            })?;
+            builder = builder.set_override(key, secret.trim_end())?;
+        }
+
+        let settings: Self = builder.build()?.try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Report every invalid setting at once
+    fn validate(&self) -> Result<(), SettingsError> {
+        let mut problems = Vec::new();
+        let mut check = |valid: bool, problem: String| {
+            if !valid {
+                problems.push(problem)
+            }
+        };
+
+        check(
+            self.application_port != 0,
+            "application_port: must not be 0".into(),
+        );
+        for proxy in &self.trusted_proxies {
+            check(
+                TrustedProxies::new(std::slice::from_ref(proxy)).is_ok(),
+                format!("trusted_proxies: {proxy} is not a valid CIDR or ip address"),
+            );
+        }
+        check(
+            is_url(&self.public_url, &["http", "https"]),
+            "public_url: must be an http(s) url".into(),
+        );
+        check(
+            self.tls.redirect_port.is_none() || self.tls.enabled,
+            "tls.redirect_port: requires tls to be enabled".into(),
+        );
+        check(
+            self.tls.redirect_port != Some(self.application_port),
+            "tls.redirect_port: must differ from application_port".into(),
+        );
+        check(
+            self.metrics.admin_port.is_none_or(|port| {
+                port != self.application_port && Some(port) != self.tls.redirect_port
+            }),
+            "metrics.admin_port: must differ from the other listeners".into(),
+        );
+        check(
+            self.tracing
+                .otlp_endpoint
+                .as_ref()
+                .is_none_or(|endpoint| is_url(endpoint, &["http", "https"])),
+            "tracing.otlp_endpoint: must be an http(s) url".into(),
+        );
+        check(
+            !self.tracing.service_name.is_empty(),
+            "tracing.service_name: must not be empty".into(),
+        );
+        check(
+            self.tls.watch_interval > 0,
+            "tls.watch_interval: must be positive".into(),
+        );
+        check(
+            self.tls.client_auth == ClientAuth::None || self.tls.client_ca_path.is_some(),
+            "tls.client_ca_path: required to verify client certificates".into(),
+        );
+        check(
+            is_url(
+                self.postgres.url.expose_secret(),
+                &["postgres", "postgresql"],
+            ),
+            "postgres.url: must be a postgres:// url".into(),
+        );
+        check(
+            is_url(&self.redis.url, &["redis", "rediss"]),
+            "redis.url: must be a redis:// url".into(),
+        );
+
+        let tokens = &self.task1_tokens;
+        for (name, task1) in [
+            ("task1_tokens.registration", &tokens.registration),
+            ("task1_tokens.password_reset", &tokens.password_reset),
+            ("task1_tokens.email_change", &tokens.email_change),
+            (
+                "task1_tokens.deletion_confirmation",
+                &tokens.deletion_confirmation,
+            ),
+            ("task1_captcha", &self.task1_captcha),
+        ] {
+            check(
+                task1.expiry_time > 0,
+                format!("{name}.expiry_time: must be positive"),
+            );
+            check(
+                task1.deletion_bulk_count > 0,
+                format!("{name}.deletion_bulk_count: must be positive"),
+            );
+        }
+
+        check(
+            self.task_supervisor.initial_backoff <= self.task_supervisor.max_backoff,
+            "task_supervisor.max_backoff: must not be lower than initial_backoff".into(),
+        );
+        check(
+            (1..self.leader_election.lease_ttl).contains(&self.leader_election.renew_interval),
+            "leader_election.renew_interval: must be positive and lower than lease_ttl".into(),
+        );
+        check(
+            self.shutdown.drain_timeout > 0,
+            "shutdown.drain_timeout: must be positive".into(),
+        );
+
+        let captcha = &self.captcha;
+        check(
+            captcha.max_failed_attempts > 0,
+            "captcha.max_failed_attempts: must be positive".into(),
+        );
+        check(
+            (1..=captcha.image.max_length).contains(&captcha.image.min_length),
+            "captcha.image.min_length: must be positive and not greater than max_length".into(),
+        );
+        check(
+            (1..=32).contains(&captcha.proof_of_work_difficulty),
+            "captcha.proof_of_work_difficulty: must be between 1 and 32".into(),
+        );
+        match &captcha.remote {
+            Some(remote) => {
+                check(
+                    is_url(&remote.verify_url, &["http", "https"]),
+                    "captcha.remote.verify_url: must be an http(s) url".into(),
+                );
+                check(
+                    is_url(&remote.script_url, &["http", "https"]),
+                    "captcha.remote.script_url: must be an http(s) url".into(),
+                );
+            }
+            None => check(
+                captcha.provider != CaptchaProviderKind::Remote,
+                "captcha.remote: required by the remote provider".into(),
+            ),
+        }
+
+        let throttle = &self.login_throttle;
+        check(
+            throttle.failure_window > 0,
+            "login_throttle.failure_window: must be positive".into(),
+        );
+        check(
+            throttle.lockout_duration > 0,
+            "login_throttle.lockout_duration: must be positive".into(),
+        );
+        check(
+            throttle.backoff_base_delay <= throttle.backoff_max_delay,
+            "login_throttle.backoff_max_delay: must not be lower than backoff_base_delay".into(),
+        );
+        for group in &self.rate_limits {
+            let name = &group.name;
+            check(
+                !group.paths.is_empty() && group.paths.iter().all(|path| path.starts_with('/')),
+                format!("rate_limits.{name}.paths: must be a non empty list of absolute paths"),
+            );
+            check(
+                group.limit > 0,
+                format!("rate_limits.{name}.limit: must be positive"),
+            );
+            check(
+                group.ip_limit.is_some() || group.key != RateLimitKey::Token,
+                format!("rate_limits.{name}.ip_limit: required to limit tokens per ip"),
+            );
+            check(
+                group.ip_limit != Some(0),
+                format!("rate_limits.{name}.ip_limit: must be positive"),
+            );
+            check(
+                group.window > 0,
+                format!("rate_limits.{name}.window: must be positive"),
+            );
+        }
+        check(
+            !self.session.keys.expose_secret().trim().is_empty(),
+            "session.keys: must not be empty".into(),
+        );
+        check(
+            self.security.token_secret.expose_secret().len() >= 32,
+            "security.token_secret: must be at least 32 bytes long".into(),
+        );
+
+        if !problems.is_empty() {
+            Err(SettingsError::Invalid(problems))?;
+        }
+        Ok(())
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum SettingsError {
+    #[error("Loading the configuration failed")]
+    Load(#[from] ConfigError),
+    #[error("Reading the {key:} secret from {path:} failed")]
+    SecretFile {
+        key: String,
+        path: String,
+        #[source]
+        err: io::Error,
+    },
+    #[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+    Invalid(Vec<String>),
+}
+
+/// (key, path) of the `<key>_file` entries
+fn secret_files(table: Map<String, Value>, prefix: &str) -> Vec<(String, String)> {
+    let mut files = Vec::new();
+    for (key, value) in table {
+        let key = format!("{prefix}{key}");
+        match value.kind {
+            ValueKind::Table(table) => files.extend(secret_files(table, &format!("{key}."))),
+            ValueKind::String(path) => {
+                if let Some(key) = key.strip_suffix("_file") {
+                    files.push((key.into(), path));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    files
+}
+
+fn is_url(url: &str, schemes: &[&str]) -> bool {
+    Url::parse(url).is_ok_and(|url| schemes.contains(&url.scheme()))
+}
+
+/// Server certificate and client certificate verification (see `CertificateReloader`)
+#[derive(Clone, Deserialize)]
+pub struct TlsSettings {
+    /// Serve plain http when disabled, behind a proxy terminating TLS
+    pub enabled: bool,
+    /// Port of the plain http listener redirecting to `public_url`, none by default
+    pub redirect_port: Option<u16>,
+    /// `Strict-Transport-Security` max-age in seconds, 0 disables the header
+    pub hsts_max_age: u64,
+    /// PEM certificate chain
+    pub cert_path: String,
+    /// PEM private key (PKCS#8, RSA or SEC1)
+    pub key_path: String,
+    /// Seconds between checks of the certificate files for changes
+    pub watch_interval: u64,
+    pub client_auth: ClientAuth,
+    /// PEM bundle of the CAs issuing client certificates
+    pub client_ca_path: Option<String>,
+}
+
+/// Verification of the client certificates, for service-to-service callers
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    None,
+    /// Clients may connect without a certificate, certificates presented must be valid
+    Optional,
+    /// Clients must present a valid certificate
+    Required,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct MetricsSettings {
+    /// Port of the plain http listener serving `/metrics`, which is served by the main
+    /// listener otherwise
+    pub admin_port: Option<u16>,
+}
+
+/// Export of the spans to an OpenTelemetry collector, alongside the JSON logs
+#[derive(Clone, Deserialize)]
+pub struct TracingSettings {
+    /// OTLP/gRPC endpoint of the collector, spans aren't exported when none
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` of the exported spans
+    pub service_name: String,
+}
+
 #[derive(Clone, Deserialize)]
 pub struct PostgresSettings {
     pub url: Secret<String>,
@@ -32,8 +359,189 @@ pub struct RedisSettings {
     pub password: Secret<String>,
 }
 
+/// Expiry and cleanup of the emailed tokens, one entry per token purpose
+#[derive(Clone, Deserialize)]
+pub struct TokenTask1Settings {
+    pub registration: Task1Settings,
+    pub password_reset: Task1Settings,
+    pub email_change: Task1Settings,
+    pub deletion_confirmation: Task1Settings,
+}
+
 #[derive(Clone, Deserialize)]
 pub struct Task1Settings {
     pub expiry_time: u64,
     pub deletion_bulk_count: usize,
 }
+
+/// Restart policy of the background tasks (durations in seconds)
+#[derive(Clone, Deserialize)]
+pub struct TaskSupervisorSettings {
+    pub max_restarts: u32,
+    pub initial_backoff: u64,
+    pub max_backoff: u64,
+}
+
+/// Redis lease electing the instance running the background tasks (durations in seconds)
+#[derive(Clone, Deserialize)]
+pub struct LeaderElectionSettings {
+    pub lease_ttl: u64,
+    pub renew_interval: u64,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ShutdownSettings {
+    /// Seconds given to in-flight requests and background tasks to finish on shutdown
+    pub drain_timeout: u64,
+}
+
+/// Keys encrypting the session cookies (see `SessionKeyRing`)
+#[derive(Clone, Deserialize)]
+pub struct SessionSettings {
+    /// One `<creation timestamp> <base64 key>` entry per line, newest first
+    pub keys: Secret<String>,
+    /// Seconds during which cookies encrypted with a replaced key are still accepted
+    pub rotation_window: u64,
+}
+
+/// Requests allowed per time window on a group of routes (see `RateLimiter`)
+#[derive(Clone, Deserialize)]
+pub struct RateLimitSettings {
+    pub name: String,
+    /// Path prefixes of the routes, the first group matching a request applies
+    pub paths: Vec<String>,
+    pub key: RateLimitKey,
+    pub limit: u64,
+    /// Requests per ip, counted on top of `key`. Required for token groups: tokens are chosen
+    /// by the client, so their counters alone don't limit token guessing.
+    pub ip_limit: Option<u64>,
+    /// Window length in seconds
+    pub window: u64,
+}
+
+/// What the requests of a rate limit group are counted by
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    Ip,
+    /// Logged in account, ip otherwise
+    Account,
+    /// `token` query parameter of the emailed links, on top of the `ip_limit` per ip
+    Token,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SecuritySettings {
+    /// Make registration and password reset requests indistinguishable whether the email is
+    /// registered or not (same response and timing, informative email instead). Login never
+    /// tells whether the email is registered.
+    pub hardened_mode: bool,
+    /// Key used to hash emailed tokens before storing them and to encrypt stateless tokens
+    /// (at least 32 bytes)
+    pub token_secret: Secret<String>,
+    pub token_backend: TokenBackend,
+}
+
+/// Format of the emailed tokens
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenBackend {
+    /// Random 150 characters token whose hash is stored in redis along with its fields
+    Random,
+    /// Encrypted payload carrying purpose, email, expiry and a nonce, verified without redis.
+    /// Redis only keeps a deny-list of redeemed tokens until they expire.
+    Signed,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CaptchaSettings {
+    pub provider: CaptchaProviderKind,
+    /// Wrong answers after which a captcha is invalidated
+    pub max_failed_attempts: u32,
+    pub image: ImageCaptchaSettings,
+    /// Number of leading zero bits required by the proof of work captcha
+    pub proof_of_work_difficulty: u32,
+    /// Required by the remote provider
+    pub remote: Option<RemoteCaptchaSettings>,
+}
+
+/// Captcha protecting the registration and password reset requests
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaProviderKind {
+    /// Distorted text image (with an audio alternative)
+    Image,
+    /// Hashcash-style challenge solved by the browser
+    ProofOfWork,
+    /// hCaptcha, Turnstile or any service exposing a compatible siteverify endpoint
+    Remote,
+}
+
+/// Generation of the image captcha. Answers are validated against the same settings.
+#[derive(Clone, Deserialize)]
+pub struct ImageCaptchaSettings {
+    pub difficulty: CaptchaDifficulty,
+    /// Filters applied to the image, their strength depends on the difficulty
+    pub filters: Vec<CaptchaFilter>,
+    /// Number of characters, picked at random in this range (inclusive)
+    pub min_length: u32,
+    pub max_length: u32,
+    /// Characters the answer is made of (ascii letters and digits)
+    pub charset: String,
+    /// Characters the spoken answer is made of: digits or letters of a single case, the case
+    /// can't be heard
+    pub audio_charset: String,
+    /// Image size in pixels
+    pub width: u32,
+    pub height: u32,
+    pub case_insensitive: bool,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaDifficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaFilter {
+    /// Random pixels
+    Noise,
+    /// Lines across the text
+    Grid,
+    /// Wavy distortion of the text
+    Wave,
+    /// Random dots
+    Dots,
+    /// Inverted circles
+    Cow,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RemoteCaptchaSettings {
+    pub verify_url: String,
+    /// Widget script loaded by the frontend
+    pub script_url: String,
+    pub site_key: String,
+    pub secret: Secret<String>,
+}
+
+/// Failed login tracking. All durations are expressed in seconds.
+#[derive(Clone, Deserialize)]
+pub struct LoginThrottleSettings {
+    /// Time during which failed attempts are remembered (refreshed on each failure)
+    pub failure_window: u64,
+    /// Number of failed attempts from an (ip, account) pair before delays are applied
+    pub backoff_threshold: u64,
+    pub backoff_base_delay: u64,
+    pub backoff_max_delay: u64,
+    /// Number of failed attempts on an account (from any ip) before it gets locked
+    pub lockout_threshold: u64,
+    pub lockout_duration: u64,
+    /// Number of failed attempts on an account, or from an ip, before a captcha is required
+    pub captcha_account_threshold: u64,
+    pub captcha_ip_threshold: u64,
+}
@@ -0,0 +1,117 @@
+use crate::tasks::{SupervisorHealth, TaskHealth};
+use deadpool_redis::redis::cmd;
+use deadpool_redis::Pool as RedisPool;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Time given to each dependency to answer a readiness check
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether the instance can serve requests: it is started and not shutting down, its
+/// dependencies answer, and none of its background tasks has been given up.
+pub struct Readiness {
+    started: AtomicBool,
+    shutdown: CancellationToken,
+    tasks: SupervisorHealth,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerState {
+    Starting,
+    Running,
+    ShuttingDown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub state: ServerState,
+    pub dependencies: BTreeMap<&'static str, DependencyCheck>,
+    pub tasks: BTreeMap<String, TaskHealth>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DependencyCheck {
+    pub healthy: bool,
+    pub latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Readiness {
+    pub fn new(shutdown: CancellationToken, tasks: SupervisorHealth) -> Self {
+        Self {
+            started: AtomicBool::new(false),
+            shutdown,
+            tasks,
+        }
+    }
+
+    pub fn set_started(&self) {
+        self.started.store(true, Ordering::Relaxed);
+    }
+
+    pub fn state(&self) -> ServerState {
+        if self.shutdown.is_cancelled() {
+            ServerState::ShuttingDown
+        } else if self.started.load(Ordering::Relaxed) {
+            ServerState::Running
+        } else {
+            ServerState::Starting
+        }
+    }
+
+    pub async fn check(&self, pg_pool: &PgPool, redis_pool: &RedisPool) -> ReadinessReport {
+        let (postgres, redis) = tokio::join!(
+            check_dependency(async {
+                sqlx::query("SELECT 1").execute(pg_pool).await?;
+                Ok(())
+            }),
+            check_dependency(async {
+                let mut redis_conn = redis_pool.get().await?;
+                cmd("PING")
+                    .query_async::<_, String>(&mut redis_conn)
+                    .await?;
+                Ok(())
+            }),
+        );
+        let dependencies = BTreeMap::from([("postgres", postgres), ("redis", redis)]);
+        let tasks = self.tasks.all();
+        let state = self.state();
+
+        ReadinessReport {
+            ready: state == ServerState::Running
+                && dependencies.values().all(|check| check.healthy)
+                && !tasks
+                    .values()
+                    .any(|task| matches!(task, TaskHealth::Failed { .. })),
+            state,
+            dependencies,
+            tasks,
+        }
+    }
+}
+
+async fn check_dependency<F>(check: F) -> DependencyCheck
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    let start = Instant::now();
+    let error = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("timed out".into()),
+    };
+
+    DependencyCheck {
+        healthy: error.is_none(),
+        latency_ms: start.elapsed().as_millis(),
+        error,
+    }
+}
@@ -1,10 +1,15 @@
 pub mod app_error;
+pub mod client_ip;
 pub mod config;
 pub mod db;
+pub mod health;
 pub mod logic;
+pub mod rate_limit;
 pub mod routes;
 pub mod server;
 pub mod services;
 pub mod session;
+pub mod shutdown;
 pub mod tasks;
 pub mod telemetry;
+pub mod tls;
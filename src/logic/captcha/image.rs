@@ -0,0 +1,623 @@
+use crate::app_error::AppError;
+use crate::config::{CaptchaDifficulty, CaptchaFilter, ImageCaptchaSettings};
+use crate::logic::{
+    CaptchaChallenge, CaptchaProvider, CaptchaSolution, CreateUserError, FieldValidationError,
+};
+use crate::tasks::{hset_nx_indexed, EmptyGeneratable, Timestampable};
+use anyhow::bail;
+use async_trait::async_trait;
+use captcha::filters::{Cow, Dots, Grid, Noise, Wave};
+use chrono::Utc;
+use deadpool_redis::redis::{
+    from_redis_value, pipe, AsyncCommands, ErrorKind, FromRedisValue, RedisResult, RedisWrite,
+    ToRedisArgs, Value as RedisValue,
+};
+use deadpool_redis::Connection;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::str::FromStr;
+use uuid::Uuid;
+
+pub struct Captcha {
+    id: CaptchaID,
+    answer: CaptchaAnswer,
+    audio_answer: CaptchaAnswer,
+    img: String,
+}
+
+impl Captcha {
+    pub fn generate(settings: &ImageCaptchaSettings) -> Result<Self, AppError> {
+        let charset: Vec<char> = settings.charset.chars().collect();
+        let length = thread_rng().gen_range(settings.min_length..=settings.max_length);
+
+        let mut captcha = captcha::Captcha::new();
+        captcha.set_chars(&charset).add_chars(length);
+        Self::apply_filters(&mut captcha, settings);
+
+        let img = match captcha.as_base64() {
+            Some(b) => b,
+            None => Err(CreateUserError::CaptchaGeneration)?,
+        };
+
+        let answer = CaptchaAnswer::from_str(captcha.chars_as_string());
+        Ok(Self {
+            id: CaptchaID::from_uuid(Uuid::new_v4()),
+            answer,
+            audio_answer: Self::generate_audio_answer(settings),
+            img,
+        })
+    }
+
+    /// The spoken challenge has its own answer, made of characters without case so that
+    /// everything needed to solve it can be heard
+    fn generate_audio_answer(settings: &ImageCaptchaSettings) -> CaptchaAnswer {
+        let charset: Vec<char> = settings.audio_charset.chars().collect();
+        let mut rng = thread_rng();
+        let length = rng.gen_range(settings.min_length..=settings.max_length);
+        let answer = (0..length)
+            .map(|_| charset[rng.gen_range(0..charset.len())])
+            .collect();
+
+        CaptchaAnswer::from_str(answer)
+    }
+
+    /// Noise, grid and wave are applied to the text before it gets cropped to the image size,
+    /// dots and cow on the final image.
+    fn apply_filters(captcha: &mut captcha::Captcha, settings: &ImageCaptchaSettings) {
+        let (noise, grid_gap, wave_amp, dots) = match settings.difficulty {
+            CaptchaDifficulty::Easy => (0.2, 8, 10.0, 10),
+            CaptchaDifficulty::Medium => (0.3, 6, 15.0, 15),
+            CaptchaDifficulty::Hard => (0.5, 4, 20.0, 20),
+        };
+        let has = |filter| settings.filters.contains(&filter);
+
+        if has(CaptchaFilter::Noise) {
+            captcha.apply_filter(Noise::new(noise));
+        }
+        if has(CaptchaFilter::Grid) {
+            captcha.apply_filter(Grid::new(grid_gap, grid_gap));
+        }
+        if has(CaptchaFilter::Wave) {
+            captcha.apply_filter(Wave::new(2.0, wave_amp));
+        }
+        captcha.view(settings.width, settings.height);
+        if has(CaptchaFilter::Dots) {
+            captcha.apply_filter(Dots::new(dots).min_radius(3).max_radius(7));
+        }
+        if has(CaptchaFilter::Cow) {
+            captcha.apply_filter(Cow::new().circles(1).min_radius(40).max_radius(50));
+        }
+    }
+
+    pub async fn reload_captcha(
+        settings: &ImageCaptchaSettings,
+        id: CaptchaID,
+        redis_conn: &mut Connection,
+        client_ip: &str,
+    ) -> Result<Self, AppError> {
+        CaptchaFields::get_for_client(redis_conn, &id, client_ip).await?;
+        let existed: bool = redis_conn.hdel("captcha", id).await?;
+        if !existed {
+            Err(FieldValidationError::InvalidCaptchaID)?;
+        }
+
+        Self::generate(settings)
+    }
+
+    pub async fn store_captcha_answer_in_redis(
+        &self,
+        redis_conn: &mut Connection,
+        client_ip: &str,
+    ) -> Result<(), AppError> {
+        let timestamp = Utc::now().timestamp();
+        let answer = serde_json::to_string(&CaptchaFields {
+            answer: self.answer.clone(),
+            audio_answer: Some(self.audio_answer.clone()),
+            timestamp,
+            client: client_ip.into(),
+        })?;
+        let res = hset_nx_indexed(redis_conn, "captcha", &self.id, answer, timestamp).await?;
+        if !res {
+            Err(CreateUserError::CaptchaGeneration)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_response_data(self) -> CaptchaChallenge {
+        CaptchaChallenge::Image {
+            id: self.id,
+            img: self.img,
+        }
+    }
+}
+
+/// Distorted text image generated with the `captcha` crate, with an audio alternative
+pub struct ImageCaptchaProvider {
+    settings: ImageCaptchaSettings,
+    max_failed_attempts: u32,
+}
+
+impl ImageCaptchaProvider {
+    pub fn new(settings: ImageCaptchaSettings, max_failed_attempts: u32) -> anyhow::Result<Self> {
+        // Only characters which can be drawn (the font lacks 0, I, L, O and o) and spoken
+        let mut supported = captcha::Captcha::new().supported_chars();
+        supported.sort();
+        if settings.charset.is_empty()
+            || !settings
+                .charset
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() && supported.contains(&c))
+        {
+            let supported: String = supported.into_iter().collect();
+            bail!("The captcha charset must only contain characters among {supported}");
+        }
+        // Only digits or a single case of letters, the case can't be heard
+        if settings.audio_charset.is_empty()
+            || !settings
+                .audio_charset
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() && supported.contains(&c))
+            || (settings
+                .audio_charset
+                .chars()
+                .any(|c| c.is_ascii_lowercase())
+                && settings
+                    .audio_charset
+                    .chars()
+                    .any(|c| c.is_ascii_uppercase()))
+        {
+            let supported: String = supported.into_iter().collect();
+            bail!(
+                "The audio captcha charset must only contain characters among {supported}, \
+                 in a single case"
+            );
+        }
+        if settings.min_length == 0 || settings.min_length > settings.max_length {
+            bail!("The captcha length range is invalid");
+        }
+        if !(100..=400).contains(&settings.width) || !(50..=200).contains(&settings.height) {
+            bail!("The captcha image must be 100 to 400 pixels wide and 50 to 200 pixels high");
+        }
+
+        Ok(Self {
+            settings,
+            max_failed_attempts,
+        })
+    }
+}
+
+#[async_trait]
+impl CaptchaProvider for ImageCaptchaProvider {
+    async fn issue_challenge(
+        &self,
+        redis_conn: &mut Connection,
+        client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError> {
+        let captcha = Captcha::generate(&self.settings)?;
+        captcha
+            .store_captcha_answer_in_redis(redis_conn, client_ip)
+            .await?;
+
+        Ok(captcha.get_response_data())
+    }
+
+    async fn reload_challenge(
+        &self,
+        redis_conn: &mut Connection,
+        id: CaptchaID,
+        client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError> {
+        let captcha = Captcha::reload_captcha(&self.settings, id, redis_conn, client_ip).await?;
+        captcha
+            .store_captcha_answer_in_redis(redis_conn, client_ip)
+            .await?;
+
+        Ok(captcha.get_response_data())
+    }
+
+    async fn verify_answer(
+        &self,
+        redis_conn: &mut Connection,
+        solution: &CaptchaSolution,
+        client_ip: &str,
+    ) -> Result<(), AppError> {
+        let id = CaptchaID::parse(solution.id.clone())?;
+        let answer = CaptchaAnswer::parse(solution.answer.clone(), &self.settings)?;
+
+        CaptchaAnswer::is_valid_captcha_answer(
+            redis_conn,
+            &answer,
+            &id,
+            client_ip,
+            self.max_failed_attempts,
+            self.settings.case_insensitive,
+        )
+        .await
+    }
+
+    async fn load_audio(
+        &self,
+        redis_conn: &mut Connection,
+        id: &CaptchaID,
+        client_ip: &str,
+    ) -> Result<(CaptchaAudio, i64), AppError> {
+        CaptchaAudio::from_redis(redis_conn, id, client_ip).await
+    }
+}
+
+/// Spoken version of a captcha, served separately from the image and tied to the same
+/// `CaptchaID`. It speaks its own answer (see `CaptchaFields::audio_answer`), which solves the
+/// captcha as well as the image one. Every clip is distorted at random and mixed over a babble
+/// of other characters, so that the clips can't be matched against the ones of the `captcha`
+/// crate.
+pub struct CaptchaAudio(Vec<u8>);
+impl CaptchaAudio {
+    const SAMPLE_RATE: u32 = 22050;
+    /// Range of the silence around each character, in samples
+    const GAP_SAMPLES: (usize, usize) = (6000, 16000);
+    /// Range of the playback rate of a clip, changing both its speed and pitch
+    const RATE: (f64, f64) = (0.85, 1.2);
+    /// Number of background clips per spoken character
+    const BABBLE_CLIPS: usize = 3;
+    const BABBLE_GAIN: (f64, f64) = (0.1, 0.25);
+    const NOISE_AMPLITUDE: f64 = 1500.0;
+
+    /// Speak the audio answer stored in redis for the captcha. Returns the audio and the
+    /// captcha creation timestamp.
+    pub async fn from_redis(
+        redis_conn: &mut Connection,
+        captcha_id: &CaptchaID,
+        client_ip: &str,
+    ) -> Result<(Self, i64), AppError> {
+        let fields = CaptchaFields::get_for_client(redis_conn, captcha_id, client_ip).await?;
+        let answer = fields
+            .audio_answer
+            .ok_or(FieldValidationError::InvalidCaptchaID)?;
+
+        Ok((Self::speak(&answer)?, fields.timestamp))
+    }
+
+    pub fn speak(answer: &CaptchaAnswer) -> Result<Self, AppError> {
+        let mut rng = thread_rng();
+        let mut track = Vec::new();
+        for c in answer.as_str().chars() {
+            track.resize(
+                track.len() + rng.gen_range(Self::GAP_SAMPLES.0..=Self::GAP_SAMPLES.1),
+                0.0,
+            );
+            let clip = Self::clip(c)?;
+            let rate = rng.gen_range(Self::RATE.0..=Self::RATE.1);
+            let gain = rng.gen_range(0.7..=1.0);
+            track.extend(Self::resample(&clip, rate).into_iter().map(|s| s * gain));
+        }
+        track.resize(
+            track.len() + rng.gen_range(Self::GAP_SAMPLES.0..=Self::GAP_SAMPLES.1),
+            0.0,
+        );
+
+        // Decoy characters spoken quieter, anywhere in the track
+        let decoys = captcha::Captcha::new().supported_chars();
+        for _ in 0..answer.as_str().chars().count() * Self::BABBLE_CLIPS {
+            let decoy = decoys[rng.gen_range(0..decoys.len())];
+            let rate = rng.gen_range(Self::RATE.0..=Self::RATE.1);
+            let gain = rng.gen_range(Self::BABBLE_GAIN.0..=Self::BABBLE_GAIN.1);
+            let clip = Self::resample(&Self::clip(decoy)?, rate);
+            let offset = rng.gen_range(0..track.len());
+            for (sample, decoy) in track[offset..].iter_mut().zip(clip) {
+                *sample += decoy * gain;
+            }
+        }
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: Self::SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec)
+            .map_err(|_| CreateUserError::CaptchaGeneration)?;
+        for sample in track {
+            let noise = rng.gen_range(-Self::NOISE_AMPLITUDE..=Self::NOISE_AMPLITUDE);
+            let sample = (sample + noise).clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+            writer
+                .write_sample(sample)
+                .map_err(|_| CreateUserError::CaptchaGeneration)?;
+        }
+        writer
+            .finalize()
+            .map_err(|_| CreateUserError::CaptchaGeneration)?;
+
+        Ok(Self(cursor.into_inner()))
+    }
+
+    /// Samples of the clip the `captcha` crate has for a character
+    fn clip(c: char) -> Result<Vec<f64>, AppError> {
+        let mut spoken = captcha::Captcha::new();
+        spoken.set_chars(&[c]).add_char();
+        let clip = spoken
+            .as_wav()
+            .pop()
+            .flatten()
+            .ok_or(CreateUserError::CaptchaGeneration)?;
+        let mut reader =
+            hound::WavReader::new(&clip[..]).map_err(|_| CreateUserError::CaptchaGeneration)?;
+
+        reader
+            .samples::<i16>()
+            .map(|s| s.map(f64::from))
+            .collect::<Result<_, _>>()
+            .map_err(|_| CreateUserError::CaptchaGeneration.into())
+    }
+
+    /// Play the clip `rate` times faster, by linear interpolation
+    fn resample(clip: &[f64], rate: f64) -> Vec<f64> {
+        let len = (clip.len() as f64 / rate) as usize;
+        (0..len)
+            .map(|i| {
+                let pos = i as f64 * rate;
+                let (j, frac) = (pos as usize, pos.fract());
+                let next = clip.get(j + 1).copied().unwrap_or(0.0);
+                clip[j] * (1.0 - frac) + next * frac
+            })
+            .collect()
+    }
+
+    pub fn into_wav(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct CaptchaAnswer(String);
+impl CaptchaAnswer {
+    /// Answers are made of `min_length` to `max_length` characters of the configured charset
+    /// (in any case if `case_insensitive`), or of the audio charset in any case
+    pub fn parse(
+        captcha_answer: String,
+        settings: &ImageCaptchaSettings,
+    ) -> Result<Self, FieldValidationError> {
+        let len = captcha_answer.chars().count() as u32;
+        let in_charset = |charset: &str, case_insensitive: bool| {
+            captcha_answer.chars().all(|c| match case_insensitive {
+                true => charset.chars().any(|s| s.eq_ignore_ascii_case(&c)),
+                false => charset.contains(c),
+            })
+        };
+
+        if !(settings.min_length..=settings.max_length).contains(&len)
+            || !(in_charset(&settings.charset, settings.case_insensitive)
+                || in_charset(&settings.audio_charset, true))
+        {
+            Err(FieldValidationError::InvalidCaptchaAnswer)?;
+        }
+
+        Ok(Self(captcha_answer))
+    }
+
+    pub fn matches(&self, other: &CaptchaAnswer, case_insensitive: bool) -> bool {
+        if case_insensitive {
+            self.0.eq_ignore_ascii_case(&other.0)
+        } else {
+            self.0 == other.0
+        }
+    }
+
+    /// Check the answer against the image answer of the captcha, or against its audio answer
+    /// which has no case
+    pub async fn is_valid_captcha_answer(
+        redis_conn: &mut Connection,
+        captcha_answer: &CaptchaAnswer,
+        captcha_id: &CaptchaID,
+        client_ip: &str,
+        max_failed_attempts: u32,
+        case_insensitive: bool,
+    ) -> Result<(), AppError> {
+        CaptchaFields::verify(
+            redis_conn,
+            captcha_id,
+            client_ip,
+            max_failed_attempts,
+            |fields| {
+                fields.answer.matches(captcha_answer, case_insensitive)
+                    || fields
+                        .audio_answer
+                        .as_ref()
+                        .is_some_and(|audio| audio.matches(captcha_answer, true))
+            },
+        )
+        .await
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(captcha_answer: String) -> Self {
+        Self(captcha_answer)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CaptchaFields {
+    pub answer: CaptchaAnswer,
+    /// Answer of the spoken challenge, for the providers which have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_answer: Option<CaptchaAnswer>,
+    pub timestamp: i64,
+    /// Ip of the client the captcha has been issued to. Only this client can solve it.
+    #[serde(default)]
+    pub client: String,
+}
+
+impl CaptchaFields {
+    /// Lifetime of the keys kept next to each captcha (failed attempts), longer than captchas are kept
+    const PER_CAPTCHA_KEYS_TTL: usize = 24 * 3600;
+
+    pub fn json_string(
+        answer: CaptchaAnswer,
+        timestamp: i64,
+        client: &str,
+    ) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&Self {
+            answer,
+            audio_answer: None,
+            timestamp,
+            client: client.into(),
+        })
+    }
+
+    /// Fields of a captcha issued to `client_ip`
+    pub async fn get_for_client(
+        redis_conn: &mut Connection,
+        captcha_id: &CaptchaID,
+        client_ip: &str,
+    ) -> Result<Self, AppError> {
+        let fields: Option<Self> = redis_conn.hget("captcha", captcha_id).await?;
+        match fields {
+            Some(f) if f.client == client_ip => Ok(f),
+            _ => Err(FieldValidationError::InvalidCaptchaID)?,
+        }
+    }
+
+    /// Remove the captcha if `is_solution` accepts its stored fields, otherwise count the
+    /// failed attempt.
+    pub async fn verify(
+        redis_conn: &mut Connection,
+        captcha_id: &CaptchaID,
+        client_ip: &str,
+        max_failed_attempts: u32,
+        is_solution: impl FnOnce(&Self) -> bool + Send,
+    ) -> Result<(), AppError> {
+        let fields = Self::get_for_client(redis_conn, captcha_id, client_ip).await?;
+        if !is_solution(&fields) {
+            Self::register_failure(redis_conn, captcha_id, max_failed_attempts).await?;
+            Err(FieldValidationError::InvalidCaptchaAnswer)?;
+        }
+
+        // Only the request which removes the captcha gets to use it
+        let (removed, _): (bool, ()) = pipe()
+            .hdel("captcha", captcha_id)
+            .del(Self::failed_attempts_key(captcha_id))
+            .query_async(redis_conn)
+            .await?;
+        if !removed {
+            Err(FieldValidationError::InvalidCaptchaID)?;
+        }
+
+        Ok(())
+    }
+
+    /// Key of the count of wrong answers to a captcha
+    fn failed_attempts_key(captcha_id: &CaptchaID) -> String {
+        format!("captcha_failed_attempts:{}", captcha_id.0)
+    }
+
+    /// Count a wrong answer and invalidate the captcha once `max_failed_attempts` is reached.
+    /// The count is incremented atomically in its own key, so concurrent answers never
+    /// overwrite each other's count or the captcha itself.
+    async fn register_failure(
+        redis_conn: &mut Connection,
+        captcha_id: &CaptchaID,
+        max_failed_attempts: u32,
+    ) -> Result<(), AppError> {
+        let key = Self::failed_attempts_key(captcha_id);
+        let (failed_attempts, _): (u32, bool) = pipe()
+            .atomic()
+            .incr(&key, 1)
+            .expire(&key, Self::PER_CAPTCHA_KEYS_TTL)
+            .query_async(redis_conn)
+            .await?;
+
+        if failed_attempts >= max_failed_attempts {
+            pipe()
+                .atomic()
+                .hdel("captcha", captcha_id)
+                .del(&key)
+                .query_async::<_, ()>(redis_conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Number of wrong answers given to a captcha
+    pub async fn failed_attempts(
+        redis_conn: &mut Connection,
+        captcha_id: &CaptchaID,
+    ) -> RedisResult<u32> {
+        let count: Option<u32> = redis_conn
+            .get(Self::failed_attempts_key(captcha_id))
+            .await?;
+        Ok(count.unwrap_or(0))
+    }
+}
+
+impl Timestampable for CaptchaFields {
+    fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+}
+
+impl FromRedisValue for CaptchaFields {
+    fn from_redis_value(v: &RedisValue) -> RedisResult<Self> {
+        let v: String = from_redis_value(v)?;
+        match serde_json::from_str(&v) {
+            Ok(f) => Ok(f),
+            Err(_) => Err((ErrorKind::TypeError, "deserializing captcha fields").into()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CaptchaID(String);
+impl CaptchaID {
+    pub fn parse(captcha_id: String) -> Result<Self, AppError> {
+        if Uuid::from_str(&captcha_id).is_err() {
+            Err(FieldValidationError::InvalidCaptchaID)?;
+        }
+
+        Ok(Self(captcha_id))
+    }
+
+    pub fn from_uuid(id: Uuid) -> Self {
+        Self(id.to_string())
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(id: String) -> Self {
+        Self(id)
+    }
+}
+
+impl EmptyGeneratable for CaptchaID {
+    fn generate_empty() -> Self {
+        Self("".into())
+    }
+}
+
+impl FromRedisValue for CaptchaID {
+    fn from_redis_value(v: &RedisValue) -> RedisResult<Self> {
+        let v: String = from_redis_value(v)?;
+        Ok(CaptchaID::from_str(v))
+    }
+}
+
+impl ToRedisArgs for CaptchaID {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        out.write_arg(self.0.as_bytes());
+    }
+}
+
+// Used for id vector allocation
+impl Clone for CaptchaID {
+    fn clone(&self) -> Self {
+        Self::generate_empty()
+    }
+}
@@ -0,0 +1,9 @@
+mod image;
+mod proof_of_work;
+mod provider;
+mod remote;
+
+pub use image::*;
+pub use proof_of_work::*;
+pub use provider::*;
+pub use remote::*;
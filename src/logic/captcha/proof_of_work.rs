@@ -0,0 +1,127 @@
+use crate::app_error::AppError;
+use crate::logic::{
+    CaptchaAnswer, CaptchaChallenge, CaptchaFields, CaptchaID, CaptchaProvider, CaptchaSolution,
+    FieldValidationError,
+};
+use crate::tasks::hset_nx_indexed;
+use async_trait::async_trait;
+use chrono::Utc;
+use deadpool_redis::redis::AsyncCommands;
+use deadpool_redis::Connection;
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Hashcash-style challenge: the client burns some cpu time instead of solving a puzzle, and
+/// no third party is involved. Challenges are stored in the `captcha` hash like image answers.
+pub struct ProofOfWorkCaptchaProvider {
+    /// Number of leading zero bits required in the solution hash
+    difficulty: u32,
+    max_failed_attempts: u32,
+}
+
+impl ProofOfWorkCaptchaProvider {
+    pub fn new(difficulty: u32, max_failed_attempts: u32) -> Self {
+        Self {
+            difficulty,
+            max_failed_attempts,
+        }
+    }
+
+    /// Check that sha256(challenge + nonce) starts with `difficulty` zero bits
+    pub fn is_valid_nonce(challenge: &str, nonce: &str, difficulty: u32) -> bool {
+        let hash = Sha256::new()
+            .chain_update(challenge.as_bytes())
+            .chain_update(nonce.as_bytes())
+            .finalize();
+
+        let mut zeros = 0;
+        for byte in hash {
+            zeros += byte.leading_zeros();
+            if byte != 0 {
+                break;
+            }
+        }
+
+        zeros >= difficulty
+    }
+
+    async fn store_challenge(
+        &self,
+        redis_conn: &mut Connection,
+        client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError> {
+        let mut bytes = [0u8; 16];
+        thread_rng().fill_bytes(&mut bytes);
+        let challenge = hex::encode(bytes);
+
+        let id = CaptchaID::from_uuid(Uuid::new_v4());
+        let timestamp = Utc::now().timestamp();
+        let fields = CaptchaFields::json_string(
+            CaptchaAnswer::from_str(challenge.clone()),
+            timestamp,
+            client_ip,
+        )?;
+        let res = hset_nx_indexed(redis_conn, "captcha", &id, fields, timestamp).await?;
+        if !res {
+            Err(AppError::with_msg(
+                "Failed storing proof of work challenge".into(),
+            ))?;
+        }
+
+        Ok(CaptchaChallenge::ProofOfWork {
+            id,
+            challenge,
+            difficulty: self.difficulty,
+        })
+    }
+}
+
+#[async_trait]
+impl CaptchaProvider for ProofOfWorkCaptchaProvider {
+    async fn issue_challenge(
+        &self,
+        redis_conn: &mut Connection,
+        client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError> {
+        self.store_challenge(redis_conn, client_ip).await
+    }
+
+    async fn reload_challenge(
+        &self,
+        redis_conn: &mut Connection,
+        id: CaptchaID,
+        client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError> {
+        CaptchaFields::get_for_client(redis_conn, &id, client_ip).await?;
+        let existed: bool = redis_conn.hdel("captcha", id).await?;
+        if !existed {
+            Err(FieldValidationError::InvalidCaptchaID)?;
+        }
+
+        self.store_challenge(redis_conn, client_ip).await
+    }
+
+    async fn verify_answer(
+        &self,
+        redis_conn: &mut Connection,
+        solution: &CaptchaSolution,
+        client_ip: &str,
+    ) -> Result<(), AppError> {
+        let id = CaptchaID::parse(solution.id.clone())?;
+        let nonce = &solution.answer;
+        if nonce.is_empty() || nonce.len() > 64 || !nonce.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            Err(FieldValidationError::InvalidCaptchaAnswer)?;
+        }
+
+        CaptchaFields::verify(
+            redis_conn,
+            &id,
+            client_ip,
+            self.max_failed_attempts,
+            |fields| Self::is_valid_nonce(fields.answer.as_str(), nonce, self.difficulty),
+        )
+        .await
+    }
+}
@@ -0,0 +1,167 @@
+use crate::app_error::AppError;
+use crate::config::{CaptchaProviderKind, CaptchaSettings};
+use crate::logic::{
+    CaptchaAudio, CaptchaID, FieldValidationError, ImageCaptchaProvider,
+    ProofOfWorkCaptchaProvider, RemoteCaptchaProvider,
+};
+use crate::telemetry::{record_captcha_issued, record_captcha_verification};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use deadpool_redis::Connection;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Human verification used by the registration and password reset requests.
+/// The provider is picked from the settings (see `CaptchaProviderKind`).
+#[async_trait]
+pub trait CaptchaProvider: Send + Sync {
+    /// Create a new challenge for the client to solve. Stored challenges are bound to the
+    /// client ip and can't be used by another client.
+    async fn issue_challenge(
+        &self,
+        redis_conn: &mut Connection,
+        client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError>;
+
+    /// Replace an unsolved challenge with a new one
+    async fn reload_challenge(
+        &self,
+        redis_conn: &mut Connection,
+        _id: CaptchaID,
+        client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError> {
+        self.issue_challenge(redis_conn, client_ip).await
+    }
+
+    /// Check the client solution. A challenge can only be solved once, and is invalidated
+    /// after too many wrong answers.
+    async fn verify_answer(
+        &self,
+        redis_conn: &mut Connection,
+        solution: &CaptchaSolution,
+        client_ip: &str,
+    ) -> Result<(), AppError>;
+
+    /// Spoken version of the challenge, for providers which have one
+    async fn load_audio(
+        &self,
+        _redis_conn: &mut Connection,
+        _id: &CaptchaID,
+        _client_ip: &str,
+    ) -> Result<(CaptchaAudio, i64), AppError> {
+        Err(FieldValidationError::InvalidCaptchaID)?
+    }
+}
+
+/// Challenge sent to the client, tagged with the provider kind
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CaptchaChallenge {
+    Image {
+        id: CaptchaID,
+        img: String,
+    },
+    /// Find a nonce such that sha256(challenge + nonce) starts with `difficulty` zero bits
+    ProofOfWork {
+        id: CaptchaID,
+        challenge: String,
+        difficulty: u32,
+    },
+    /// Solved client side through the third party widget
+    Remote {
+        site_key: String,
+        script_url: String,
+    },
+}
+
+/// Raw captcha fields sent with a form, validated by the configured provider
+pub struct CaptchaSolution {
+    pub id: String,
+    pub answer: String,
+}
+
+pub fn build_captcha_provider(
+    settings: &CaptchaSettings,
+) -> anyhow::Result<Arc<dyn CaptchaProvider>> {
+    let provider: Arc<dyn CaptchaProvider> = match settings.provider {
+        CaptchaProviderKind::Image => Arc::new(ImageCaptchaProvider::new(
+            settings.image.clone(),
+            settings.max_failed_attempts,
+        )?),
+        CaptchaProviderKind::ProofOfWork => Arc::new(ProofOfWorkCaptchaProvider::new(
+            settings.proof_of_work_difficulty,
+            settings.max_failed_attempts,
+        )),
+        CaptchaProviderKind::Remote => {
+            let remote = settings.remote.as_ref().ok_or(anyhow!(
+                "The remote captcha provider requires remote settings"
+            ))?;
+            Arc::new(RemoteCaptchaProvider::new(remote)?)
+        }
+    };
+
+    Ok(Arc::new(MeteredCaptchaProvider {
+        inner: provider,
+        kind: match settings.provider {
+            CaptchaProviderKind::Image => "image",
+            CaptchaProviderKind::ProofOfWork => "proof_of_work",
+            CaptchaProviderKind::Remote => "remote",
+        },
+    }))
+}
+
+/// Counts the challenges issued and the answers verified by the wrapped provider
+struct MeteredCaptchaProvider {
+    inner: Arc<dyn CaptchaProvider>,
+    kind: &'static str,
+}
+
+#[async_trait]
+impl CaptchaProvider for MeteredCaptchaProvider {
+    async fn issue_challenge(
+        &self,
+        redis_conn: &mut Connection,
+        client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError> {
+        let challenge = self.inner.issue_challenge(redis_conn, client_ip).await?;
+        record_captcha_issued(self.kind);
+        Ok(challenge)
+    }
+
+    async fn reload_challenge(
+        &self,
+        redis_conn: &mut Connection,
+        id: CaptchaID,
+        client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError> {
+        let challenge = self
+            .inner
+            .reload_challenge(redis_conn, id, client_ip)
+            .await?;
+        record_captcha_issued(self.kind);
+        Ok(challenge)
+    }
+
+    async fn verify_answer(
+        &self,
+        redis_conn: &mut Connection,
+        solution: &CaptchaSolution,
+        client_ip: &str,
+    ) -> Result<(), AppError> {
+        let verified = self
+            .inner
+            .verify_answer(redis_conn, solution, client_ip)
+            .await;
+        record_captcha_verification(self.kind, verified.is_ok());
+        verified
+    }
+
+    async fn load_audio(
+        &self,
+        redis_conn: &mut Connection,
+        id: &CaptchaID,
+        client_ip: &str,
+    ) -> Result<(CaptchaAudio, i64), AppError> {
+        self.inner.load_audio(redis_conn, id, client_ip).await
+    }
+}
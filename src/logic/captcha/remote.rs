@@ -0,0 +1,90 @@
+use crate::app_error::AppError;
+use crate::config::RemoteCaptchaSettings;
+use crate::logic::{CaptchaChallenge, CaptchaProvider, CaptchaSolution, FieldValidationError};
+use anyhow::Context;
+use async_trait::async_trait;
+use deadpool_redis::Connection;
+use secrecy::{ExposeSecret, Secret};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// hCaptcha/Turnstile-style verifier: the client solves the challenge through the third party
+/// widget and the resulting token is checked server side against the siteverify endpoint.
+/// The third party makes sure a token is only accepted once.
+pub struct RemoteCaptchaProvider {
+    verify_url: String,
+    site_key: String,
+    script_url: String,
+    secret: Secret<String>,
+    http_client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+impl RemoteCaptchaProvider {
+    pub fn new(settings: &RemoteCaptchaSettings) -> anyhow::Result<Self> {
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+
+        Ok(Self {
+            verify_url: settings.verify_url.clone(),
+            site_key: settings.site_key.clone(),
+            script_url: settings.script_url.clone(),
+            secret: settings.secret.clone(),
+            http_client,
+        })
+    }
+}
+
+#[async_trait]
+impl CaptchaProvider for RemoteCaptchaProvider {
+    async fn issue_challenge(
+        &self,
+        _redis_conn: &mut Connection,
+        _client_ip: &str,
+    ) -> Result<CaptchaChallenge, AppError> {
+        Ok(CaptchaChallenge::Remote {
+            site_key: self.site_key.clone(),
+            script_url: self.script_url.clone(),
+        })
+    }
+
+    async fn verify_answer(
+        &self,
+        _redis_conn: &mut Connection,
+        solution: &CaptchaSolution,
+        client_ip: &str,
+    ) -> Result<(), AppError> {
+        let token = &solution.answer;
+        if token.is_empty() || token.len() > 4096 {
+            Err(FieldValidationError::InvalidCaptchaAnswer)?;
+        }
+
+        let res: SiteVerifyResponse = self
+            .http_client
+            .post(&self.verify_url)
+            .form(&[
+                ("secret", self.secret.expose_secret().as_str()),
+                ("response", token.as_str()),
+                ("remoteip", client_ip),
+                ("sitekey", self.site_key.as_str()),
+            ])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| "Failed reaching the captcha verification server")?
+            .json()
+            .await
+            .with_context(|| "Invalid captcha verification server response")?;
+
+        if !res.success {
+            Err(FieldValidationError::InvalidCaptchaAnswer)?;
+        }
+
+        Ok(())
+    }
+}
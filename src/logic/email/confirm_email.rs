@@ -1,4 +1,4 @@
-use crate::logic::Email;
+use crate::logic::{Email, TokenPurpose};
 use crate::tasks::Timestampable;
 use deadpool_redis::redis::{
     from_redis_value, ErrorKind, FromRedisValue, RedisResult, Value as RedisValue,
@@ -7,13 +7,22 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize)]
 pub struct ConfirmEmail {
+    pub purpose: TokenPurpose,
     pub email: Email,
     pub timestamp: i64,
 }
 
 impl ConfirmEmail {
-    pub fn json_string(email: Email, timestamp: i64) -> Result<String, serde_json::Error> {
-        Ok(serde_json::to_string(&Self { email, timestamp })?)
+    pub fn json_string(
+        purpose: TokenPurpose,
+        email: Email,
+        timestamp: i64,
+    ) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&Self {
+            purpose,
+            email,
+            timestamp,
+        })
     }
 }
 
@@ -36,3 +45,30 @@ impl FromRedisValue for ConfirmEmail {
         }
     }
 }
+
+/// Fields of the tokens stored in the former `email` hash, shared by every purpose. These
+/// tokens can't be redeemed anymore, they are only read to be expired.
+#[derive(Deserialize)]
+pub struct LegacyConfirmEmail {
+    pub timestamp: i64,
+}
+
+impl Timestampable for LegacyConfirmEmail {
+    fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+}
+
+impl FromRedisValue for LegacyConfirmEmail {
+    fn from_redis_value(v: &RedisValue) -> RedisResult<Self> {
+        let v: String = from_redis_value(v)?;
+        match serde_json::from_str(&v) {
+            Ok(f) => Ok(f),
+            Err(_) => Err((
+                ErrorKind::TypeError,
+                "deserializing legacy email confirmation fields",
+            )
+                .into()),
+        }
+    }
+}
@@ -1,5 +1,11 @@
 mod confirm_email;
+mod token_hash;
+mod token_purpose;
+mod token_store;
 mod url_token;
 
 pub use confirm_email::*;
+pub use token_hash::*;
+pub use token_purpose::*;
+pub use token_store::*;
 pub use url_token::*;
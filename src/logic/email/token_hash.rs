@@ -0,0 +1,127 @@
+use crate::tasks::EmptyGeneratable;
+use aes_gcm::aead::AeadInPlace;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, Tag};
+use anyhow::bail;
+use deadpool_redis::redis::{
+    from_redis_value, FromRedisValue, RedisResult, RedisWrite, ToRedisArgs, Value as RedisValue,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Keeps the stateless token encryption key distinct from the token hashing key
+const SEALING_KEY_INFO: &[u8] = b"signed_token_encryption";
+pub const SEALING_NONCE_LEN: usize = 12;
+pub const SEALING_TAG_LEN: usize = 16;
+
+/// Server secret used to hash emailed tokens before storing them (redis, postgres).
+/// Only the hash is stored so that a read-only leak doesn't hand over working links.
+/// Stateless tokens are encrypted with a key derived from the same secret.
+#[derive(Clone)]
+pub struct TokenHashKey(Secret<String>);
+impl TokenHashKey {
+    pub fn new(secret: Secret<String>) -> anyhow::Result<Self> {
+        if secret.expose_secret().len() < 32 {
+            bail!("Token secret must be at least 32 bytes long");
+        }
+
+        Ok(Self(secret))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        <HmacSha256 as Mac>::new_from_slice(self.0.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size")
+    }
+
+    pub fn hash(&self, token: &str) -> HashedToken {
+        let mut mac = self.mac();
+        mac.update(token.as_bytes());
+        HashedToken(hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, self.0.expose_secret().as_bytes())
+            .expand(SEALING_KEY_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Aes256Gcm::new(&key.into())
+    }
+
+    /// Encrypt a stateless token payload with AES-256-GCM. Returns the random nonce followed
+    /// by the ciphertext, and the authentication tag.
+    pub fn seal(&self, payload: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut nonce = [0u8; SEALING_NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+
+        let mut sealed = payload.to_vec();
+        let tag = self
+            .cipher()
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[], &mut sealed)
+            .expect("Token payloads are far below the AES-GCM size limit");
+        sealed.splice(0..0, nonce);
+
+        (sealed, tag.to_vec())
+    }
+
+    /// Decrypt a payload sealed by `seal`, `None` if it has been tampered with
+    pub fn open(&self, sealed: &[u8], tag: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < SEALING_NONCE_LEN || tag.len() != SEALING_TAG_LEN {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(SEALING_NONCE_LEN);
+        let mut payload = ciphertext.to_vec();
+        self.cipher()
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                &[],
+                &mut payload,
+                Tag::from_slice(tag),
+            )
+            .ok()?;
+
+        Some(payload)
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of an emailed token
+#[derive(PartialEq)]
+pub struct HashedToken(String);
+impl HashedToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl EmptyGeneratable for HashedToken {
+    fn generate_empty() -> Self {
+        Self("".into())
+    }
+}
+
+impl FromRedisValue for HashedToken {
+    fn from_redis_value(v: &RedisValue) -> RedisResult<Self> {
+        let v: String = from_redis_value(v)?;
+        Ok(Self(v))
+    }
+}
+
+impl ToRedisArgs for HashedToken {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        out.write_arg(self.0.as_bytes());
+    }
+}
+
+/// Used for token vector allocation
+impl Clone for HashedToken {
+    fn clone(&self) -> Self {
+        Self::generate_empty()
+    }
+}
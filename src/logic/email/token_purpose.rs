@@ -0,0 +1,51 @@
+use crate::config::{Settings, Task1Settings, TokenTask1Settings};
+use serde::{Deserialize, Serialize};
+
+/// Action a emailed token has been issued for. Each purpose has its own redis hash so that
+/// a token can only be redeemed for the action it was issued for.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+    Registration,
+    PasswordReset,
+    EmailChange,
+    DeletionConfirmation,
+}
+
+impl TokenPurpose {
+    pub const ALL: [TokenPurpose; 4] = [
+        TokenPurpose::Registration,
+        TokenPurpose::PasswordReset,
+        TokenPurpose::EmailChange,
+        TokenPurpose::DeletionConfirmation,
+    ];
+
+    /// Hash all the tokens were stored in before they had a purpose
+    pub const LEGACY_HASH_NAME: &'static str = "email";
+
+    pub fn hash_name(&self) -> &'static str {
+        match self {
+            TokenPurpose::Registration => "registration_token",
+            TokenPurpose::PasswordReset => "password_reset_token",
+            TokenPurpose::EmailChange => "email_change_token",
+            TokenPurpose::DeletionConfirmation => "deletion_confirmation_token",
+        }
+    }
+
+    pub fn from_hash_name(hash_name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.hash_name() == hash_name)
+    }
+
+    pub fn task1_settings<'a>(&self, settings: &'a Settings) -> &'a Task1Settings {
+        self.token_settings(&settings.task1_tokens)
+    }
+
+    pub fn token_settings<'a>(&self, tokens: &'a TokenTask1Settings) -> &'a Task1Settings {
+        match self {
+            TokenPurpose::Registration => &tokens.registration,
+            TokenPurpose::PasswordReset => &tokens.password_reset,
+            TokenPurpose::EmailChange => &tokens.email_change,
+            TokenPurpose::DeletionConfirmation => &tokens.deletion_confirmation,
+        }
+    }
+}
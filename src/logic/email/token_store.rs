@@ -0,0 +1,173 @@
+use crate::app_error::AppError;
+use crate::config::{TokenBackend, TokenTask1Settings};
+use crate::logic::{
+    ConfirmEmail, Email, FieldValidationError, TokenHashKey, TokenPurpose, URLToken,
+    SEALING_NONCE_LEN, SEALING_TAG_LEN,
+};
+use anyhow::Context;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use deadpool_redis::redis::{cmd, Value as RedisValue};
+use deadpool_redis::Connection;
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+/// Issues and redeems emailed tokens with the configured backend (see `TokenBackend`).
+#[derive(Clone)]
+pub struct TokenStore {
+    backend: TokenBackend,
+    hash_key: TokenHashKey,
+    expiry: TokenTask1Settings,
+}
+
+/// Payload of a signed token, encrypted so that the link doesn't reveal the email
+#[derive(Deserialize, Serialize)]
+struct SignedTokenClaims {
+    purpose: TokenPurpose,
+    email: Email,
+    /// Issuance and expiry timestamps (seconds)
+    iat: i64,
+    exp: i64,
+    nonce: String,
+}
+
+/// Longest email `Email::parse` accepts: 64 bytes local part and 255 characters domain
+const MAX_EMAIL_LEN: usize = 64 + 1 + 255 * 4;
+/// Serialized claims with the longest purpose, email, timestamps and the hex nonce
+const MAX_CLAIMS_LEN: usize =
+    r#"{"purpose":"deletion_confirmation","email":"","iat":,"exp":,"nonce":""}"#.len()
+        + MAX_EMAIL_LEN
+        + 2 * "-9223372036854775808".len()
+        + 32;
+/// base64url(nonce + encrypted claims).base64url(tag)
+pub const MAX_SIGNED_TOKEN_LEN: usize =
+    base64_len(SEALING_NONCE_LEN + MAX_CLAIMS_LEN) + 1 + SIGNED_TOKEN_SIGNATURE_LEN;
+pub const SIGNED_TOKEN_SIGNATURE_LEN: usize = base64_len(SEALING_TAG_LEN);
+
+/// Length of unpadded base64
+const fn base64_len(bytes: usize) -> usize {
+    (bytes * 4).div_ceil(3)
+}
+
+impl TokenStore {
+    pub fn new(backend: TokenBackend, hash_key: TokenHashKey, expiry: TokenTask1Settings) -> Self {
+        Self {
+            backend,
+            hash_key,
+            expiry,
+        }
+    }
+
+    pub async fn issue(
+        &self,
+        redis_conn: Connection,
+        purpose: TokenPurpose,
+        email: &Email,
+    ) -> anyhow::Result<URLToken> {
+        match self.backend {
+            TokenBackend::Random => {
+                URLToken::store_user_fields_to_redis(redis_conn, purpose, email, &self.hash_key)
+                    .await
+            }
+            TokenBackend::Signed => Ok(self.sign(purpose, email)?),
+        }
+    }
+
+    /// Validate the format of a token received from a client
+    pub fn parse(&self, token: String) -> Result<URLToken, FieldValidationError> {
+        match self.backend {
+            TokenBackend::Random => URLToken::parse(token),
+            TokenBackend::Signed => URLToken::parse_signed(token),
+        }
+    }
+
+    /// Redeem the token for the given purpose. Each token can only be redeemed once.
+    pub async fn redeem(
+        &self,
+        mut redis_conn: Connection,
+        token: &URLToken,
+        purpose: TokenPurpose,
+    ) -> Result<ConfirmEmail, AppError> {
+        match self.backend {
+            TokenBackend::Random => {
+                token
+                    .get_associated_redis_fields(redis_conn, purpose, &self.hash_key)
+                    .await
+            }
+            TokenBackend::Signed => {
+                let claims = self.verify(token, purpose)?;
+                Self::deny(&mut redis_conn, &claims).await?;
+                Ok(ConfirmEmail {
+                    purpose: claims.purpose,
+                    email: claims.email,
+                    timestamp: claims.iat,
+                })
+            }
+        }
+    }
+
+    /// base64url(nonce + encrypted claims).base64url(authentication tag)
+    fn sign(&self, purpose: TokenPurpose, email: &Email) -> Result<URLToken, serde_json::Error> {
+        let mut nonce = [0u8; 16];
+        thread_rng().fill_bytes(&mut nonce);
+
+        let iat = Utc::now().timestamp();
+        let expiry_time = purpose.token_settings(&self.expiry).expiry_time;
+        let claims = SignedTokenClaims {
+            purpose,
+            email: email.clone(),
+            iat,
+            exp: iat + expiry_time as i64,
+            nonce: hex::encode(nonce),
+        };
+
+        let (payload, tag) = self.hash_key.seal(&serde_json::to_vec(&claims)?);
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+        let signature = URL_SAFE_NO_PAD.encode(tag);
+        Ok(URLToken::from_str_no_validate(format!(
+            "{payload}.{signature}"
+        )))
+    }
+
+    fn verify(
+        &self,
+        token: &URLToken,
+        purpose: TokenPurpose,
+    ) -> Result<SignedTokenClaims, FieldValidationError> {
+        let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).ok();
+        let claims: SignedTokenClaims = token
+            .as_str()
+            .split_once('.')
+            .and_then(|(payload, signature)| Some((decode(payload)?, decode(signature)?)))
+            .and_then(|(payload, tag)| self.hash_key.open(&payload, &tag))
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or(FieldValidationError::InvalidUrlToken)?;
+        if claims.purpose != purpose || claims.exp <= Utc::now().timestamp() {
+            return Err(FieldValidationError::InvalidUrlToken);
+        }
+
+        Ok(claims)
+    }
+
+    /// Add the token nonce to the deny-list until the token expires. Fails if it was already
+    /// there (token already redeemed).
+    async fn deny(redis_conn: &mut Connection, claims: &SignedTokenClaims) -> Result<(), AppError> {
+        let ttl = (claims.exp - Utc::now().timestamp()).max(1);
+        let ret: RedisValue = cmd("SET")
+            .arg(format!("redeemed_token:{}", claims.nonce))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query_async(redis_conn)
+            .await
+            .with_context(|| "Failed adding redeemed token to the redis deny-list")?;
+
+        if ret == RedisValue::Nil {
+            Err(FieldValidationError::InvalidUrlToken)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,12 +1,12 @@
 use crate::app_error::AppError;
-use crate::logic::{ConfirmEmail, Email, FieldValidationError};
-use crate::tasks::EmptyGeneratable;
+use crate::logic::{
+    ConfirmEmail, Email, FieldValidationError, TokenHashKey, TokenPurpose, MAX_SIGNED_TOKEN_LEN,
+    SIGNED_TOKEN_SIGNATURE_LEN,
+};
+use crate::tasks::hset_nx_indexed;
 use anyhow::Context;
 use chrono::Utc;
-use deadpool_redis::redis::{
-    from_redis_value, AsyncCommands, FromRedisValue, RedisResult, RedisWrite, ToRedisArgs,
-    Value as RedisValue,
-};
+use deadpool_redis::redis::AsyncCommands;
 use deadpool_redis::Connection;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
@@ -16,56 +16,90 @@ use validator::HasLen;
 #[derive(Serialize, Deserialize)]
 pub struct URLToken(String);
 impl URLToken {
+    /// Random token: 150 alphanumeric characters
     pub fn parse(token: String) -> Result<Self, FieldValidationError> {
         if token.length() == 150 && token.chars().all(|c| c.is_ascii_alphanumeric()) {
-            return Ok(Self { 0: token });
+            return Ok(Self(token));
         }
 
         Err(FieldValidationError::InvalidUrlToken)
     }
 
+    /// Signed token: url safe base64 payload and signature separated by a dot
+    pub fn parse_signed(token: String) -> Result<Self, FieldValidationError> {
+        let is_base64url = |part: &str| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+
+        match token.split_once('.') {
+            Some((payload, signature))
+                if token.len() <= MAX_SIGNED_TOKEN_LEN
+                    && signature.len() == SIGNED_TOKEN_SIGNATURE_LEN
+                    && is_base64url(payload)
+                    && is_base64url(signature) =>
+            {
+                Ok(Self(token))
+            }
+            _ => Err(FieldValidationError::InvalidUrlToken),
+        }
+    }
+
     pub fn as_str(&self) -> &str {
         &self.0
     }
 
     pub fn generate() -> Self {
         let rng = thread_rng();
-        Self {
-            0: rng
-                .sample_iter(Alphanumeric)
+        Self(
+            rng.sample_iter(Alphanumeric)
                 .map(char::from)
                 .take(150)
                 .collect(),
-        }
+        )
     }
 
+    /// Only the token hash is stored in redis, the token itself is meant to be emailed.
     pub async fn store_user_fields_to_redis(
         mut redis_conn: Connection,
+        purpose: TokenPurpose,
         email: &Email,
+        hash_key: &TokenHashKey,
     ) -> anyhow::Result<URLToken> {
         loop {
             let token =
                 tokio::task::spawn_blocking(move || -> URLToken { URLToken::generate() }).await?;
 
-            let confirmation_fields =
-                ConfirmEmail::json_string(email.clone(), Utc::now().timestamp())?;
-
-            if redis_conn
-                .hset_nx::<&str, &str, &str, u8>("email", token.as_str(), &confirmation_fields)
-                .await?
-                != 0
+            let timestamp = Utc::now().timestamp();
+            let confirmation_fields = ConfirmEmail::json_string(purpose, email.clone(), timestamp)?;
+
+            if hset_nx_indexed(
+                &mut redis_conn,
+                purpose.hash_name(),
+                hash_key.hash(token.as_str()),
+                &confirmation_fields,
+                timestamp,
+            )
+            .await?
             {
                 return Ok(token);
             }
         }
     }
 
+    /// Redeem the token for the given purpose. A token issued for another purpose is
+    /// considered invalid.
     pub async fn get_associated_redis_fields(
         &self,
         mut redis_conn: Connection,
+        purpose: TokenPurpose,
+        hash_key: &TokenHashKey,
     ) -> Result<ConfirmEmail, AppError> {
+        let hashed_token = hash_key.hash(self.as_str());
         let fields = match redis_conn
-            .hget::<&str, &Self, ConfirmEmail>("email", self)
+            .hget::<_, _, ConfirmEmail>(purpose.hash_name(), &hashed_token)
             .await
         {
             Ok(f) => Ok(f),
@@ -84,43 +118,18 @@ impl URLToken {
         // Removing (token -> fields) entry to make sure the email isn't validated multiple times
         // (user clicking confirmation link multiple times).
         redis_conn
-            .hdel("email", &self)
+            .hdel::<_, _, ()>(purpose.hash_name(), &hashed_token)
             .await
             .with_context(|| "Failed removing confirmation fields from redis")?;
 
+        if fields.purpose != purpose {
+            Err(FieldValidationError::InvalidUrlToken)?;
+        }
+
         Ok(fields)
     }
 
     pub fn from_str_no_validate(token: String) -> Self {
-        Self { 0: token }
-    }
-}
-
-impl EmptyGeneratable for URLToken {
-    fn generate_empty() -> Self {
-        Self { 0: "".into() }
-    }
-}
-
-impl FromRedisValue for URLToken {
-    fn from_redis_value(v: &RedisValue) -> RedisResult<Self> {
-        let v: String = from_redis_value(v)?;
-        Ok(URLToken::from_str_no_validate(v))
-    }
-}
-
-impl ToRedisArgs for URLToken {
-    fn write_redis_args<W>(&self, out: &mut W)
-    where
-        W: ?Sized + RedisWrite,
-    {
-        out.write_arg(self.0.as_bytes());
-    }
-}
-
-/// Used for token vector allocation
-impl Clone for URLToken {
-    fn clone(&self) -> Self {
-        Self::generate_empty()
+        Self(token)
     }
 }
@@ -3,7 +3,7 @@ mod email;
 mod reset_password;
 mod user;
 
-pub use captcha::{Captcha, CaptchaAnswer, CaptchaFields, CaptchaID, CaptchaResponseData};
+pub use captcha::*;
 pub use email::*;
 pub use reset_password::*;
 pub use user::*;
@@ -1,6 +1,6 @@
 use crate::app_error::AppError;
 use crate::logic::{
-    CaptchaAnswer, CaptchaID, Email, FieldValidationError, Password, PasswordHash, URLToken,
+    CaptchaSolution, Email, FieldValidationError, Password, PasswordHash, TokenStore, URLToken,
     Username,
 };
 use crate::routes::{ResetPasswordForm, ResetPasswordRequestForm};
@@ -10,8 +10,7 @@ use sqlx::{query_as, PgPool};
 
 pub struct ResetPasswordRequest {
     pub email: Email,
-    pub captcha_id: CaptchaID,
-    pub captcha_answer: CaptchaAnswer,
+    pub captcha: CaptchaSolution,
 }
 impl ResetPasswordRequest {
     pub fn validate_password_reset_request_form(
@@ -32,6 +31,16 @@ impl ResetPasswordRequest {
         );
         println!("{link}");
     }
+
+    pub async fn send_no_account_email(&self) {
+        //todo: send no account email
+        println!(
+            "{} | Hi,\nSomeone requested a password reset for this email address, but no \
+            account is registered with it.\nIf you are the author of this request, you may have \
+            registered with another address. Otherwise, you can safely ignore this email.\n",
+            self.email.as_str(),
+        );
+    }
 }
 
 impl TryFrom<ResetPasswordRequestForm> for ResetPasswordRequest {
@@ -40,8 +49,10 @@ impl TryFrom<ResetPasswordRequestForm> for ResetPasswordRequest {
     fn try_from(form: ResetPasswordRequestForm) -> Result<Self, Self::Error> {
         Ok(Self {
             email: Email::parse(form.email)?,
-            captcha_id: CaptchaID::parse(form.captcha_id)?,
-            captcha_answer: CaptchaAnswer::parse(form.captcha_answer)?,
+            captcha: CaptchaSolution {
+                id: form.captcha_id,
+                answer: form.captcha_answer,
+            },
         })
     }
 }
@@ -52,13 +63,16 @@ pub struct ResetPassword {
 }
 
 impl ResetPassword {
-    pub fn validate_reset_password_form(form: ResetPasswordForm) -> Result<Self, AppError> {
+    pub fn validate_reset_password_form(
+        form: ResetPasswordForm,
+        token_store: &TokenStore,
+    ) -> Result<Self, AppError> {
         //todo: check password length first
         if form.new_password.expose_secret() != form.new_password_confirm.expose_secret() {
             Err(FieldValidationError::InvalidPasswordFmt)?;
         }
 
-        form.try_into()
+        (form, token_store).try_into()
     }
 
     pub async fn update_password_in_db(
@@ -95,12 +109,15 @@ impl ResetPassword {
     }
 }
 
-impl TryFrom<ResetPasswordForm> for ResetPassword {
+/// The token format depends on the configured backend
+impl TryFrom<(ResetPasswordForm, &TokenStore)> for ResetPassword {
     type Error = AppError;
 
-    fn try_from(form: ResetPasswordForm) -> Result<Self, Self::Error> {
+    fn try_from(
+        (form, token_store): (ResetPasswordForm, &TokenStore),
+    ) -> Result<Self, Self::Error> {
         Ok(Self {
-            token: URLToken::parse(form.token)?,
+            token: token_store.parse(form.token)?,
             new_password: Password::parse(form.new_password)?,
         })
     }
@@ -1,5 +1,7 @@
-use crate::app_error::AppError;
-use crate::logic::{Email, FieldValidationError, Password, PasswordHash};
+use crate::app_error::{AppError, AppErrorType};
+use crate::logic::{
+    CaptchaChallenge, CaptchaSolution, Email, FieldValidationError, Password, PasswordHash,
+};
 use crate::routes::LoginForm;
 use serde::Serialize;
 use sqlx::PgPool;
@@ -10,12 +12,16 @@ use uuid::Uuid;
 pub enum AuthError {
     InvalidCredentials,
     InvalidPassword,
+    TooManyAttempts,
+    /// Too many failed attempts: the next attempt must solve this challenge
+    CaptchaRequired(CaptchaChallenge),
 }
 
 pub struct Login {
     pub email: Email,
     pub password: Password,
     pub cancel_deletion: bool,
+    pub captcha: Option<CaptchaSolution>,
 }
 
 impl Login {
@@ -24,9 +30,18 @@ impl Login {
             email: Email::parse(form.email)?,
             password: Password::parse(form.password)?,
             cancel_deletion: form.cancel_deletion,
+            captcha: form
+                .captcha_answer
+                .filter(|answer| !answer.is_empty())
+                .map(|answer| CaptchaSolution {
+                    id: form.captcha_id.unwrap_or_default(),
+                    answer,
+                }),
         })
     }
 
+    /// Unknown emails and wrong passwords both result in `AuthError::InvalidCredentials`, and an
+    /// unknown email goes through a dummy password verification so that it takes as long.
     pub async fn check_password_is_valid(&self, pool: &PgPool) -> Result<(Uuid, bool), AppError> {
         let ret = sqlx::query!(
             "select id, password_hash, requested_deletion from users where email = $1",
@@ -37,12 +52,21 @@ impl Login {
 
         if let Some(infos) = ret {
             let hash = PasswordHash::from_str(infos.password_hash);
-            self.password.verify_password(&hash)?;
+            if let Err(e) = self.password.verify_password(&hash) {
+                if matches!(e.error_type, AppErrorType::AuthError(_)) {
+                    Err(AuthError::InvalidCredentials)?;
+                }
+
+                return Err(e);
+            }
+
             Ok((
                 infos.id,
                 infos.requested_deletion.map(|_| true).unwrap_or(false),
             ))
         } else {
+            let _ = self.password.verify_password(PasswordHash::dummy());
+
             Err(AuthError::InvalidCredentials)?
         }
     }
@@ -1,8 +1,8 @@
 use crate::app_error::AppError;
 use crate::db::sqlx_user_insertion_error;
-use crate::logic::captcha::CaptchaAnswer;
 use crate::logic::{
-    CaptchaID, Email, FieldValidationError, Password, PasswordHash, URLToken, Username,
+    CaptchaSolution, Email, FieldValidationError, Password, PasswordHash, TokenStore, URLToken,
+    Username,
 };
 use crate::routes::{CreateUserForm, CreateUserRequestForm};
 use anyhow::Context;
@@ -20,8 +20,7 @@ pub enum CreateUserError {
 
 pub struct CreateUserRequest {
     pub email: Email,
-    pub captcha_id: CaptchaID,
-    pub captcha_answer: CaptchaAnswer,
+    pub captcha: CaptchaSolution,
 }
 
 impl CreateUserRequest {
@@ -38,7 +37,7 @@ impl CreateUserRequest {
             "select exists (select 1 from users where email = $1) as exists",
             self.email.as_str(),
         )
-        .fetch_one(&*pool)
+        .fetch_one(pool)
         .await?;
 
         if let Some(e) = ret.exists {
@@ -63,6 +62,17 @@ impl CreateUserRequest {
         );
         println!("{link}");
     }
+
+    pub async fn send_registration_attempt_email(&self) {
+        //todo: send registration attempt email
+        println!(
+            "{} | Hi,\nSomeone tried to register a new account with your email address, \
+            which already has an account.\nIf you are the author of this request and forgot \
+            your password, you can reset it here: https://127.0.0.1:8443/reset-password/request\n\
+            Otherwise, you can safely ignore this email.\n",
+            self.email.as_str(),
+        );
+    }
 }
 
 impl TryFrom<CreateUserRequestForm> for CreateUserRequest {
@@ -71,8 +81,10 @@ impl TryFrom<CreateUserRequestForm> for CreateUserRequest {
     fn try_from(form: CreateUserRequestForm) -> Result<Self, Self::Error> {
         Ok(Self {
             email: Email::parse(form.email)?,
-            captcha_id: CaptchaID::parse(form.captcha_id)?,
-            captcha_answer: CaptchaAnswer::parse(form.captcha_answer)?,
+            captcha: CaptchaSolution {
+                id: form.captcha_id,
+                answer: form.captcha_answer,
+            },
         })
     }
 }
@@ -84,13 +96,16 @@ pub struct CreateUser {
 }
 
 impl CreateUser {
-    pub fn validate_register_form(form: CreateUserForm) -> Result<Self, AppError> {
+    pub fn validate_register_form(
+        form: CreateUserForm,
+        token_store: &TokenStore,
+    ) -> Result<Self, AppError> {
         //todo: check password length first
         if form.password.expose_secret() != form.password_confirm.expose_secret() {
             Err(FieldValidationError::InvalidPasswordFmt)?;
         }
 
-        form.try_into()
+        (form, token_store).try_into()
     }
 
     pub async fn check_username_taken(&self, pool: &PgPool) -> Result<(), AppError> {
@@ -98,7 +113,7 @@ impl CreateUser {
             "select exists (select 1 from users where lower(username) = lower($1)) as exists",
             self.username.as_str(),
         )
-        .fetch_one(&*pool)
+        .fetch_one(pool)
         .await?;
 
         if let Some(u) = ret.exists {
@@ -121,8 +136,8 @@ impl CreateUser {
     ) -> Result<Uuid, AppError> {
         let password_hash =
             tokio::task::spawn_blocking(move || -> Result<PasswordHash, anyhow::Error> {
-                Ok(Password::generate_argon2_hash(&self.password)
-                    .with_context(|| "Failed generating password hash")?)
+                Password::generate_argon2_hash(&self.password)
+                    .with_context(|| "Failed generating password hash")
             })
             .await??;
 
@@ -141,12 +156,13 @@ impl CreateUser {
     }
 }
 
-impl TryFrom<CreateUserForm> for CreateUser {
+/// The token format depends on the configured backend
+impl TryFrom<(CreateUserForm, &TokenStore)> for CreateUser {
     type Error = AppError;
 
-    fn try_from(form: CreateUserForm) -> Result<Self, Self::Error> {
+    fn try_from((form, token_store): (CreateUserForm, &TokenStore)) -> Result<Self, Self::Error> {
         Ok(Self {
-            token: URLToken::parse(form.token)?,
+            token: token_store.parse(form.token)?,
             username: Username::parse(form.username)?,
             password: Password::parse(form.password)?,
         })
@@ -1,5 +1,7 @@
 use crate::app_error::AppError;
-use crate::logic::{Email, FieldValidationError, Password, PasswordHash, URLToken, Username};
+use crate::logic::{
+    Email, FieldValidationError, Password, PasswordHash, TokenHashKey, URLToken, Username,
+};
 use crate::routes::DeleteUserRequestForm;
 use crate::session::UserSessionError;
 use sqlx::{query, query_as, PgPool};
@@ -45,14 +47,16 @@ impl DeleteUserRequest {
     pub async fn insert_account_deletion_entry_to_db(
         &self,
         pool: &PgPool,
+        hash_key: &TokenHashKey,
     ) -> Result<(URLToken, SQLXUser), AppError> {
         let token =
             tokio::task::spawn_blocking(move || -> URLToken { URLToken::generate() }).await?;
 
+        let token_hash = hash_key.hash(token.as_str());
         let mut transaction = pool.begin().await?;
         query!(
-            "insert into account_deletions (id, account_id) values ($1, $2)",
-            token.as_str(),
+            "insert into account_deletions (token_hash, account_id) values ($1, $2)",
+            token_hash.as_str(),
             self.id
         )
         .execute(&mut *transaction)
@@ -105,11 +109,16 @@ impl CancelUserDeletion {
         })
     }
 
-    pub async fn remove_deletion_fields_with_token(&self, pool: &PgPool) -> Result<(), AppError> {
+    pub async fn remove_deletion_fields_with_token(
+        &self,
+        pool: &PgPool,
+        hash_key: &TokenHashKey,
+    ) -> Result<(), AppError> {
+        let token_hash = hash_key.hash(self.token.as_str());
         let mut transaction = pool.begin().await?;
         let ret = query!(
-            "delete from account_deletions where id = $1 returning account_id",
-            self.token.as_str()
+            "delete from account_deletions where token_hash = $1 returning account_id",
+            token_hash.as_str()
         )
         .fetch_optional(&mut *transaction)
         .await?;
@@ -148,4 +157,29 @@ impl CancelUserDeletion {
         transaction.commit().await?;
         Ok(())
     }
+
+    /// Hash the cancel tokens stored in clear before they were hashed at rest
+    /// (see the hash_account_deletion_tokens migration). Returns the number of rehashed rows.
+    pub async fn hash_legacy_tokens(pool: &PgPool, hash_key: &TokenHashKey) -> anyhow::Result<u64> {
+        let mut transaction = pool.begin().await?;
+        let legacy_tokens = query!(
+            "select token_hash as token from account_deletions where length(token_hash) = 150 for update"
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        for row in &legacy_tokens {
+            let token_hash = hash_key.hash(&row.token);
+            query!(
+                "update account_deletions set token_hash = $1 where token_hash = $2",
+                token_hash.as_str(),
+                row.token,
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(legacy_tokens.len() as u64)
+    }
 }
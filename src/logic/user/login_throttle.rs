@@ -0,0 +1,197 @@
+use crate::app_error::AppError;
+use crate::config::LoginThrottleSettings;
+use crate::logic::{AuthError, Email, Username};
+use anyhow::Context;
+use deadpool_redis::redis::{pipe, AsyncCommands};
+use deadpool_redis::Connection;
+use sqlx::{query_as, PgPool};
+
+/// Failed login tracking stored in redis.
+///
+/// Failures are counted per account (from any ip), per ip and per (ip, account) pair:
+/// - the (ip, account) counter drives an exponential backoff, slowing down a single client
+///   guessing passwords for one account.
+/// - the account counter drives a temporary lockout, stopping attackers spreading their
+///   attempts over many ips.
+/// - the account and ip counters make a captcha required, stopping bots without locking
+///   out legitimate users first.
+///
+/// Counters are kept whether or not the account exists so that responses don't reveal it.
+pub struct LoginThrottle<'a> {
+    settings: &'a LoginThrottleSettings,
+    account_key: String,
+    ip_key: String,
+    ip_account_key: String,
+    backoff_key: String,
+    lockout_key: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoginFailureOutcome {
+    Counted,
+    Delayed,
+    Locked,
+}
+
+impl<'a> LoginThrottle<'a> {
+    pub fn new(settings: &'a LoginThrottleSettings, email: &Email, ip: &str) -> Self {
+        let email = email.as_str().to_lowercase();
+        Self {
+            settings,
+            account_key: format!("login_failures:account:{email}"),
+            ip_key: format!("login_failures:ip:{ip}"),
+            ip_account_key: format!("login_failures:ip_account:{ip}:{email}"),
+            backoff_key: format!("login_backoff:{ip}:{email}"),
+            lockout_key: format!("login_lockout:{email}"),
+        }
+    }
+
+    /// Fails with `AuthError::TooManyAttempts` while the account is locked or while the
+    /// client still has to wait before its next attempt.
+    pub async fn check_allowed(&self, redis_conn: &mut Connection) -> Result<(), AppError> {
+        let (locked, delayed): (bool, bool) = pipe()
+            .exists(&self.lockout_key)
+            .exists(&self.backoff_key)
+            .query_async(redis_conn)
+            .await
+            .with_context(|| "Failed retrieving login throttling state from redis")?;
+
+        if locked || delayed {
+            Err(AuthError::TooManyAttempts)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the account or the ip has reached its captcha threshold
+    pub async fn captcha_required(&self, redis_conn: &mut Connection) -> Result<bool, AppError> {
+        let (account_failures, ip_failures): (Option<u64>, Option<u64>) = pipe()
+            .get(&self.account_key)
+            .get(&self.ip_key)
+            .query_async(redis_conn)
+            .await
+            .with_context(|| "Failed retrieving login failure counters from redis")?;
+
+        Ok(
+            account_failures.unwrap_or(0) >= self.settings.captcha_account_threshold
+                || ip_failures.unwrap_or(0) >= self.settings.captcha_ip_threshold,
+        )
+    }
+
+    pub async fn register_failure(
+        &self,
+        redis_conn: &mut Connection,
+    ) -> Result<LoginFailureOutcome, AppError> {
+        let window = self.settings.failure_window as usize;
+        let (account_failures, ip_account_failures): (u64, u64) = pipe()
+            .atomic()
+            .incr(&self.account_key, 1)
+            .expire(&self.account_key, window)
+            .ignore()
+            .incr(&self.ip_account_key, 1)
+            .expire(&self.ip_account_key, window)
+            .ignore()
+            .incr(&self.ip_key, 1)
+            .ignore()
+            .expire(&self.ip_key, window)
+            .ignore()
+            .query_async(redis_conn)
+            .await
+            .with_context(|| "Failed counting login failure in redis")?;
+
+        if account_failures >= self.settings.lockout_threshold {
+            // Counters are cleared so that the account starts from scratch once the lock expires
+            pipe()
+                .atomic()
+                .set_ex(
+                    &self.lockout_key,
+                    1,
+                    self.settings.lockout_duration as usize,
+                )
+                .ignore()
+                .del(&[&self.account_key, &self.ip_account_key, &self.backoff_key])
+                .ignore()
+                .query_async::<_, ()>(redis_conn)
+                .await
+                .with_context(|| "Failed locking account in redis")?;
+
+            return Ok(LoginFailureOutcome::Locked);
+        }
+
+        if let Some(delay) = self.backoff_delay(ip_account_failures) {
+            redis_conn
+                .set_ex::<_, _, ()>(&self.backoff_key, 1, delay as usize)
+                .await
+                .with_context(|| "Failed setting login backoff delay in redis")?;
+
+            return Ok(LoginFailureOutcome::Delayed);
+        }
+
+        Ok(LoginFailureOutcome::Counted)
+    }
+
+    /// Delay in seconds before the next attempt: base * 2^(failures - threshold), capped.
+    fn backoff_delay(&self, failures: u64) -> Option<u64> {
+        if failures < self.settings.backoff_threshold {
+            return None;
+        }
+
+        let exponent = (failures - self.settings.backoff_threshold).min(32) as u32;
+        let delay = self
+            .settings
+            .backoff_base_delay
+            .saturating_mul(2u64.saturating_pow(exponent))
+            .min(self.settings.backoff_max_delay);
+
+        (delay > 0).then_some(delay)
+    }
+
+    /// Called after a successful login. The ip counter is kept so that logging into an
+    /// attacker owned account doesn't lift the captcha requirement of the ip.
+    pub async fn reset(&self, redis_conn: &mut Connection) -> Result<(), AppError> {
+        redis_conn
+            .del::<_, ()>(&[&self.account_key, &self.ip_account_key, &self.backoff_key])
+            .await
+            .with_context(|| "Failed resetting login failure counters in redis")?;
+
+        Ok(())
+    }
+
+    pub async fn send_account_locked_email(
+        &self,
+        pool: &PgPool,
+        email: &Email,
+    ) -> Result<(), AppError> {
+        #[derive(sqlx::FromRow)]
+        struct UsernameRow {
+            username: Username,
+        }
+
+        let ret = query_as!(
+            UsernameRow,
+            "select username from users where email = $1",
+            email.as_str(),
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        // Nothing to notify if the account doesn't exist
+        if let Some(user) = ret {
+            //todo: send account locked email
+            println!(
+                "{} | Hi {},\nYour account has been temporarily locked after too many failed \
+                login attempts. You will be able to log in again in {} minutes.\n\
+                If you are not the author of these attempts, consider changing your password.\n",
+                email.as_str(),
+                user.username.as_str(),
+                self.settings.lockout_duration.div_ceil(60),
+            );
+            tracing::info!(
+                username = user.username.as_str(),
+                "account locked email sent"
+            );
+        }
+
+        Ok(())
+    }
+}
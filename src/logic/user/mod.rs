@@ -1,11 +1,13 @@
 mod authenticate;
 mod create;
 mod delete;
+mod login_throttle;
 mod update;
 mod validate;
 
 pub use self::authenticate::*;
 pub use create::*;
 pub use delete::*;
+pub use login_throttle::*;
 pub use update::*;
 pub use validate::*;
@@ -1,5 +1,6 @@
 use crate::app_error::AppError;
 use crate::logic::AuthError;
+use crate::telemetry::time_argon2;
 use anyhow::bail;
 use argon2::password_hash::rand_core::OsRng;
 use argon2::password_hash::SaltString;
@@ -7,6 +8,7 @@ use argon2::{password_hash, Argon2, PasswordHasher, PasswordVerifier};
 use secrecy::{ExposeSecret, Secret};
 use serde::{Deserialize, Serialize, Serializer};
 use sqlx::PgPool;
+use std::sync::LazyLock;
 use validator::{validate_email, HasLen};
 
 #[derive(Debug, Serialize)]
@@ -64,7 +66,7 @@ impl Username {
 
     pub fn parse(username: String) -> Result<Self, FieldValidationError> {
         let len = username.length();
-        if len < 2 || len > 30 {
+        if !(2..=30).contains(&len) {
             Err(FieldValidationError::InvalidUsernameFmt)?;
         }
 
@@ -102,17 +104,17 @@ impl From<String> for Username {
 pub struct Password(Secret<String>);
 impl Password {
     pub fn expose_as_str(&self) -> &str {
-        &self.0.expose_secret()
+        self.0.expose_secret()
     }
 
     pub fn expose_as_bytes(&self) -> &[u8] {
-        &self.0.expose_secret().as_bytes()
+        self.0.expose_secret().as_bytes()
     }
 
     pub fn parse(password: Secret<String>) -> Result<Self, FieldValidationError> {
         let pwd = password.expose_secret();
         let len = pwd.len();
-        if len < 8 || len > 100 {
+        if !(8..=100).contains(&len) {
             Err(FieldValidationError::InvalidPasswordFmt)?;
         }
 
@@ -140,7 +142,9 @@ impl Password {
         let salt = SaltString::generate(&mut OsRng);
         let argon2 = Argon2::default();
         //todo: test failure (try hashing empty string)
-        match argon2.hash_password(self.expose_as_bytes(), &salt) {
+        match time_argon2("hash", || {
+            argon2.hash_password(self.expose_as_bytes(), &salt)
+        }) {
             Ok(hash) => Ok(PasswordHash::new(hash)),
             Err(e) => bail!(e),
         }
@@ -158,27 +162,40 @@ impl Password {
             }
         };
 
-        Argon2::default()
-            .verify_password(self.expose_as_bytes(), &parsed_hash)
-            .map_err(|_| AuthError::InvalidPassword)?;
+        time_argon2("verify", || {
+            Argon2::default().verify_password(self.expose_as_bytes(), &parsed_hash)
+        })
+        .map_err(|_| AuthError::InvalidPassword)?;
 
         Ok(())
     }
 }
 
+/// Hash of a random password, verified against when an email isn't registered so that
+/// the request takes as long as a real password verification.
+static DUMMY_PASSWORD_HASH: LazyLock<PasswordHash> = LazyLock::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    let password = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_str().as_bytes(), &salt)
+        .expect("hashing dummy password");
+    PasswordHash::new(hash)
+});
+
 #[derive(Deserialize)]
 pub struct PasswordHash(Secret<String>);
 impl PasswordHash {
+    pub fn dummy() -> &'static PasswordHash {
+        &DUMMY_PASSWORD_HASH
+    }
+
     pub fn new(hash: argon2::PasswordHash) -> Self {
-        Self {
-            0: Secret::new(hash.to_string()),
-        }
+        Self(Secret::new(hash.to_string()))
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(hash_str: String) -> Self {
-        Self {
-            0: Secret::new(hash_str),
-        }
+        Self(Secret::new(hash_str))
     }
 
     pub fn expose_as_str(&self) -> &str {
@@ -198,8 +215,6 @@ impl Serialize for PasswordHash {
 // Only used in tests
 impl Default for PasswordHash {
     fn default() -> Self {
-        Self {
-            0: Secret::new("".into()),
-        }
+        Self(Secret::new("".into()))
     }
 }
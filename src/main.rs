@@ -1,29 +1,99 @@
+use anyhow::bail;
 use auth::app_error::select_return;
 use auth::config::Settings;
-use auth::server::{start_redis_fields_deletion_task, start_server, ServerSetup};
-use auth::telemetry::init_tracing;
+use auth::logic::CancelUserDeletion;
+use auth::server::{run_background_tasks_as_leader, start_server, ServerSetup};
+use auth::session::SessionKeyRing;
+use auth::shutdown::ShutdownCoordinator;
+use auth::tasks::{RestartPolicy, TaskSupervisor};
+use auth::telemetry::{init_tracing, shutdown_tracing};
+use std::path::Path;
+use tokio::time::timeout_at;
 use tracing::level_filters::LevelFilter;
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
-    init_tracing(LevelFilter::INFO)?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args
+        .first()
+        .is_some_and(|command| command == "session-keys")
+    {
+        return session_keys_command(&args[1..]);
+    }
+
+    let settings = Settings::from_env()?;
+    init_tracing(LevelFilter::INFO, &settings.tracing)?;
 
-    let settings = Settings::new("config/dev")?;
     let setup = ServerSetup::new(&settings).await?;
+    let rehashed =
+        CancelUserDeletion::hash_legacy_tokens(&setup.pg_pool, &setup.token_hash_key).await?;
+    if rehashed != 0 {
+        tracing::info!("{rehashed} legacy account deletion tokens hashed");
+    }
 
-    let task1_email = tokio::spawn(start_redis_fields_deletion_task(settings.clone(), "email"));
-    let task1_captcha = tokio::spawn(start_redis_fields_deletion_task(settings.clone(), "captcha"));
-    let srv = tokio::spawn(start_server(settings, setup));
+    let shutdown = ShutdownCoordinator::new(&settings.shutdown);
+    shutdown.listen_for_signals()?;
 
-    tokio::select! {
-        ret = srv => select_return("server", ret),
-        ret = task1_email => select_return("task1 (redis deletion: email confirm)", ret),
-        ret = task1_captcha => select_return("task1 (redis deletion: captcha)", ret),
+    // Background tasks run on the elected instance only, and never stop the server
+    let mut supervisor = TaskSupervisor::new(&shutdown.token());
+    // The leader's tasks report to the same health, so that /readyz sees them
+    let election_settings = settings.clone();
+    let task_health = supervisor.health();
+    supervisor.spawn(
+        "leader election",
+        RestartPolicy::from(&settings.task_supervisor),
+        move |token| {
+            run_background_tasks_as_leader(election_settings.clone(), token, task_health.clone())
+        },
+    );
+
+    // Every instance serves its own certificate
+    if let Some(certificates) = setup.certificates.clone() {
+        supervisor.spawn(
+            "tls certificate reload",
+            RestartPolicy::from(&settings.task_supervisor),
+            move |token| certificates.clone().watch(token),
+        );
     }
 
+    let srv = tokio::spawn(start_server(
+        settings,
+        setup,
+        shutdown.clone(),
+        supervisor.health(),
+    ));
+    select_return("server", srv.await);
+
+    // Also reached when the server stopped on its own. The tasks drained alongside the server,
+    // they only get what is left of the drain timeout.
+    shutdown.shutdown();
+    if timeout_at(shutdown.deadline(), supervisor.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::warn!("background tasks didn't stop in time");
+    }
+    shutdown_tracing().await;
+
     Ok(())
 }
 
+/// `auth session-keys generate <file>` creates a session key ring file,
+/// `auth session-keys rotate <file>` adds a new primary key to it. Instances pick up the new
+/// key on restart, and keep accepting the previous ones during the rotation window.
+fn session_keys_command(args: &[String]) -> anyhow::Result<()> {
+    match args {
+        [action, path] if action == "generate" => SessionKeyRing::generate_file(Path::new(path))?,
+        [action, path] if action == "rotate" => {
+            let rotation_window = Settings::from_env()?.session.rotation_window;
+            SessionKeyRing::rotate_file(Path::new(path), rotation_window)?;
+        }
+        _ => bail!("Usage: auth session-keys <generate|rotate> <file>"),
+    }
+
+    println!("Session keys written to {}", args[1]);
+    Ok(())
+}
 
 //todo: first ==============================
 //todo: confirm email for deletion and email change
@@ -42,9 +112,7 @@ async fn main() -> anyhow::Result<()> {
 //todo: ci
 
 //todo: task1 error if new entries appended
-//todo: log line number on unknown errors
 //todo: flush redis before starting server (removes old sessions) + lifetime for sessions in redis
-//todo: HttpsRedirect middleware not working with bind_rustls_021
 
 //todo: frontend ===========================
 //todo: port js to ts (+ use ts-rs for response types)
@@ -0,0 +1,186 @@
+use crate::client_ip::client_ip;
+use crate::config::{RateLimitKey, RateLimitSettings};
+use crate::db::get_redis_connection;
+use actix_session::SessionExt;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::web::{Data, Query};
+use actix_web::HttpResponse;
+use actix_web_lab::middleware::Next;
+use chrono::Utc;
+use deadpool_redis::redis::{pipe, RedisResult};
+use deadpool_redis::{Connection, Pool as RedisPool};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Fixed window request counters per route group, stored in redis so that every instance
+/// enforces the same limits.
+///
+/// A request is counted by the first group one of whose path prefixes matches its path,
+/// requests matching no group aren't limited. Each group counts the requests per ip, per
+/// logged in account (falling back to the ip) or per emailed token. Groups with an
+/// `ip_limit` also count the requests per ip, which bounds the tokens guessed by a client.
+pub struct RateLimiter {
+    groups: Vec<RateLimitSettings>,
+}
+
+/// State of a counter after a request, sent back as `RateLimit-*` headers
+#[derive(Debug)]
+pub struct RateLimitStatus {
+    pub limit: u64,
+    pub window: u64,
+    pub remaining: u64,
+    /// Seconds before the window resets
+    pub reset: u64,
+    pub exceeded: bool,
+}
+
+impl RateLimiter {
+    pub fn new(groups: Vec<RateLimitSettings>) -> Self {
+        Self { groups }
+    }
+
+    pub fn group(&self, path: &str) -> Option<&RateLimitSettings> {
+        self.groups.iter().find(|group| {
+            group.paths.iter().any(|prefix| {
+                path.strip_prefix(prefix.as_str()).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')
+                })
+            })
+        })
+    }
+
+    /// Count a request of `identity` in the current window of the group, against `limit`
+    pub async fn hit(
+        &self,
+        redis_conn: &mut Connection,
+        group: &RateLimitSettings,
+        identity: &str,
+        limit: u64,
+    ) -> RedisResult<RateLimitStatus> {
+        let now = Utc::now().timestamp() as u64;
+        let window_start = now - now % group.window;
+        let key = format!("rate_limit:{}:{identity}:{window_start}", group.name);
+        let (count,): (u64,) = pipe()
+            .atomic()
+            .incr(&key, 1)
+            .expire(&key, group.window as usize)
+            .ignore()
+            .query_async(redis_conn)
+            .await?;
+
+        Ok(RateLimitStatus {
+            limit,
+            window: group.window,
+            remaining: limit.saturating_sub(count),
+            reset: window_start + group.window - now,
+            exceeded: count > limit,
+        })
+    }
+
+    /// Count a request against every counter of the group, the most restrictive status is
+    /// returned
+    pub async fn hit_all(
+        &self,
+        redis_conn: &mut Connection,
+        group: &RateLimitSettings,
+        counters: &[(String, u64)],
+    ) -> RedisResult<Option<RateLimitStatus>> {
+        let mut statuses = Vec::with_capacity(counters.len());
+        for (identity, limit) in counters {
+            statuses.push(self.hit(redis_conn, group, identity, *limit).await?);
+        }
+
+        Ok(statuses
+            .into_iter()
+            .min_by_key(|status| (!status.exceeded, status.remaining)))
+    }
+}
+
+impl RateLimitStatus {
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        for (name, value) in [
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            ("ratelimit-reset", self.reset.to_string()),
+            (
+                "ratelimit-policy",
+                format!("{};w={}", self.limit, self.window),
+            ),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+    }
+}
+
+/// Identities whose requests are counted with their limit, tokens are hashed to keep them out
+/// of redis. Tokens only add a narrower counter: the per ip one always applies.
+fn counters(req: &ServiceRequest, group: &RateLimitSettings) -> Vec<(String, u64)> {
+    let ip = format!("ip:{}", client_ip(req.request()));
+    let identity = match group.key {
+        RateLimitKey::Ip => None,
+        RateLimitKey::Account => req
+            .get_session()
+            .get::<Uuid>("id")
+            .ok()
+            .flatten()
+            .map(|id| format!("account:{id}")),
+        RateLimitKey::Token => Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.get("token").cloned())
+            .map(|token| format!("token:{}", hex::encode(Sha256::digest(token)))),
+    };
+
+    match (identity, group.ip_limit) {
+        (Some(identity), Some(ip_limit)) => vec![(identity, group.limit), (ip, ip_limit)],
+        (Some(identity), None) => vec![(identity, group.limit)],
+        // e.g. tokens sent in a form, counted per ip like the requests of ip groups
+        (None, _) => vec![(ip, group.limit)],
+    }
+}
+
+/// Middleware applying the `RateLimiter`, it must be wrapped by the session middleware to
+/// count requests per account. Requests are let through when redis can't be reached.
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let limiter = req.app_data::<Data<RateLimiter>>().cloned();
+    let redis_pool = req.app_data::<Data<RedisPool>>().cloned();
+    let (Some(limiter), Some(redis_pool)) = (limiter, redis_pool) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let Some(group) = limiter.group(req.path()) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    let counters = counters(&req, group);
+    let status = match get_redis_connection(&redis_pool).await {
+        Ok(mut redis_conn) => limiter
+            .hit_all(&mut redis_conn, group, &counters)
+            .await
+            .ok()
+            .flatten(),
+        Err(_) => None,
+    };
+    let Some(status) = status else {
+        tracing::warn!(group = %group.name, "rate limit counter unavailable, request let through");
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    if status.exceeded {
+        let mut res = HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, status.reset.max(1)))
+            .finish();
+        status.insert_headers(res.headers_mut());
+        return Ok(req.into_response(res).map_into_right_body());
+    }
+
+    let mut res = next.call(req).await?;
+    status.insert_headers(res.headers_mut());
+    Ok(res.map_into_left_body())
+}
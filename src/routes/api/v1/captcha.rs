@@ -1,21 +1,27 @@
 use crate::app_error::AppError;
+use crate::client_ip::client_ip;
+use crate::config::Task1Settings;
 use crate::db::get_redis_connection;
-use crate::logic::{Captcha, CaptchaID, CaptchaResponseData};
+use crate::logic::{CaptchaChallenge, CaptchaID, CaptchaProvider};
+use actix_web::http::header::{CacheControl, CacheDirective};
 use actix_web::web::Json;
-use actix_web::{get, web};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use chrono::Utc;
 use deadpool_redis::Pool as RedisPool;
 use serde::Deserialize;
 
 #[get("/captcha")]
 async fn load_captcha(
+    req: HttpRequest,
     redis_pool: web::Data<RedisPool>,
-) -> Result<Json<CaptchaResponseData>, AppError> {
-    let captcha = Captcha::generate()?;
-    let redis_conn = get_redis_connection(&redis_pool).await?;
-    captcha.store_captcha_answer_in_redis(redis_conn).await?;
+    captcha_provider: web::Data<dyn CaptchaProvider>,
+) -> Result<Json<CaptchaChallenge>, AppError> {
+    let mut redis_conn = get_redis_connection(&redis_pool).await?;
+    let challenge = captcha_provider
+        .issue_challenge(&mut redis_conn, &client_ip(&req))
+        .await?;
 
-    let data = captcha.get_response_data();
-    Ok(Json(data))
+    Ok(Json(challenge))
 }
 
 #[derive(Deserialize)]
@@ -25,17 +31,45 @@ pub struct CaptchaIDParam {
 
 #[get("/captcha/reload")]
 async fn reload_captcha(
+    req: HttpRequest,
+    web::Query(param): web::Query<CaptchaIDParam>,
+    redis_pool: web::Data<RedisPool>,
+    captcha_provider: web::Data<dyn CaptchaProvider>,
+) -> Result<Json<CaptchaChallenge>, AppError> {
+    let id = CaptchaID::parse(param.id)?;
+
+    let mut redis_conn = get_redis_connection(&redis_pool).await?;
+    let challenge = captcha_provider
+        .reload_challenge(&mut redis_conn, id, &client_ip(&req))
+        .await?;
+
+    Ok(Json(challenge))
+}
+
+/// The answer of a captcha never changes, so the audio can be cached by the browser until
+/// the captcha expires.
+#[get("/captcha/audio")]
+async fn load_captcha_audio(
+    req: HttpRequest,
     web::Query(param): web::Query<CaptchaIDParam>,
     redis_pool: web::Data<RedisPool>,
-) -> Result<Json<CaptchaResponseData>, AppError> {
+    captcha_provider: web::Data<dyn CaptchaProvider>,
+    captcha_settings: web::Data<Task1Settings>,
+) -> Result<HttpResponse, AppError> {
     let id = CaptchaID::parse(param.id)?;
 
     let mut redis_conn = get_redis_connection(&redis_pool).await?;
-    let new_captcha = Captcha::reload_captcha(id, &mut redis_conn).await?;
-    new_captcha
-        .store_captcha_answer_in_redis(redis_conn)
+    let (audio, timestamp) = captcha_provider
+        .load_audio(&mut redis_conn, &id, &client_ip(&req))
         .await?;
 
-    let data = new_captcha.get_response_data();
-    Ok(Json(data))
+    let elapsed = (Utc::now().timestamp() - timestamp).max(0) as u64;
+    let max_age = captcha_settings.expiry_time.saturating_sub(elapsed) as u32;
+    Ok(HttpResponse::Ok()
+        .content_type("audio/wav")
+        .insert_header(CacheControl(vec![
+            CacheDirective::Private,
+            CacheDirective::MaxAge(max_age),
+        ]))
+        .body(audio.into_wav()))
 }
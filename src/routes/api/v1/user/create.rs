@@ -1,10 +1,13 @@
 use crate::app_error::AppError;
+use crate::client_ip::client_ip;
+use crate::config::SecuritySettings;
 use crate::db::get_redis_connection;
-use crate::logic::{CaptchaAnswer, CreateUser, CreateUserRequest, URLToken};
+use crate::logic::{CaptchaProvider, CreateUser, CreateUserRequest, TokenPurpose, TokenStore};
 use crate::routes::utils::see_other_303;
 use crate::session::UserSession;
+use crate::telemetry::record_registration;
 use actix_web::http::header::LOCATION;
-use actix_web::{post, web, HttpResponse};
+use actix_web::{post, web, HttpRequest, HttpResponse};
 use deadpool_redis::Pool as RedisPool;
 use secrecy::Secret;
 use serde::Deserialize;
@@ -13,17 +16,24 @@ use sqlx::PgPool;
 #[derive(Deserialize)]
 pub struct CreateUserRequestForm {
     pub email: String,
+    /// Not sent with remote captchas
+    #[serde(default)]
     pub captcha_id: String,
     pub captcha_answer: String,
     pub bzz: Option<String>,
 }
 
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip_all)]
 #[post("/create/request")]
 pub async fn create_user_request(
+    req: HttpRequest,
     web::Form(form): web::Form<CreateUserRequestForm>,
     pg_pool: web::Data<PgPool>,
     redis_pool: web::Data<RedisPool>,
+    security_settings: web::Data<SecuritySettings>,
+    captcha_provider: web::Data<dyn CaptchaProvider>,
+    token_store: web::Data<TokenStore>,
     session: UserSession,
 ) -> Result<HttpResponse, AppError> {
     if session.is_active()? {
@@ -31,18 +41,30 @@ pub async fn create_user_request(
     }
 
     let creds = CreateUserRequest::validate_register_request_form(form)?;
-    creds.check_email_taken(&pg_pool).await?;
-
     let mut redis_conn = get_redis_connection(&redis_pool).await?;
-    CaptchaAnswer::is_valid_captcha_answer(
-        &mut redis_conn,
-        &creds.captcha_answer,
-        &creds.captcha_id,
-    )
-    .await?;
+    captcha_provider
+        .verify_answer(&mut redis_conn, &creds.captcha, &client_ip(&req))
+        .await?;
+
+    // In hardened mode, respond as if the request succeeded and let the owner of the
+    // address know instead
+    let email_taken = if security_settings.hardened_mode {
+        !creds.email.is_available(&pg_pool).await?
+    } else {
+        creds.check_email_taken(&pg_pool).await?;
+        false
+    };
 
-    let url_token = URLToken::store_user_fields_to_redis(redis_conn, &creds.email).await?;
-    creds.send_confirmation_email(url_token).await;
+    // Also issued when it won't be sent, so that a taken email takes as long to answer
+    let url_token = token_store
+        .issue(redis_conn, TokenPurpose::Registration, &creds.email)
+        .await?;
+    if email_taken {
+        creds.send_registration_attempt_email().await;
+    } else {
+        creds.send_confirmation_email(url_token).await;
+        record_registration("requested");
+    }
 
     Ok(HttpResponse::Accepted().finish())
 }
@@ -61,17 +83,21 @@ pub async fn create_user(
     web::Form(form): web::Form<CreateUserForm>,
     pg_pool: web::Data<PgPool>,
     redis_pool: web::Data<RedisPool>,
+    token_store: web::Data<TokenStore>,
     session: UserSession,
 ) -> Result<HttpResponse, AppError> {
-    let creds = CreateUser::validate_register_form(form)?;
+    let creds = CreateUser::validate_register_form(form, &token_store)?;
     let redis_conn = get_redis_connection(&redis_pool).await?;
     creds.check_username_taken(&pg_pool).await?;
 
-    let user_fields = creds.token.get_associated_redis_fields(redis_conn).await?;
+    let user_fields = token_store
+        .redeem(redis_conn, &creds.token, TokenPurpose::Registration)
+        .await?;
     let user_id = creds
         .insert_user_infos_to_db(&pg_pool, user_fields.email)
         .await?;
     session.activate(user_id)?;
+    record_registration("completed");
 
     Ok(HttpResponse::Created()
         .insert_header((LOCATION, "/home"))
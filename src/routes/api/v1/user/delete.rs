@@ -1,5 +1,5 @@
 use crate::app_error::AppError;
-use crate::logic::{CancelUserDeletion, DeleteUserRequest, UpdateUserError};
+use crate::logic::{CancelUserDeletion, DeleteUserRequest, TokenHashKey, UpdateUserError};
 use crate::session::UserSession;
 use actix_web::http::header::LOCATION;
 use actix_web::{get, post, web, HttpResponse};
@@ -18,6 +18,7 @@ pub struct DeleteUserRequestForm {
 pub async fn delete_user_request(
     web::Form(form): web::Form<DeleteUserRequestForm>,
     pg_pool: web::Data<PgPool>,
+    token_hash_key: web::Data<TokenHashKey>,
     session: UserSession,
 ) -> Result<HttpResponse, AppError> {
     let id = session.get_session_id()?;
@@ -28,7 +29,9 @@ pub async fn delete_user_request(
     let creds = DeleteUserRequest::validate_delete_user_request_form(form, id)?;
     creds.verify_password(&pg_pool).await?;
 
-    let (cancel_token, user_infos) = creds.insert_account_deletion_entry_to_db(&pg_pool).await?;
+    let (cancel_token, user_infos) = creds
+        .insert_account_deletion_entry_to_db(&pg_pool, &token_hash_key)
+        .await?;
     session.deactivate();
     DeleteUserRequest::send_account_deletion_requested_email(cancel_token, user_infos).await;
 
@@ -47,9 +50,12 @@ pub struct Token {
 pub async fn cancel_delete_user_request(
     web::Query(param): web::Query<Token>,
     pg_pool: web::Data<PgPool>,
+    token_hash_key: web::Data<TokenHashKey>,
 ) -> Result<HttpResponse, AppError> {
     let token = CancelUserDeletion::from_url_token(param.token)?;
-    token.remove_deletion_fields_with_token(&pg_pool).await?;
+    token
+        .remove_deletion_fields_with_token(&pg_pool, &token_hash_key)
+        .await?;
 
     Ok(HttpResponse::Ok()
         .insert_header((LOCATION, "/login"))
@@ -1,9 +1,16 @@
-use crate::app_error::AppError;
-use crate::logic::{CancelUserDeletion, Login};
+use crate::app_error::{AppError, AppErrorType};
+use crate::client_ip::client_ip;
+use crate::config::LoginThrottleSettings;
+use crate::db::get_redis_connection;
+use crate::logic::{
+    AuthError, CancelUserDeletion, CaptchaProvider, Login, LoginFailureOutcome, LoginThrottle,
+};
 use crate::routes::utils::see_other_303;
 use crate::session::UserSession;
+use crate::telemetry::record_login;
 use actix_web::http::header::LOCATION;
-use actix_web::{post, web, HttpResponse};
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use deadpool_redis::Pool as RedisPool;
 use secrecy::Secret;
 use serde::Deserialize;
 use sqlx::PgPool;
@@ -14,31 +21,87 @@ pub struct LoginForm {
     pub password: Secret<String>,
     /// set to true if the user wish to cancel his account deletion.
     pub cancel_deletion: bool,
+    /// Only required after too many failed attempts (see `AuthError::CaptchaRequired`)
+    pub captcha_id: Option<String>,
+    pub captcha_answer: Option<String>,
 }
 
 #[tracing::instrument(skip_all)]
 #[post("/login")]
 pub async fn login_user(
+    req: HttpRequest,
     web::Form(form): web::Form<LoginForm>,
     pg_pool: web::Data<PgPool>,
+    redis_pool: web::Data<RedisPool>,
+    throttle_settings: web::Data<LoginThrottleSettings>,
+    captcha_provider: web::Data<dyn CaptchaProvider>,
     session: UserSession,
 ) -> Result<HttpResponse, AppError> {
     if session.is_active()? {
         return Ok(see_other_303("/home"));
     }
 
-    let creds = Login::validate_form_fields(form)?;
-    let (user_id, requested_deletion) = creds.check_password_is_valid(&pg_pool).await?;
-    if requested_deletion {
-        if !creds.cancel_deletion {
-            return Ok(HttpResponse::Conflict().finish());
-        } else {
-            CancelUserDeletion::remove_deletion_fields_with_user_id(&pg_pool, user_id).await?;
+    let outcome = async {
+        let creds = Login::validate_form_fields(form)?;
+        let ip = client_ip(&req);
+        let throttle = LoginThrottle::new(&throttle_settings, &creds.email, &ip);
+
+        let mut redis_conn = get_redis_connection(&redis_pool).await?;
+        throttle.check_allowed(&mut redis_conn).await?;
+        if throttle.captcha_required(&mut redis_conn).await? {
+            let solved = match &creds.captcha {
+                Some(solution) => match captcha_provider
+                    .verify_answer(&mut redis_conn, solution, &ip)
+                    .await
+                {
+                    Ok(()) => true,
+                    // The captcha may have been invalidated, the client gets a new one
+                    Err(e) if matches!(e.error_type, AppErrorType::ValidationError(_)) => false,
+                    Err(e) => return Err(e),
+                },
+                None => false,
+            };
+            if !solved {
+                let challenge = captcha_provider
+                    .issue_challenge(&mut redis_conn, &ip)
+                    .await?;
+                Err(AuthError::CaptchaRequired(challenge))?;
+            }
+        }
+
+        let (user_id, requested_deletion) = match creds.check_password_is_valid(&pg_pool).await {
+            Ok(ret) => ret,
+            Err(e) => {
+                if let AppErrorType::AuthError(_) = e.error_type {
+                    let outcome = throttle.register_failure(&mut redis_conn).await?;
+                    if outcome == LoginFailureOutcome::Locked {
+                        throttle
+                            .send_account_locked_email(&pg_pool, &creds.email)
+                            .await?;
+                    }
+                }
+
+                return Err(e);
+            }
+        };
+        throttle.reset(&mut redis_conn).await?;
+
+        if requested_deletion {
+            if !creds.cancel_deletion {
+                return Ok(HttpResponse::Conflict().finish());
+            } else {
+                CancelUserDeletion::remove_deletion_fields_with_user_id(&pg_pool, user_id).await?;
+            }
         }
+        session.activate(user_id)?;
+        tracing::info!(%user_id, client_ip = %ip, "user logged in");
+
+        Ok(HttpResponse::Ok()
+            .insert_header((LOCATION, "/home"))
+            .finish())
     }
-    session.activate(user_id)?;
+    .await;
+    record_login(&outcome);
 
-    Ok(HttpResponse::Ok()
-        .insert_header((LOCATION, "/home"))
-        .finish())
+    outcome
 }
@@ -3,14 +3,13 @@ mod data;
 mod delete;
 mod login;
 mod logout;
-mod update;
 mod reset_password;
+mod update;
 
 pub use create::*;
 pub use data::*;
 pub use delete::*;
 pub use login::*;
 pub use logout::*;
-pub use update::*;
 pub use reset_password::*;
-
+pub use update::*;
@@ -1,10 +1,15 @@
 use crate::app_error::AppError;
+use crate::client_ip::client_ip;
+use crate::config::SecuritySettings;
 use crate::db::get_redis_connection;
-use crate::logic::{CaptchaAnswer, ResetPassword, ResetPasswordRequest, URLToken};
+use crate::logic::{
+    CaptchaProvider, ResetPassword, ResetPasswordRequest, TokenPurpose, TokenStore,
+};
 use crate::routes::utils::see_other_303;
 use crate::session::UserSession;
+use crate::telemetry::record_password_reset;
 use actix_web::http::header::LOCATION;
-use actix_web::{post, web, HttpResponse};
+use actix_web::{post, web, HttpRequest, HttpResponse};
 use deadpool_redis::Pool as RedisPool;
 use secrecy::Secret;
 use serde::Deserialize;
@@ -13,16 +18,24 @@ use sqlx::PgPool;
 #[derive(Deserialize)]
 pub struct ResetPasswordRequestForm {
     pub email: String,
+    /// Not sent with remote captchas
+    #[serde(default)]
     pub captcha_id: String,
     pub captcha_answer: String,
     pub bzz: Option<String>,
 }
 
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip_all)]
 #[post("/reset-password/request")]
 pub async fn reset_user_password_request(
+    req: HttpRequest,
     web::Form(form): web::Form<ResetPasswordRequestForm>,
+    pg_pool: web::Data<PgPool>,
     redis_pool: web::Data<RedisPool>,
+    security_settings: web::Data<SecuritySettings>,
+    captcha_provider: web::Data<dyn CaptchaProvider>,
+    token_store: web::Data<TokenStore>,
     session: UserSession,
 ) -> Result<HttpResponse, AppError> {
     if session.is_active()? {
@@ -31,15 +44,23 @@ pub async fn reset_user_password_request(
 
     let creds = ResetPasswordRequest::validate_password_reset_request_form(form)?;
     let mut redis_conn = get_redis_connection(&redis_pool).await?;
-    CaptchaAnswer::is_valid_captcha_answer(
-        &mut redis_conn,
-        &creds.captcha_answer,
-        &creds.captcha_id,
-    )
-    .await?;
+    captcha_provider
+        .verify_answer(&mut redis_conn, &creds.captcha, &client_ip(&req))
+        .await?;
+
+    let unknown_email =
+        security_settings.hardened_mode && creds.email.is_available(&pg_pool).await?;
 
-    let url_token = URLToken::store_user_fields_to_redis(redis_conn, &creds.email).await?;
-    creds.send_confirmation_email(url_token).await;
+    // Also issued when it won't be sent, so that an unknown email takes as long to answer
+    let url_token = token_store
+        .issue(redis_conn, TokenPurpose::PasswordReset, &creds.email)
+        .await?;
+    if unknown_email {
+        creds.send_no_account_email().await;
+    } else {
+        creds.send_confirmation_email(url_token).await;
+        record_password_reset("requested");
+    }
 
     Ok(HttpResponse::Accepted().finish())
 }
@@ -57,15 +78,19 @@ pub async fn reset_user_password(
     web::Form(form): web::Form<ResetPasswordForm>,
     pg_pool: web::Data<PgPool>,
     redis_pool: web::Data<RedisPool>,
+    token_store: web::Data<TokenStore>,
 ) -> Result<HttpResponse, AppError> {
-    let creds = ResetPassword::validate_reset_password_form(form)?;
+    let creds = ResetPassword::validate_reset_password_form(form, &token_store)?;
     let redis_conn = get_redis_connection(&redis_pool).await?;
 
-    let user_fields = creds.token.get_associated_redis_fields(redis_conn).await?;
+    let user_fields = token_store
+        .redeem(redis_conn, &creds.token, TokenPurpose::PasswordReset)
+        .await?;
     let update_fields = creds
         .update_password_in_db(&pg_pool, user_fields.email)
         .await?;
     update_fields.send_password_updated_email().await;
+    record_password_reset("completed");
 
     Ok(HttpResponse::NoContent()
         .insert_header((LOCATION, "/login"))
@@ -0,0 +1,26 @@
+use crate::health::Readiness;
+use actix_web::{get, web, HttpResponse};
+use deadpool_redis::Pool as RedisPool;
+use serde_json::json;
+use sqlx::PgPool;
+
+/// Liveness probe, answers as long as the process serves requests
+#[get("/healthz")]
+pub async fn get_liveness() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "alive" }))
+}
+
+/// Readiness probe, 503 while starting, shutting down or when a dependency is unavailable
+#[get("/readyz")]
+pub async fn get_readiness(
+    readiness: web::Data<Readiness>,
+    pg_pool: web::Data<PgPool>,
+    redis_pool: web::Data<RedisPool>,
+) -> HttpResponse {
+    let report = readiness.check(&pg_pool, &redis_pool).await;
+    if report.ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
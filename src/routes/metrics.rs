@@ -0,0 +1,19 @@
+use crate::telemetry::record_pool_usage;
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse};
+use deadpool_redis::Pool as RedisPool;
+use metrics_exporter_prometheus::PrometheusHandle;
+use sqlx::PgPool;
+
+/// Prometheus scrape endpoint, served on the admin port when one is configured
+#[get("/metrics")]
+pub async fn get_metrics(
+    prometheus: web::Data<PrometheusHandle>,
+    pg_pool: web::Data<PgPool>,
+    redis_pool: web::Data<RedisPool>,
+) -> HttpResponse {
+    record_pool_usage(&pg_pool, &redis_pool);
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(prometheus.render())
+}
@@ -1,14 +1,18 @@
 mod api;
 mod cancel_delete_account;
+mod health;
 mod home;
 mod login;
+mod metrics;
 mod register;
 mod reset_password;
 mod settings;
 mod utils;
 
+pub use self::metrics::*;
 pub use api::*;
 pub use cancel_delete_account::*;
+pub use health::*;
 pub use home::*;
 pub use login::*;
 pub use register::*;
@@ -1,48 +1,88 @@
-use crate::config::Settings;
-use crate::logic::{CaptchaFields, CaptchaID, ConfirmEmail, URLToken};
+use crate::app_error::{form_config, json_config, query_config, report_errors};
+use crate::client_ip::TrustedProxies;
+use crate::config::{LoginThrottleSettings, SecuritySettings, Settings, Task1Settings};
+use crate::health::Readiness;
+use crate::logic::{
+    build_captcha_provider, CaptchaFields, CaptchaID, CaptchaProvider, ConfirmEmail, HashedToken,
+    LegacyConfirmEmail, PasswordHash, TokenHashKey, TokenPurpose, TokenStore,
+};
+use crate::rate_limit::{rate_limit, RateLimiter};
+use crate::routes::get_metrics;
 use crate::services::services;
-use crate::tasks::{redis_fields_deletion_task, Task1Config, Task1Error};
+use crate::session::{rotate_session_cookie, SessionKeyRing, SESSION_COOKIE_NAME};
+use crate::shutdown::ShutdownCoordinator;
+use crate::tasks::{
+    redis_fields_deletion_task, LeaderElection, RestartPolicy, SupervisorHealth, Task1Config,
+    Task1Error, TaskSupervisor,
+};
+use crate::telemetry::{init_metrics, record_http_metrics, ClientIpRootSpanBuilder};
+use crate::tls::{server_config, CertificateReloader};
 use actix_cors::Cors;
-use actix_governor::governor::middleware::NoOpMiddleware;
-use actix_governor::{Governor, GovernorConfig, GovernorConfigBuilder, PeerIpKeyExtractor};
 use actix_session::config::{BrowserSession, CookieContentSecurity};
 use actix_session::storage::RedisSessionStore;
 use actix_session::SessionMiddleware;
 use actix_web::cookie::SameSite;
+use actix_web::dev::Server;
 use actix_web::http::header;
+use actix_web::middleware::{Condition, DefaultHeaders};
 use actix_web::web::Data;
-use actix_web::{cookie, App, HttpServer};
-use actix_web_lab::middleware::RedirectHttps;
-use anyhow::{anyhow, bail};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web_lab::middleware::from_fn;
+use anyhow::bail;
 use deadpool_redis::redis::{Client, ConnectionLike};
 use deadpool_redis::{Config, Pool as RedisPool, Runtime};
-use rustls::{Certificate, PrivateKey, ServerConfig};
-use rustls_pemfile::{certs, pkcs8_private_keys};
+use metrics_exporter_prometheus::PrometheusHandle;
+use reqwest::Url;
+use rustls::ServerConfig;
 use secrecy::ExposeSecret;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
-use std::fs::File;
-use std::io::BufReader;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 use tracing_actix_web::TracingLogger;
+use uuid::Uuid;
 
-pub async fn start_server(settings: Settings, setup: ServerSetup) -> anyhow::Result<()> {
-    let host = settings.application_host.clone();
+/// Run the server until the shutdown starts, then drain in-flight requests and close the
+/// pools
+pub async fn start_server(
+    settings: Settings,
+    setup: ServerSetup,
+    shutdown: ShutdownCoordinator,
+    task_health: SupervisorHealth,
+) -> anyhow::Result<()> {
+    let readiness = Data::new(Readiness::new(shutdown.token(), task_health));
+    let server_readiness = readiness.clone();
+    let origin = Url::parse(&settings.public_url)?
+        .origin()
+        .ascii_serialization();
+    let hsts = settings.tls.enabled && settings.tls.hsts_max_age > 0;
+    let hsts_header = format!("max-age={}", settings.tls.hsts_max_age);
+    let metrics_on_main = settings.metrics.admin_port.is_none();
+    let (redis_pool, pg_pool) = (setup.redis_pool.clone(), setup.pg_pool.clone());
+    let prometheus = setup.prometheus.clone();
     let srv = HttpServer::new(move || {
         App::new()
-            .wrap(RedirectHttps::default().to_port(8443))
+            // Wrapped by the session middleware to count requests per account
+            .wrap(from_fn(rate_limit))
             .wrap(
-                SessionMiddleware::builder(setup.session_store.clone(), setup.session_pkey.clone())
-                    .cookie_secure(true)
-                    .cookie_http_only(true)
-                    .cookie_same_site(SameSite::Strict)
-                    .cookie_content_security(CookieContentSecurity::Private)
-                    .session_lifecycle(BrowserSession::default())
-                    .build(),
+                SessionMiddleware::builder(
+                    setup.session_store.clone(),
+                    setup.session_keys.primary().clone(),
+                )
+                .cookie_name(SESSION_COOKIE_NAME.into())
+                .cookie_secure(true)
+                .cookie_http_only(true)
+                .cookie_same_site(SameSite::Strict)
+                .cookie_content_security(CookieContentSecurity::Private)
+                .session_lifecycle(BrowserSession::default())
+                .build(),
             )
+            // Must wrap the session middleware to rewrite the cookie before it
+            .wrap(from_fn(rotate_session_cookie))
             .wrap(
                 Cors::default()
-                    .allowed_origin(&format!("https://{}:{}", host, settings.application_port))
+                    .allowed_origin(&origin)
                     .allowed_methods(vec!["GET", "POST", "PUT", "OPTIONS"])
                     .allowed_headers(vec![
                         header::AUTHORIZATION,
@@ -51,31 +91,147 @@ pub async fn start_server(settings: Settings, setup: ServerSetup) -> anyhow::Res
                     ])
                     .max_age(3600),
             )
-            .wrap(Governor::new(&setup.governor_config))
-            .wrap(TracingLogger::default())
+            .wrap(Condition::new(
+                hsts,
+                DefaultHeaders::new().add((header::STRICT_TRANSPORT_SECURITY, hsts_header.clone())),
+            ))
+            .wrap(from_fn(record_http_metrics))
+            // In the request span, to log the errors with the request id
+            .wrap(from_fn(report_errors))
+            .wrap(TracingLogger::<ClientIpRootSpanBuilder>::new())
+            .app_data(setup.session_keys.clone())
             .app_data(setup.redis_pool.clone())
             .app_data(setup.pg_pool.clone())
+            .app_data(setup.task1_captcha.clone())
+            .app_data(setup.captcha_provider.clone())
+            .app_data(setup.login_throttle.clone())
+            .app_data(setup.security.clone())
+            .app_data(setup.token_hash_key.clone())
+            .app_data(setup.token_store.clone())
+            .app_data(setup.rate_limiter.clone())
+            .app_data(setup.trusted_proxies.clone())
+            .app_data(server_readiness.clone())
+            .app_data(setup.prometheus.clone())
+            .app_data(json_config())
+            .app_data(form_config())
+            .app_data(query_config())
+            .configure(|cfg| {
+                if metrics_on_main {
+                    cfg.service(get_metrics);
+                }
+            })
             .configure(services)
-    })
-    .bind_rustls_021(
-        (settings.application_host, settings.application_port),
-        setup.rustls_config,
-    )?
+    });
+    let address = (settings.application_host.clone(), settings.application_port);
+    let srv = match setup.rustls_config {
+        Some(rustls_config) => srv.bind_rustls_021(address, rustls_config)?,
+        None => srv.bind(address)?,
+    }
+    .disable_signals()
+    .shutdown_timeout(shutdown.drain_timeout().as_secs())
     .run();
 
-    srv.await?;
+    let mut servers = vec![srv];
+    if let Some(port) = settings.tls.redirect_port {
+        servers.push(start_redirect_server(&settings, port, &shutdown)?);
+    }
+    if let Some(port) = settings.metrics.admin_port {
+        let pools = (redis_pool.clone(), pg_pool.clone());
+        servers.push(start_admin_server(
+            &settings, port, prometheus, pools, &shutdown,
+        )?);
+    }
+
+    readiness.set_started();
+    for handle in servers.iter().map(Server::handle) {
+        let token = shutdown.token();
+        tokio::spawn(async move {
+            token.cancelled().await;
+            // Stop accepting connections and wait for in-flight requests
+            handle.stop(true).await;
+        });
+    }
+
+    let servers: Vec<_> = servers.into_iter().map(tokio::spawn).collect();
+    for srv in servers {
+        srv.await??;
+    }
+    tracing::info!("server stopped, closing the pools");
+    redis_pool.close();
+    pg_pool.close().await;
     Ok(())
 }
 
+/// Plain http listener permanently redirecting every request to the same path on the
+/// public url
+fn start_redirect_server(
+    settings: &Settings,
+    port: u16,
+    shutdown: &ShutdownCoordinator,
+) -> anyhow::Result<Server> {
+    let public_url = settings.public_url.trim_end_matches('/').to_owned();
+    let srv = HttpServer::new(move || {
+        let public_url = public_url.clone();
+        App::new().default_service(web::to(move |req: HttpRequest| {
+            let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+            let location = format!("{public_url}{path}");
+            async move {
+                HttpResponse::PermanentRedirect()
+                    .insert_header((header::LOCATION, location))
+                    .finish()
+            }
+        }))
+    })
+    .bind((settings.application_host.clone(), port))?
+    .workers(1)
+    .disable_signals()
+    .shutdown_timeout(shutdown.drain_timeout().as_secs())
+    .run();
 
+    Ok(srv)
+}
+
+/// Plain http listener serving the metrics only
+fn start_admin_server(
+    settings: &Settings,
+    port: u16,
+    prometheus: Data<PrometheusHandle>,
+    (redis_pool, pg_pool): (Data<RedisPool>, Data<PgPool>),
+    shutdown: &ShutdownCoordinator,
+) -> anyhow::Result<Server> {
+    let srv = HttpServer::new(move || {
+        App::new()
+            .app_data(prometheus.clone())
+            .app_data(redis_pool.clone())
+            .app_data(pg_pool.clone())
+            .service(get_metrics)
+    })
+    .bind((settings.application_host.clone(), port))?
+    .workers(1)
+    .disable_signals()
+    .shutdown_timeout(shutdown.drain_timeout().as_secs())
+    .run();
+
+    Ok(srv)
+}
 
 pub struct ServerSetup {
     pub redis_pool: Data<RedisPool>,
     pub pg_pool: Data<PgPool>,
-    pub governor_config: GovernorConfig<PeerIpKeyExtractor, NoOpMiddleware>,
+    pub task1_captcha: Data<Task1Settings>,
+    pub captcha_provider: Data<dyn CaptchaProvider>,
+    pub login_throttle: Data<LoginThrottleSettings>,
+    pub security: Data<SecuritySettings>,
+    pub token_hash_key: Data<TokenHashKey>,
+    pub token_store: Data<TokenStore>,
+    pub rate_limiter: Data<RateLimiter>,
+    pub trusted_proxies: Data<TrustedProxies>,
+    pub prometheus: Data<PrometheusHandle>,
     pub session_store: RedisSessionStore,
-    pub session_pkey: cookie::Key,
-    pub rustls_config: rustls::ServerConfig,
+    pub session_keys: Data<SessionKeyRing>,
+    /// None when TLS is disabled
+    pub certificates: Option<Arc<CertificateReloader>>,
+    pub rustls_config: Option<ServerConfig>,
 }
 
 impl ServerSetup {
@@ -91,51 +247,60 @@ impl ServerSetup {
         let cfg = Config::from_url(&settings.redis.url);
         let redis_pool = Data::new(cfg.create_pool(Some(Runtime::Tokio1))?);
 
-        let governor_config = GovernorConfigBuilder::default()
-            .per_second(1)
-            .burst_size(20)
-            .finish()
-            .ok_or(anyhow!("Getting rate limiter governor configuration"))?;
+        let task1_captcha = Data::new(settings.task1_captcha.clone());
+        let captcha_provider = Data::from(build_captcha_provider(&settings.captcha)?);
+        let login_throttle = Data::new(settings.login_throttle.clone());
+        let security = Data::new(settings.security.clone());
+        // Hash the dummy password now rather than during the first login request
+        let _ = PasswordHash::dummy();
+
+        let token_hash_key = Data::new(TokenHashKey::new(settings.security.token_secret.clone())?);
+        let token_store = Data::new(TokenStore::new(
+            settings.security.token_backend,
+            (**token_hash_key).clone(),
+            settings.task1_tokens.clone(),
+        ));
+
+        let rate_limiter = Data::new(RateLimiter::new(settings.rate_limits.clone()));
+        let trusted_proxies = Data::new(TrustedProxies::new(&settings.trusted_proxies)?);
+        let prometheus = Data::new(init_metrics());
 
         let session_store = RedisSessionStore::new(&settings.redis.url).await?;
-        let session_pkey = cookie::Key::generate();
+        let session_keys = Data::new(SessionKeyRing::from_settings(&settings.session)?);
 
-        let rustls_config = Self::load_rustls_config()?;
+        let (certificates, rustls_config) = if settings.tls.enabled {
+            let certificates = Arc::new(CertificateReloader::new(&settings.tls)?);
+            let rustls_config = server_config(&settings.tls, certificates.clone())?;
+            (Some(certificates), Some(rustls_config))
+        } else {
+            (None, None)
+        };
 
         Ok(Self {
             redis_pool,
             pg_pool,
-            governor_config,
+            task1_captcha,
+            captcha_provider,
+            login_throttle,
+            security,
+            token_hash_key,
+            token_store,
+            rate_limiter,
+            trusted_proxies,
+            prometheus,
             session_store,
-            session_pkey,
+            session_keys,
+            certificates,
             rustls_config,
         })
     }
-
-    fn load_rustls_config() -> anyhow::Result<rustls::ServerConfig> {
-        let config = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth();
-
-        let cert_file = &mut BufReader::new(File::open("tls/cert.pem")?);
-        let key_file = &mut BufReader::new(File::open("tls/key.pem")?);
-
-        let cert_chain = certs(cert_file)?.into_iter().map(Certificate).collect();
-
-        let mut keys: Vec<PrivateKey> = pkcs8_private_keys(key_file)?
-            .into_iter()
-            .map(PrivateKey)
-            .collect();
-
-        if keys.is_empty() {
-            bail!("Could not locate PKCS 8 private keys.");
-        }
-
-        Ok(config.with_single_cert(cert_chain, keys.remove(0))?)
-    }
 }
 
-pub async fn start_redis_fields_deletion_task(settings: Settings, hash_name: &str) -> anyhow::Result<()> {
+pub async fn start_redis_fields_deletion_task(
+    settings: Settings,
+    hash_name: &str,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
     let redis_client = Client::open(&*settings.redis.url)?;
     if !redis_client.is_open() {
         bail!(
@@ -145,31 +310,98 @@ pub async fn start_redis_fields_deletion_task(settings: Settings, hash_name: &st
     }
 
     let task_redis_conn = redis_client.get_multiplexed_tokio_connection().await?;
+    if let Some(purpose) = TokenPurpose::from_hash_name(hash_name) {
+        let task1_settings = purpose.task1_settings(&settings);
+        let task1_cfg = Task1Config::new(
+            task_redis_conn,
+            hash_name,
+            task1_settings.expiry_time,
+            task1_settings.deletion_bulk_count,
+            shutdown,
+        );
+        redis_fields_deletion_task::<HashedToken, ConfirmEmail>(task1_cfg).await?;
+        return Ok(());
+    }
+
     match hash_name {
-        "email" => {
-            let task1_cfg = Task1Config::new(
-                task_redis_conn,
-                hash_name,
-                settings.task1_email_confirm.expiry_time,
-                settings.task1_email_confirm.deletion_bulk_count,
-            );
-            redis_fields_deletion_task::<URLToken, ConfirmEmail>(task1_cfg).await?;
-        }
         "captcha" => {
             let task1_cfg = Task1Config::new(
                 task_redis_conn,
                 hash_name,
                 settings.task1_captcha.expiry_time,
                 settings.task1_captcha.deletion_bulk_count,
+                shutdown,
             );
             redis_fields_deletion_task::<CaptchaID, CaptchaFields>(task1_cfg).await?;
         }
+        TokenPurpose::LEGACY_HASH_NAME => {
+            // Kept until its last token expired, with the longest expiry of the purposes
+            let task1_settings = TokenPurpose::ALL
+                .iter()
+                .map(|purpose| purpose.task1_settings(&settings))
+                .max_by_key(|task1_settings| task1_settings.expiry_time)
+                .unwrap();
+            let task1_cfg = Task1Config::new(
+                task_redis_conn,
+                hash_name,
+                task1_settings.expiry_time,
+                task1_settings.deletion_bulk_count,
+                shutdown,
+            )
+            .until_empty();
+            redis_fields_deletion_task::<HashedToken, LegacyConfirmEmail>(task1_cfg).await?;
+        }
         _ => Err(Task1Error::InvalidHashName)?,
     }
 
     Ok(())
 }
 
+/// Register the background tasks: one task1 worker per emailed token purpose, one for
+/// captchas and one emptying the legacy token hash, each restarted on its own when failing.
+pub fn supervise_background_tasks(supervisor: &mut TaskSupervisor, settings: &Settings) {
+    let policy = RestartPolicy::from(&settings.task_supervisor);
+    let hash_names = TokenPurpose::ALL
+        .iter()
+        .map(|purpose| purpose.hash_name())
+        .chain(["captcha", TokenPurpose::LEGACY_HASH_NAME]);
+
+    for hash_name in hash_names {
+        let settings = settings.clone();
+        supervisor.spawn(
+            format!("task1 (redis deletion: {hash_name})"),
+            policy.clone(),
+            move |shutdown| start_redis_fields_deletion_task(settings.clone(), hash_name, shutdown),
+        );
+    }
+
+    //todo: supervise start_pg_accounts_deletion_task once implemented
+}
+
+/// Run the background tasks only while this instance holds the leader lease, so that
+/// replicas don't race each other. The health of the tasks is reported in `task_health`.
+pub async fn run_background_tasks_as_leader(
+    settings: Settings,
+    shutdown: CancellationToken,
+    task_health: SupervisorHealth,
+) -> anyhow::Result<()> {
+    let redis_client = Client::open(&*settings.redis.url)?;
+    let election = LeaderElection::from_settings(
+        redis_client,
+        Uuid::new_v4().to_string(),
+        &settings.leader_election,
+    )?;
+    tracing::info!("instance {} started", election.instance_id());
+
+    election
+        .run(shutdown.clone(), || {
+            let mut supervisor = TaskSupervisor::with_health(&shutdown, task_health.clone());
+            supervise_background_tasks(&mut supervisor, &settings);
+            supervisor
+        })
+        .await
+}
+
 /*
 pub async fn start_pg_accounts_deletion_task(settings: Settings) -> anyhow::Result<()> {
 
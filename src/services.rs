@@ -1,16 +1,19 @@
 use crate::routes::{
     cancel_delete_user_request, create_user, create_user_request, delete_user_request,
-    get_account_delete_cancel_page, get_home_page, get_login_page, get_register_page,
-    get_register_request_page, get_reset_password_page, get_reset_password_request_page,
-    get_settings_page, get_user_data, load_captcha, login_user, logout_user, reload_captcha,
-    reset_user_password, reset_user_password_request, update_user,
+    get_account_delete_cancel_page, get_home_page, get_liveness, get_login_page, get_readiness,
+    get_register_page, get_register_request_page, get_reset_password_page,
+    get_reset_password_request_page, get_settings_page, get_user_data, load_captcha,
+    load_captcha_audio, login_user, logout_user, reload_captcha, reset_user_password,
+    reset_user_password_request, update_user,
 };
 use actix_files::Files;
-use actix_web::http::header::ContentType;
-use actix_web::{web, HttpResponse};
+use actix_web::http::header::{ContentType, ACCEPT};
+use actix_web::{web, HttpRequest, HttpResponse};
 
 pub fn services(cfg: &mut web::ServiceConfig) {
-    cfg.service(get_home_page)
+    cfg.service(get_liveness)
+        .service(get_readiness)
+        .service(get_home_page)
         .service(get_login_page)
         .service(get_register_request_page)
         .service(get_register_page)
@@ -33,11 +36,22 @@ pub fn services(cfg: &mut web::ServiceConfig) {
                 )
                 .service(load_captcha)
                 .service(reload_captcha)
+                .service(load_captcha_audio)
                 .service(reset_user_password_request)
                 .service(reset_user_password),
         )
         .service(Files::new("/", "static").index_file("index.html"))
-        .default_service(web::to(|| async {
+        // Browsers get the 404 page, other clients a problem body (see `report_errors`)
+        .default_service(web::to(|req: HttpRequest| async move {
+            let accepts_html = req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains("text/html"));
+            if !accepts_html {
+                return HttpResponse::NotFound().finish();
+            }
+
             HttpResponse::NotFound()
                 .content_type(ContentType::html())
                 .body(include_str!("../static/html/errors/404.html"))
@@ -0,0 +1,232 @@
+use crate::config::SessionSettings;
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderValue, COOKIE};
+use actix_web::web::Data;
+use actix_web_lab::middleware::Next;
+use anyhow::{anyhow, bail, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use secrecy::ExposeSecret;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+pub const SESSION_COOKIE_NAME: &str = "id";
+
+/// Keys encrypting the session cookies. New cookies use the primary key, cookies encrypted
+/// with a previous key are still accepted during the rotation window and re-encrypted with
+/// the primary key.
+///
+/// Key rings are stored as one `<creation timestamp> <base64 key>` entry per line, newest
+/// first. A key is replaced when a newer one is created, and dropped `rotation_window`
+/// seconds later.
+pub struct SessionKeyRing {
+    primary: Key,
+    previous: Vec<Key>,
+}
+
+struct KeyEntry {
+    created_at: i64,
+    key: Key,
+    line: String,
+}
+
+impl SessionKeyRing {
+    pub fn from_settings(settings: &SessionSettings) -> anyhow::Result<Self> {
+        Self::parse(
+            settings.keys.expose_secret(),
+            settings.rotation_window,
+            Utc::now().timestamp(),
+        )
+    }
+
+    pub fn parse(key_ring: &str, rotation_window: u64, now: i64) -> anyhow::Result<Self> {
+        let mut keys = Self::valid_entries(key_ring, rotation_window, now)?
+            .into_iter()
+            .map(|entry| entry.key);
+        let primary = keys
+            .next()
+            .ok_or(anyhow!("The session key ring is empty"))?;
+
+        Ok(Self {
+            primary,
+            previous: keys.collect(),
+        })
+    }
+
+    pub fn primary(&self) -> &Key {
+        &self.primary
+    }
+
+    /// Create a key ring file holding a single key
+    pub fn generate_file(path: &Path) -> anyhow::Result<()> {
+        if path.exists() {
+            bail!("{} already exists, rotate its keys instead", path.display());
+        }
+
+        Self::write_file(path, &[Self::new_entry(Utc::now().timestamp())])
+    }
+
+    /// Add a new primary key to the key ring file, and drop the keys past their rotation window
+    pub fn rotate_file(path: &Path, rotation_window: u64) -> anyhow::Result<()> {
+        let key_ring = fs::read_to_string(path)
+            .with_context(|| format!("Failed reading {}", path.display()))?;
+        let now = Utc::now().timestamp();
+        let mut lines = vec![Self::new_entry(now)];
+        lines.extend(
+            Self::valid_entries(&key_ring, rotation_window, now)?
+                .into_iter()
+                .map(|entry| entry.line),
+        );
+
+        Self::write_file(path, &lines)
+    }
+
+    fn new_entry(now: i64) -> String {
+        format!("{now} {}", STANDARD.encode(Key::generate().master()))
+    }
+
+    fn write_file(path: &Path, lines: &[String]) -> anyhow::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(format!("{}\n", lines.join("\n")).as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Entries still in their rotation window, newest first
+    fn valid_entries(
+        key_ring: &str,
+        rotation_window: u64,
+        now: i64,
+    ) -> anyhow::Result<Vec<KeyEntry>> {
+        let mut entries = Vec::new();
+        for line in key_ring.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (created_at, key) = line
+                .split_once(' ')
+                .ok_or(anyhow!("Invalid session key entry"))?;
+            let created_at = created_at
+                .parse()
+                .with_context(|| "Invalid session key creation timestamp")?;
+            let key = STANDARD
+                .decode(key.trim())
+                .ok()
+                .and_then(|key| Key::try_from(key.as_slice()).ok())
+                .ok_or(anyhow!("Session keys must be base64 encoded 64 bytes keys"))?;
+
+            entries.push(KeyEntry {
+                created_at,
+                key,
+                line: line.into(),
+            });
+        }
+
+        entries.sort_by_key(|entry| -entry.created_at);
+        let mut replaced_at = None;
+        entries.retain(|entry| {
+            let valid = replaced_at.is_none_or(|replaced_at: i64| {
+                replaced_at.saturating_add(rotation_window as i64) >= now
+            });
+            replaced_at = Some(entry.created_at);
+            valid
+        });
+
+        Ok(entries)
+    }
+
+    /// Re-encrypt with the primary key a session cookie encrypted with a previous key
+    fn reencrypt(&self, value: &str) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(SESSION_COOKIE_NAME, value.to_owned()));
+        if jar
+            .private(&self.primary)
+            .get(SESSION_COOKIE_NAME)
+            .is_some()
+        {
+            return None;
+        }
+
+        let session_key = self
+            .previous
+            .iter()
+            .find_map(|key| jar.private(key).get(SESSION_COOKIE_NAME))?;
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.primary).add(Cookie::new(
+            SESSION_COOKIE_NAME,
+            session_key.value().to_owned(),
+        ));
+
+        Some(jar.get(SESSION_COOKIE_NAME)?.value().to_owned())
+    }
+
+    /// Rewrite the session cookie of the request if it was encrypted with a previous key.
+    /// Returns the re-encrypted value.
+    fn rotate_request_cookie(&self, headers: &mut HeaderMap) -> Option<String> {
+        let mut cookies: Vec<Cookie> = headers
+            .get_all(COOKIE)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|cookie| Cookie::parse_encoded(cookie.trim().to_owned()).ok())
+            .collect();
+
+        let session_cookie = cookies
+            .iter_mut()
+            .find(|cookie| cookie.name() == SESSION_COOKIE_NAME)?;
+        let value = self.reencrypt(session_cookie.value())?;
+        session_cookie.set_value(value.clone());
+
+        let header = cookies
+            .iter()
+            .map(|cookie| cookie.encoded().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        headers.insert(COOKIE, HeaderValue::from_str(&header).ok()?);
+
+        Some(value)
+    }
+}
+
+/// Middleware wrapping the session middleware: session cookies encrypted with a previous key
+/// of the ring are re-encrypted before reaching it, and sent back to the client.
+pub async fn rotate_session_cookie(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let key_ring = req.app_data::<Data<SessionKeyRing>>().cloned();
+    let rotated = key_ring.and_then(|key_ring| key_ring.rotate_request_cookie(req.headers_mut()));
+
+    let mut res = next.call(req).await?;
+    if let Some(value) = rotated {
+        let renewed = res
+            .response()
+            .cookies()
+            .any(|cookie| cookie.name() == SESSION_COOKIE_NAME);
+        if !renewed {
+            // Same attributes as the cookies set by the session middleware
+            let cookie = Cookie::build(SESSION_COOKIE_NAME, value)
+                .path("/")
+                .secure(true)
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .finish();
+            res.response_mut().add_cookie(&cookie)?;
+        }
+    }
+
+    Ok(res)
+}
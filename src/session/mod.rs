@@ -1,5 +1,8 @@
 pub mod client_cache;
+mod key_ring;
+#[allow(clippy::module_inception)]
 mod session;
 
 pub use client_cache::*;
+pub use key_ring::*;
 pub use session::*;
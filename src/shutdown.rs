@@ -0,0 +1,69 @@
+use crate::config::ShutdownSettings;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Coordinates the shutdown on SIGTERM/SIGINT: the server stops accepting connections and
+/// drains in-flight requests, and background tasks finish their current batch, both within
+/// `drain_timeout`. The pools are closed once the server stopped.
+///
+/// The drain timeout is a single deadline set when the shutdown starts, shared by the server
+/// and the background tasks, so that stopping never takes longer than `drain_timeout`.
+#[derive(Clone)]
+pub struct ShutdownCoordinator {
+    token: CancellationToken,
+    drain_timeout: Duration,
+    deadline: Arc<OnceLock<Instant>>,
+}
+
+impl ShutdownCoordinator {
+    pub fn new(settings: &ShutdownSettings) -> Self {
+        Self {
+            token: CancellationToken::new(),
+            drain_timeout: Duration::from_secs(settings.drain_timeout),
+            deadline: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// Cancelled when the shutdown starts
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    /// `drain_timeout` after the shutdown started, or from now if it hasn't started yet
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+            .get()
+            .copied()
+            .unwrap_or_else(|| Instant::now() + self.drain_timeout)
+    }
+
+    /// Start the shutdown on SIGTERM or SIGINT
+    pub fn listen_for_signals(&self) -> anyhow::Result<()> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let coordinator = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sigterm.recv() => tracing::info!("SIGTERM received, shutting down"),
+                _ = sigint.recv() => tracing::info!("SIGINT received, shutting down"),
+                _ = coordinator.token.cancelled() => return,
+            }
+            coordinator.shutdown();
+        });
+
+        Ok(())
+    }
+
+    pub fn shutdown(&self) {
+        self.deadline
+            .get_or_init(|| Instant::now() + self.drain_timeout);
+        self.token.cancel();
+    }
+}
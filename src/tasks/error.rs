@@ -6,10 +6,10 @@ use thiserror::Error;
 pub enum Task1Error {
     #[error("{hash_name:} fields async iterator acquisition failed")]
     RedisIterError { err: RedisError, hash_name: String },
+    #[error("Retrieving expired {hash_name:} fields from the expiry index failed")]
+    RedisIndexError { err: RedisError, hash_name: String },
     #[error("Removing {hash_name:} fields from redis failed")]
     RedisRemovalError { err: RedisError, hash_name: String },
-    #[error("Not all {hash_name:} fields were removed from redis")]
-    Removal { hash_name: String },
     #[error("Invalid redis hash name chosen for field deletion")]
     InvalidHashName,
 }
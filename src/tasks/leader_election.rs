@@ -0,0 +1,203 @@
+use crate::config::LeaderElectionSettings;
+use crate::tasks::TaskSupervisor;
+use anyhow::bail;
+use deadpool_redis::redis::aio::Connection;
+use deadpool_redis::redis::{
+    cmd, pipe, AsyncCommands, Client, Pipeline, RedisResult, Value as RedisValue,
+};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+/// Redis lease making sure only one instance runs the background tasks. The leader renews
+/// the lease every `renew_interval`, and another instance takes over when the lease expires
+/// without being renewed (`lease_ttl`), e.g. when the leader dies.
+pub struct LeaderElection {
+    redis_client: Client,
+    /// Dedicated connection, WATCH can't be used on a multiplexed connection
+    redis_conn: Option<Connection>,
+    lease_key: String,
+    instance_id: String,
+    lease_ttl: Duration,
+    renew_interval: Duration,
+}
+
+impl LeaderElection {
+    pub const LEASE_KEY: &'static str = "background_tasks_leader";
+
+    pub fn new(
+        redis_client: Client,
+        lease_key: &str,
+        instance_id: String,
+        lease_ttl: Duration,
+        renew_interval: Duration,
+    ) -> anyhow::Result<Self> {
+        if renew_interval >= lease_ttl {
+            bail!("The lease renewal interval must be shorter than the lease ttl");
+        }
+
+        Ok(Self {
+            redis_client,
+            redis_conn: None,
+            lease_key: lease_key.into(),
+            instance_id,
+            lease_ttl,
+            renew_interval,
+        })
+    }
+
+    pub fn from_settings(
+        redis_client: Client,
+        instance_id: String,
+        settings: &LeaderElectionSettings,
+    ) -> anyhow::Result<Self> {
+        Self::new(
+            redis_client,
+            Self::LEASE_KEY,
+            instance_id,
+            Duration::from_secs(settings.lease_ttl),
+            Duration::from_secs(settings.renew_interval),
+        )
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// Take the lease if nobody holds it. Also succeeds if this instance already holds it.
+    pub async fn try_acquire(&mut self) -> RedisResult<bool> {
+        let ttl = self.lease_ttl.as_millis() as u64;
+        let (key, id) = (self.lease_key.clone(), self.instance_id.clone());
+        let conn = self.connection().await?;
+        let acquired: Option<String> = match cmd("SET")
+            .arg(&key)
+            .arg(&id)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl)
+            .query_async(conn)
+            .await
+        {
+            Ok(acquired) => acquired,
+            Err(e) => {
+                self.redis_conn = None;
+                return Err(e);
+            }
+        };
+        if acquired.is_some() {
+            return Ok(true);
+        }
+
+        self.renew().await
+    }
+
+    /// Extend the lease. Returns false if the lease is held by another instance or expired.
+    pub async fn renew(&mut self) -> RedisResult<bool> {
+        let ttl = self.lease_ttl.as_millis() as u64;
+        self.if_leader(pipe().atomic().pexpire(&self.lease_key, ttl as usize))
+            .await
+    }
+
+    /// Give up the lease so that another instance can take over right away
+    pub async fn release(&mut self) -> RedisResult<bool> {
+        self.if_leader(pipe().atomic().del(&self.lease_key)).await
+    }
+
+    /// Run the tasks started by `on_elected` each time this instance becomes the leader, and
+    /// stop them as soon as the leadership is lost. On shutdown, the tasks are stopped and the
+    /// lease is released for another instance to take over right away.
+    pub async fn run<F>(
+        mut self,
+        shutdown: CancellationToken,
+        mut on_elected: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut() -> TaskSupervisor,
+    {
+        loop {
+            match self.try_acquire().await {
+                Ok(true) => {}
+                Ok(false) => {
+                    if self.wait(&shutdown).await {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "acquiring the leader lease failed");
+                    if self.wait(&shutdown).await {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            }
+
+            tracing::info!("{} elected leader", self.instance_id);
+            let mut supervisor = on_elected();
+            let mut renewed = Instant::now();
+            loop {
+                if self.wait(&shutdown).await {
+                    supervisor.shutdown().await;
+                    self.release().await?;
+                    tracing::info!("{} released the leadership", self.instance_id);
+                    return Ok(());
+                }
+
+                match self.renew().await {
+                    Ok(true) => renewed = Instant::now(),
+                    Ok(false) => break,
+                    // The lease may have expired before the next attempt, step down before
+                    // another instance takes over
+                    Err(e) if renewed.elapsed() + self.renew_interval >= self.lease_ttl => {
+                        tracing::warn!(error = %e, "renewing the leader lease failed");
+                        break;
+                    }
+                    Err(e) => tracing::warn!(error = %e, "renewing the leader lease failed"),
+                }
+            }
+
+            tracing::warn!("{} lost the leadership", self.instance_id);
+            supervisor.shutdown().await;
+        }
+    }
+
+    /// Wait for the next lease attempt, returns true on shutdown
+    async fn wait(&self, shutdown: &CancellationToken) -> bool {
+        tokio::select! {
+            _ = shutdown.cancelled() => true,
+            _ = sleep(self.renew_interval) => false,
+        }
+    }
+
+    /// Run the transaction only if this instance holds the lease
+    async fn if_leader(&mut self, transaction: &mut Pipeline) -> RedisResult<bool> {
+        let (key, id) = (self.lease_key.clone(), self.instance_id.clone());
+        let conn = self.connection().await?;
+        let ret = async {
+            cmd("WATCH").arg(&key).query_async::<_, ()>(conn).await?;
+            let holder: Option<String> = conn.get(&key).await?;
+            if holder.as_deref() != Some(id.as_str()) {
+                cmd("UNWATCH").query_async::<_, ()>(conn).await?;
+                return Ok(false);
+            }
+
+            // Nil when the lease changed in the meantime
+            let ret: RedisValue = transaction.query_async(conn).await?;
+            Ok(ret != RedisValue::Nil)
+        }
+        .await;
+
+        if ret.is_err() {
+            // Reconnect on the next call
+            self.redis_conn = None;
+        }
+        ret
+    }
+
+    async fn connection(&mut self) -> RedisResult<&mut Connection> {
+        if self.redis_conn.is_none() {
+            self.redis_conn = Some(self.redis_client.get_async_connection().await?);
+        }
+        Ok(self.redis_conn.as_mut().unwrap())
+    }
+}
@@ -1,7 +1,11 @@
 mod error;
-mod redis_fields_deletion;
+mod leader_election;
 mod pg_accounts_deletion;
+mod redis_fields_deletion;
+mod supervisor;
 
 pub use error::*;
-pub use redis_fields_deletion::*;
+pub use leader_election::*;
 pub use pg_accounts_deletion::*;
+pub use redis_fields_deletion::*;
+pub use supervisor::*;
@@ -1,3 +1 @@
-pub struct Task2Config {
-
-}
\ No newline at end of file
+pub struct Task2Config {}
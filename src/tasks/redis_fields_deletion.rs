@@ -1,15 +1,22 @@
 use crate::tasks::error::Task1Error;
+use crate::telemetry::record_task1_removals;
 use chrono::Utc;
+use deadpool_redis::redis::aio::ConnectionLike;
 use deadpool_redis::redis::aio::MultiplexedConnection;
-use deadpool_redis::redis::{AsyncCommands, AsyncIter, FromRedisValue, ToRedisArgs};
+use deadpool_redis::redis::{
+    cmd, pipe, AsyncCommands, AsyncIter, FromRedisValue, RedisResult, ToRedisArgs,
+};
 use std::time::Duration;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 
 pub struct Task1Config<'a> {
     redis_conn: MultiplexedConnection,
     hash_name: &'a str,
     expiry_time: u64,
     deletion_bulk_count: usize,
+    shutdown: CancellationToken,
+    until_empty: bool,
 }
 
 impl<'a> Task1Config<'a> {
@@ -18,14 +25,23 @@ impl<'a> Task1Config<'a> {
         hash_name: &'a str,
         expiry_time: u64,
         deletion_bulk_count: usize,
+        shutdown: CancellationToken,
     ) -> Self {
         Self {
             redis_conn,
             hash_name,
             expiry_time,
             deletion_bulk_count,
+            shutdown,
+            until_empty: false,
         }
     }
+
+    /// Stop once the hash is empty, for hashes which aren't written to anymore
+    pub fn until_empty(mut self) -> Self {
+        self.until_empty = true;
+        self
+    }
 }
 
 /// Used for id vector allocation
@@ -37,76 +53,214 @@ pub trait Timestampable {
     fn get_timestamp(&self) -> i64;
 }
 
+/// Sorted set indexing the fields of a hash by creation timestamp, so that a cleanup pass
+/// only touches expired fields instead of scanning and decoding the whole hash.
+/// Fields removed before they expire (redeemed tokens, solved captchas) are left in the
+/// index until their expiry, where removing them is a no-op.
+pub fn expiry_index_name(hash_name: &str) -> String {
+    format!("{hash_name}_expiry")
+}
+
+/// Key set once the fields stored before the expiry index existed have been indexed
+pub fn expiry_index_complete_name(hash_name: &str) -> String {
+    format!("{hash_name}_expiry_complete")
+}
+
+/// Only a created field is indexed, so that an existing one keeps its expiry
+const HSET_NX_INDEXED: &str = r"
+if redis.call('HSETNX', KEYS[1], ARGV[1], ARGV[2]) == 0 then
+    return 0
+end
+redis.call('ZADD', KEYS[2], ARGV[3], ARGV[1])
+return 1
+";
+
+/// HSETNX a field and index it for expiry. Returns false if the field already existed.
+pub async fn hset_nx_indexed<C, K, V>(
+    redis_conn: &mut C,
+    hash_name: &str,
+    field: K,
+    value: V,
+    timestamp: i64,
+) -> RedisResult<bool>
+where
+    C: ConnectionLike,
+    K: ToRedisArgs,
+    V: ToRedisArgs,
+{
+    cmd("EVAL")
+        .arg(HSET_NX_INDEXED)
+        .arg(2)
+        .arg(hash_name)
+        .arg(expiry_index_name(hash_name))
+        .arg(field)
+        .arg(value)
+        .arg(timestamp)
+        .query_async(redis_conn)
+        .await
+}
+
+/// Remove the fields created before `expiry_time` seconds ago, by bulk of
+/// `deletion_bulk_count`. Stops after the current bulk on shutdown.
+/// Returns the number of removed fields.
+pub async fn remove_expired_fields<C, T>(
+    redis_conn: &mut C,
+    hash_name: &str,
+    expiry_time: u64,
+    deletion_bulk_count: usize,
+    shutdown: &CancellationToken,
+) -> Result<usize, Task1Error>
+where
+    C: ConnectionLike,
+    T: FromRedisValue + ToRedisArgs,
+{
+    let index_name = expiry_index_name(hash_name);
+    let max_timestamp = Utc::now().timestamp() - expiry_time as i64;
+    let mut removed_cnt = 0;
+    loop {
+        let ids: Vec<T> = cmd("ZRANGEBYSCORE")
+            .arg(&index_name)
+            .arg("-inf")
+            .arg(max_timestamp)
+            .arg("LIMIT")
+            .arg(0)
+            .arg(deletion_bulk_count)
+            .query_async(redis_conn)
+            .await
+            .map_err(|e| Task1Error::RedisIndexError {
+                err: e,
+                hash_name: hash_name.into(),
+            })?;
+
+        if ids.is_empty() {
+            break;
+        }
+
+        let (ret,): (usize,) = pipe()
+            .atomic()
+            .hdel(hash_name, &ids)
+            .zrem(&index_name, &ids)
+            .ignore()
+            .query_async(redis_conn)
+            .await
+            .map_err(|e| Task1Error::RedisRemovalError {
+                err: e,
+                hash_name: hash_name.into(),
+            })?;
+
+        tracing::info!("{ret} fields removed from {} hash", hash_name);
+        removed_cnt += ret;
+        if ids.len() < deletion_bulk_count || shutdown.is_cancelled() {
+            break;
+        }
+    }
+
+    Ok(removed_cnt)
+}
+
+/// Index the fields stored before the expiry index existed. The whole hash is scanned, so
+/// this is only done until a pass completes, which is recorded in redis.
+async fn index_unindexed_fields<T, F>(cfg: &mut Task1Config<'_>) -> Result<(), Task1Error>
+where
+    T: FromRedisValue + ToRedisArgs,
+    F: Timestampable + FromRedisValue,
+    (T, F): Send + Unpin,
+{
+    let index_name = expiry_index_name(cfg.hash_name);
+    let complete_name = expiry_index_complete_name(cfg.hash_name);
+    let index_error = |e| Task1Error::RedisIndexError {
+        err: e,
+        hash_name: cfg.hash_name.into(),
+    };
+    let complete: bool = cfg
+        .redis_conn
+        .exists(&complete_name)
+        .await
+        .map_err(index_error)?;
+    if complete {
+        return Ok(());
+    }
+
+    let mut redis_conn_c = cfg.redis_conn.clone();
+    let mut iter: AsyncIter<(T, F)> =
+        cfg.redis_conn
+            .hscan(cfg.hash_name)
+            .await
+            .map_err(|e| Task1Error::RedisIterError {
+                err: e,
+                hash_name: cfg.hash_name.into(),
+            })?;
+
+    while let Some((id, fields)) = iter.next_item().await {
+        // NX: fields already indexed keep their score
+        cmd("ZADD")
+            .arg(&index_name)
+            .arg("NX")
+            .arg(fields.get_timestamp())
+            .arg(&id)
+            .query_async::<_, ()>(&mut redis_conn_c)
+            .await
+            .map_err(index_error)?;
+    }
+
+    redis_conn_c
+        .set::<_, _, ()>(&complete_name, 1)
+        .await
+        .map_err(index_error)?;
+    tracing::info!("fields of {} hash indexed for expiry", cfg.hash_name);
+
+    Ok(())
+}
+
+/// Remove expired fields of the hash. Passes are cheap since they only touch expired fields,
+/// so they run every tenth of the expiry time and fields outlive their expiry by at most 10%.
+/// Returns once the current pass is over on shutdown.
 #[tracing::instrument(skip_all)]
 pub async fn redis_fields_deletion_task<'a, T, F>(
     mut cfg: Task1Config<'a>,
 ) -> anyhow::Result<(), Task1Error>
 where
-    T: EmptyGeneratable + FromRedisValue + ToRedisArgs + Sync + Clone,
+    T: FromRedisValue + ToRedisArgs,
     F: Timestampable + FromRedisValue,
     (T, F): Send + Unpin,
 {
-    let empty_token = T::generate_empty();
-    let mut id_vec: Vec<T> = vec![empty_token; cfg.deletion_bulk_count];
-    let mut redis_conn_c = cfg.redis_conn.clone();
+    index_unindexed_fields::<T, F>(&mut cfg).await?;
+
+    let interval = Duration::from_millis((cfg.expiry_time * 100).max(1000));
     loop {
-        let timestamp = Utc::now().timestamp();
-        let mut iter: AsyncIter<(T, F)> =
-            cfg.redis_conn
-                .hscan(&cfg.hash_name)
-                .await
-                .map_err(|e| Task1Error::RedisIterError {
-                    err: e,
-                    hash_name: cfg.hash_name.into(),
-                })?;
-
-        let mut id_index = 0;
-        while let Some((id, fields)) = iter.next_item().await {
-            if timestamp > cfg.expiry_time as i64 + fields.get_timestamp() {
-                id_vec[id_index] = id;
-                id_index += 1;
-            }
+        let removed = remove_expired_fields::<_, T>(
+            &mut cfg.redis_conn,
+            cfg.hash_name,
+            cfg.expiry_time,
+            cfg.deletion_bulk_count,
+            &cfg.shutdown,
+        )
+        .await?;
+        record_task1_removals(cfg.hash_name, removed);
 
-            // Remove fields by bulk for efficiency purpose
-            if id_index == cfg.deletion_bulk_count {
-                let ret = redis_conn_c
-                    .hdel::<&str, &Vec<T>, usize>(&cfg.hash_name, &id_vec)
+        if cfg.until_empty {
+            let index_error = |e| Task1Error::RedisIndexError {
+                err: e,
+                hash_name: cfg.hash_name.into(),
+            };
+            let exists: bool = cfg
+                .redis_conn
+                .exists(cfg.hash_name)
+                .await
+                .map_err(index_error)?;
+            if !exists {
+                cfg.redis_conn
+                    .del::<_, ()>(expiry_index_complete_name(cfg.hash_name))
                     .await
-                    .map_err(|e| Task1Error::RedisRemovalError {
-                        err: e,
-                        hash_name: cfg.hash_name.into(),
-                    })?;
-
-                if ret != cfg.deletion_bulk_count {
-                    Err(Task1Error::Removal {
-                        hash_name: cfg.hash_name.into(),
-                    })?;
-                }
-
-                tracing::info!("{ret} fields removed from {} hash", cfg.hash_name);
-                id_index = 0;
+                    .map_err(index_error)?;
+                tracing::info!("{} hash is empty, no more fields to remove", cfg.hash_name);
+                return Ok(());
             }
         }
 
-        // Remove remaining fields whose id are in the vector (from index 0 to id_index - 1)
-        if id_index != 0 {
-            let ret = redis_conn_c
-                .hdel::<&str, &[T], usize>(&cfg.hash_name, &id_vec[0..id_index])
-                .await
-                .map_err(|e| Task1Error::RedisRemovalError {
-                    err: e,
-                    hash_name: cfg.hash_name.into(),
-                })?;
-
-            if ret != id_index {
-                Err(Task1Error::Removal {
-                    hash_name: cfg.hash_name.into(),
-                })?;
-            }
-
-            tracing::info!("{ret} fields removed from {} hash", cfg.hash_name);
+        tokio::select! {
+            _ = cfg.shutdown.cancelled() => return Ok(()),
+            _ = sleep(interval) => {}
         }
-
-        sleep(Duration::from_secs(cfg.expiry_time)).await;
     }
 }
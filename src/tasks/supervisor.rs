@@ -0,0 +1,205 @@
+use crate::app_error::select_return;
+use crate::config::TaskSupervisorSettings;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+/// How a supervised task is restarted after returning an error or panicking.
+/// A task which returns `Ok` is considered done and isn't restarted.
+#[derive(Clone, Debug)]
+pub struct RestartPolicy {
+    /// Consecutive restarts allowed before giving up on the task. A run lasting longer
+    /// than `max_backoff` resets the count, so occasional transient errors never exhaust it.
+    pub max_restarts: u32,
+    /// Delay before the first restart, doubled on each consecutive restart
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RestartPolicy {
+    fn backoff(&self, restarts: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(restarts.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+impl From<&TaskSupervisorSettings> for RestartPolicy {
+    fn from(settings: &TaskSupervisorSettings) -> Self {
+        Self {
+            max_restarts: settings.max_restarts,
+            initial_backoff: Duration::from_secs(settings.initial_backoff),
+            max_backoff: Duration::from_secs(settings.max_backoff),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TaskHealth {
+    Running {
+        restarts: u32,
+    },
+    /// Waiting before the next restart
+    Restarting {
+        restarts: u32,
+        last_error: String,
+    },
+    /// Returned successfully
+    Stopped,
+    /// Gave up after too many consecutive restarts
+    Failed {
+        last_error: String,
+    },
+}
+
+/// Shared view on the health of the supervised tasks
+#[derive(Clone, Default)]
+pub struct SupervisorHealth(Arc<RwLock<BTreeMap<String, TaskHealth>>>);
+
+impl SupervisorHealth {
+    pub fn get(&self, name: &str) -> Option<TaskHealth> {
+        self.0.read().unwrap().get(name).cloned()
+    }
+
+    pub fn all(&self) -> BTreeMap<String, TaskHealth> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn set(&self, name: &str, health: TaskHealth) {
+        self.0.write().unwrap().insert(name.into(), health);
+    }
+}
+
+/// Runs named background tasks so that one failing task is restarted on its own instead of
+/// stopping the whole process. Dropping the supervisor aborts the tasks, `shutdown` lets them
+/// finish their current work.
+pub struct TaskSupervisor {
+    tasks: JoinSet<()>,
+    health: SupervisorHealth,
+    shutdown: CancellationToken,
+}
+
+impl TaskSupervisor {
+    /// The tasks are also stopped when `shutdown` is cancelled
+    pub fn new(shutdown: &CancellationToken) -> Self {
+        Self::with_health(shutdown, SupervisorHealth::default())
+    }
+
+    /// Report the health of the tasks in `health`, shared with another supervisor, so that a
+    /// nested supervisor's tasks are seen by whoever watches the outer one
+    pub fn with_health(shutdown: &CancellationToken, health: SupervisorHealth) -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            health,
+            shutdown: shutdown.child_token(),
+        }
+    }
+
+    pub fn health(&self) -> SupervisorHealth {
+        self.health.clone()
+    }
+
+    /// Register a task, `task` is called again to get a new future on each restart. The task
+    /// is given a token cancelled on shutdown, after which it should return `Ok`.
+    pub fn spawn<F, Fut>(&mut self, name: impl Into<String>, policy: RestartPolicy, task: F)
+    where
+        F: Fn(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        let health = self.health.clone();
+        let shutdown = self.shutdown.clone();
+        health.set(&name, TaskHealth::Running { restarts: 0 });
+
+        self.tasks.spawn(async move {
+            let mut restarts = 0;
+            loop {
+                health.set(&name, TaskHealth::Running { restarts });
+                let started = Instant::now();
+                // Spawned separately so that a panic is caught as a JoinError
+                let handle = tokio::spawn(task(shutdown.clone()));
+                let _abort_on_drop = AbortOnDrop(handle.abort_handle());
+                let ret = handle.await;
+                let last_error = match &ret {
+                    Ok(Ok(_)) => None,
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(e) => Some(e.to_string()),
+                };
+                select_return(&name, ret);
+
+                let Some(last_error) = last_error else {
+                    health.set(&name, TaskHealth::Stopped);
+                    return;
+                };
+                if shutdown.is_cancelled() {
+                    health.set(&name, TaskHealth::Failed { last_error });
+                    return;
+                }
+
+                if started.elapsed() > policy.max_backoff {
+                    restarts = 0;
+                }
+                if restarts >= policy.max_restarts {
+                    tracing::error!(
+                        task = %name,
+                        restarts,
+                        "{} failed too many times, giving up",
+                        name
+                    );
+                    health.set(&name, TaskHealth::Failed { last_error });
+                    return;
+                }
+
+                restarts += 1;
+                let backoff = policy.backoff(restarts);
+                tracing::warn!(
+                    task = %name,
+                    restarts,
+                    backoff_ms = backoff.as_millis() as u64,
+                    "restarting {}",
+                    name
+                );
+                health.set(
+                    &name,
+                    TaskHealth::Restarting {
+                        restarts,
+                        last_error,
+                    },
+                );
+                tokio::select! {
+                    _ = shutdown.cancelled() => {
+                        health.set(&name, TaskHealth::Stopped);
+                        return;
+                    }
+                    _ = sleep(backoff) => {}
+                }
+            }
+        });
+    }
+
+    /// Wait until every task has stopped or failed for good
+    pub async fn join(&mut self) {
+        while self.tasks.join_next().await.is_some() {}
+    }
+
+    /// Signal the tasks to stop and wait for them
+    pub async fn shutdown(&mut self) {
+        self.shutdown.cancel();
+        self.join().await;
+    }
+}
+
+/// Aborting a supervised task must also stop the run in progress
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,47 @@
+use super::traces::otlp_layer;
+use crate::client_ip::client_ip;
+use crate::config::TracingSettings;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::Error;
+use tracing::subscriber::set_global_default;
+use tracing::Span;
+use tracing_actix_web::{DefaultRootSpanBuilder, RootSpanBuilder};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+use tracing_subscriber::{EnvFilter, Registry};
+
+/// JSON logs of `level` on stdout, and the info spans exported to the OTLP collector of
+/// `settings` when one is set
+pub fn init_tracing(level: LevelFilter, settings: &TracingSettings) -> anyhow::Result<()> {
+    let formatting_layer = tracing_subscriber::fmt::layer()
+        .json()
+        .compact()
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+        .with_filter(EnvFilter::new(level.to_string()));
+    let otlp_layer = otlp_layer(settings)?.with_filter(LevelFilter::INFO);
+    let subscriber = Registry::default().with(formatting_layer).with(otlp_layer);
+
+    //LogTracer::init()?;
+    set_global_default(subscriber)?;
+
+    Ok(())
+}
+
+/// Default `TracingLogger` spans, with the client ip forwarded by trusted proxies instead of
+/// the one taken from any forwarding header
+pub struct ClientIpRootSpanBuilder;
+
+impl RootSpanBuilder for ClientIpRootSpanBuilder {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let span = DefaultRootSpanBuilder::on_request_start(request);
+        span.record("http.client_ip", client_ip(request.request()));
+        span
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::app_error::{AppError, AppErrorType};
+use crate::logic::AuthError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpResponse};
+use actix_web_lab::middleware::Next;
+use deadpool_redis::Pool as RedisPool;
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::PgPool;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Buckets of the `*_duration_seconds` histograms
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Install the prometheus recorder of the process on the first call. The handle renders the
+/// metrics in the prometheus text format.
+pub fn init_metrics() -> PrometheusHandle {
+    PROMETHEUS
+        .get_or_init(|| {
+            let recorder = PrometheusBuilder::new()
+                .set_buckets_for_metric(
+                    Matcher::Suffix("duration_seconds".into()),
+                    DURATION_BUCKETS,
+                )
+                .expect("setting the histogram buckets")
+                .build_recorder();
+            let handle = recorder.handle();
+            metrics::set_boxed_recorder(Box::new(recorder))
+                .expect("installing the metrics recorder");
+            handle
+        })
+        .clone()
+}
+
+/// Middleware counting the requests and their duration per method, route and status
+pub async fn record_http_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".into());
+
+    let res = next.call(req).await;
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", status.as_u16().to_string()),
+    ];
+    counter!("http_requests_total", 1, &labels);
+    histogram!(
+        "http_request_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        &labels
+    );
+
+    res
+}
+
+/// Count a login attempt, failures are labelled with their reason
+pub fn record_login(outcome: &Result<HttpResponse, AppError>) {
+    let reason = match outcome {
+        // The account deletion must be cancelled to log in
+        Ok(res) if res.status() == StatusCode::CONFLICT => "deletion_requested",
+        Ok(_) => return counter!("auth_logins_total", 1, "outcome" => "success"),
+        Err(e) => match &e.error_type {
+            AppErrorType::AuthError(AuthError::InvalidCredentials) => "invalid_credentials",
+            AppErrorType::AuthError(AuthError::InvalidPassword) => "invalid_password",
+            AppErrorType::AuthError(AuthError::TooManyAttempts) => "too_many_attempts",
+            AppErrorType::AuthError(AuthError::CaptchaRequired(_)) => "captcha_required",
+            AppErrorType::ValidationError(_) => "validation_error",
+            _ => "error",
+        },
+    };
+    counter!("auth_logins_total", 1, "outcome" => "failure", "reason" => reason);
+}
+
+/// `stage` is `requested` (confirmation email sent) or `completed`
+pub fn record_registration(stage: &'static str) {
+    counter!("auth_registrations_total", 1, "stage" => stage);
+}
+
+/// `stage` is `requested` (reset email sent) or `completed`
+pub fn record_password_reset(stage: &'static str) {
+    counter!("auth_password_resets_total", 1, "stage" => stage);
+}
+
+pub fn record_captcha_issued(provider: &'static str) {
+    counter!("captcha_challenges_issued_total", 1, "provider" => provider);
+}
+
+pub fn record_captcha_verification(provider: &'static str, solved: bool) {
+    let outcome = if solved { "solved" } else { "failed" };
+    counter!("captcha_verifications_total", 1, "provider" => provider, "outcome" => outcome);
+}
+
+pub fn record_task1_removals(hash_name: &str, removed: usize) {
+    counter!(
+        "task1_fields_removed_total",
+        removed as u64,
+        "hash" => hash_name.to_owned()
+    );
+}
+
+/// Time an argon2 `operation` (hash or verify)
+pub fn time_argon2<T>(operation: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let ret = f();
+    histogram!(
+        "argon2_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        "operation" => operation
+    );
+    ret
+}
+
+/// Connections in use and idle per pool, refreshed before each scrape
+pub fn record_pool_usage(pg_pool: &PgPool, redis_pool: &RedisPool) {
+    let idle = pg_pool.num_idle() as f64;
+    let size = pg_pool.size() as f64;
+    gauge!("db_pool_connections", size - idle, "pool" => "postgres", "state" => "in_use");
+    gauge!("db_pool_connections", idle, "pool" => "postgres", "state" => "idle");
+    gauge!(
+        "db_pool_max_connections",
+        pg_pool.options().get_max_connections() as f64,
+        "pool" => "postgres"
+    );
+
+    let status = redis_pool.status();
+    let idle = status.available.max(0) as f64;
+    gauge!("db_pool_connections", status.size as f64 - idle, "pool" => "redis", "state" => "in_use");
+    gauge!("db_pool_connections", idle, "pool" => "redis", "state" => "idle");
+    gauge!("db_pool_max_connections", status.max_size as f64, "pool" => "redis");
+    gauge!(
+        "db_pool_waiting",
+        (-status.available).max(0) as f64,
+        "pool" => "redis"
+    );
+}
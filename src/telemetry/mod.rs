@@ -0,0 +1,7 @@
+mod logs;
+mod metrics;
+mod traces;
+
+pub use self::metrics::*;
+pub use logs::*;
+pub use traces::*;
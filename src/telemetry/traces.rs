@@ -0,0 +1,45 @@
+use crate::config::TracingSettings;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{config, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::{global, runtime, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Layer exporting the spans to the OTLP collector, none when no endpoint is set.
+/// Request spans become children of the span of an incoming W3C `traceparent` header.
+pub(crate) fn otlp_layer<S>(
+    settings: &TracingSettings,
+) -> anyhow::Result<Option<OpenTelemetryLayer<S, Tracer>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let Some(endpoint) = &settings.otlp_endpoint else {
+        return Ok(None);
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(config().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            settings.service_name.clone(),
+        )])))
+        .install_batch(runtime::Tokio)?;
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Export the spans still buffered, before exiting
+pub async fn shutdown_tracing() {
+    // The batch exporter runs on the current runtime, which must keep running while the
+    // provider blocks on its flush
+    let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+}
@@ -0,0 +1,205 @@
+use crate::config::{ClientAuth, TlsSettings};
+use anyhow::{anyhow, bail, Context};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, NoClientAuth,
+    ResolvesServerCert,
+};
+use rustls::sign::{any_supported_type, CertifiedKey, SigningKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, SignatureScheme};
+use rustls_pemfile::{read_all, Item};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+/// Server certificate, reloaded from its files on SIGHUP or when they change on disk.
+/// New handshakes use the reloaded certificate while established connections are kept.
+/// A certificate failing to load is reported and the previous one keeps being served.
+pub struct CertificateReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    watch_interval: Duration,
+    certificate: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of the loaded files
+    loaded: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertificateReloader {
+    pub fn new(settings: &TlsSettings) -> anyhow::Result<Self> {
+        let (cert_path, key_path) = (
+            PathBuf::from(&settings.cert_path),
+            PathBuf::from(&settings.key_path),
+        );
+        let loaded = (modified(&cert_path), modified(&key_path));
+
+        Ok(Self {
+            certificate: RwLock::new(Arc::new(load_certified_key(&cert_path, &key_path)?)),
+            cert_path,
+            key_path,
+            watch_interval: Duration::from_secs(settings.watch_interval),
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    pub fn certificate(&self) -> Arc<CertifiedKey> {
+        self.certificate.read().unwrap().clone()
+    }
+
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let files = (modified(&self.cert_path), modified(&self.key_path));
+        let certificate = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.certificate.write().unwrap() = Arc::new(certificate);
+        *self.loaded.lock().unwrap() = files;
+
+        Ok(())
+    }
+
+    /// Reload the certificate on SIGHUP, or when its files changed since the last reload
+    pub async fn watch(self: Arc<Self>, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                _ = sighup.recv() => tracing::info!("SIGHUP received, reloading the certificate"),
+                _ = tokio::time::sleep(self.watch_interval) => {
+                    let files = (modified(&self.cert_path), modified(&self.key_path));
+                    if files == *self.loaded.lock().unwrap() {
+                        continue;
+                    }
+                    tracing::info!("certificate files changed, reloading the certificate");
+                }
+            }
+
+            match self.reload() {
+                Ok(()) => tracing::info!("certificate reloaded"),
+                Err(e) => tracing::error!(error = ?e, "certificate reload failed"),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateReloader {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.certificate())
+    }
+}
+
+/// Server config using the reloadable certificate, and verifying client certificates
+/// against `client_ca_path` unless `client_auth` is none (changes to the CA bundle are only
+/// picked up on restart)
+pub fn server_config(
+    settings: &TlsSettings,
+    certificates: Arc<CertificateReloader>,
+) -> anyhow::Result<ServerConfig> {
+    let verifier = match (settings.client_auth, &settings.client_ca_path) {
+        (ClientAuth::None, _) => NoClientAuth::boxed(),
+        (ClientAuth::Optional, Some(ca_path)) => {
+            AllowAnyAnonymousOrAuthenticatedClient::new(load_roots(Path::new(ca_path))?).boxed()
+        }
+        (ClientAuth::Required, Some(ca_path)) => {
+            AllowAnyAuthenticatedClient::new(load_roots(Path::new(ca_path))?).boxed()
+        }
+        (_, None) => bail!("Client certificates can't be verified without a CA bundle"),
+    };
+
+    Ok(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(certificates))
+}
+
+/// Certificate chain and private key (PKCS#8, RSA or SEC1) from PEM files. Fails if the key
+/// doesn't belong to the leaf certificate.
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> anyhow::Result<CertifiedKey> {
+    let cert_chain: Vec<Certificate> = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+    if cert_chain.is_empty() {
+        bail!("No certificate found in {}", cert_path.display());
+    }
+
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(anyhow!("No private key found in {}", key_path.display()))?;
+    let key = any_supported_type(&key)
+        .with_context(|| format!("Unsupported private key in {}", key_path.display()))?;
+    check_key_matches(&cert_chain[0], key.as_ref()).with_context(|| {
+        format!(
+            "Private key in {} doesn't match the certificate in {}",
+            key_path.display(),
+            cert_path.display()
+        )
+    })?;
+
+    Ok(CertifiedKey::new(cert_chain, key))
+}
+
+/// Sign a message with the key and verify the signature with the certificate public key
+fn check_key_matches(cert: &Certificate, key: &dyn SigningKey) -> anyhow::Result<()> {
+    const SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 4] = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+    ];
+    const MESSAGE: &[u8] = b"certificate key check";
+
+    let offered = SCHEMES.map(|(scheme, _)| scheme);
+    let signer = key
+        .choose_scheme(&offered)
+        .ok_or(anyhow!("Unsupported private key algorithm"))?;
+    let signature = signer.sign(MESSAGE)?;
+    let (_, algorithm) = SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .ok_or(anyhow!("Unsupported private key algorithm"))?;
+
+    webpki::EndEntityCert::try_from(cert.0.as_slice())?
+        .verify_signature(algorithm, MESSAGE, &signature)?;
+
+    Ok(())
+}
+
+fn load_roots(ca_path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for item in read_pem(ca_path)? {
+        if let Item::X509Certificate(cert) = item {
+            roots.add(&Certificate(cert))?;
+        }
+    }
+    if roots.is_empty() {
+        bail!("No CA certificate found in {}", ca_path.display());
+    }
+
+    Ok(roots)
+}
+
+fn read_pem(path: &Path) -> anyhow::Result<Vec<Item>> {
+    let file = File::open(path).with_context(|| format!("Failed opening {}", path.display()))?;
+    read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed reading PEM file {}", path.display()))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
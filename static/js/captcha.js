@@ -1,3 +1,6 @@
+// image, proof_of_work or remote (see the server captcha settings)
+let captchaKind = "image";
+
 async function loadCaptcha() {
     let elems = getCaptchaElements(true);
     if (!elems) {
@@ -41,7 +44,7 @@ async function reloadCaptcha() {
 
 function getCaptchaElements(isLoad) {
     let elems = {};
-    const elementNames = ["captcha-id", "captcha-img"]; //,"captcha-wav"];
+    const elementNames = ["captcha-id", "captcha-img", "captcha-wav", "captcha-answer"];
     if (isLoad) {
         elementNames.push("captcha-loading");
     }
@@ -64,17 +67,72 @@ async function populateCaptchaElements(response, elems, isLoad) {
     const json = await response.json();
     if (json) {
         const captchaJSON = JSON.stringify(json);
-        //todo: parse in id/img/wav format (typed) (captcha.img /!\)
+        //todo: parse in id/img format (typed) (captcha.img /!\)
         const captcha = JSON.parse(captchaJSON);
         if (isLoad) {
             elems["captcha-loading"].remove();
         }
-        elems["captcha-id"].value = captcha.id;
-        elems["captcha-img"].style.display = "";
-        elems["captcha-img"].src = "data:image/png;base64," + captcha.img;
-        /*
-        elems["captcha-wav"].style.display = "";
-        elems["captcha-wav"].src = "data:audio/wav;base64," + captcha.wav;
-         */
+        await displayCaptchaChallenge(captcha, elems);
+    }
+}
+
+async function displayCaptchaChallenge(captcha, elems) {
+    captchaKind = captcha.kind;
+    switch (captcha.kind) {
+        case "image":
+            elems["captcha-id"].value = captcha.id;
+            elems["captcha-img"].style.display = "";
+            elems["captcha-img"].src = "data:image/png;base64," + captcha.img;
+            elems["captcha-wav"].style.display = "";
+            elems["captcha-wav"].src = "/api/v1/captcha/audio?id=" + captcha.id;
+            break;
+        case "proof_of_work":
+            elems["captcha-id"].value = captcha.id;
+            elems["captcha-answer"].parentElement.style.display = "none";
+            elems["captcha-answer"].value = await solveProofOfWork(captcha.challenge, captcha.difficulty);
+            break;
+        case "remote":
+            loadRemoteCaptcha(captcha, elems);
+            break;
+    }
+}
+
+async function solveProofOfWork(challenge, difficulty) {
+    const encoder = new TextEncoder();
+    for (let nonce = 0; ; nonce++) {
+        const digest = await crypto.subtle.digest("SHA-256", encoder.encode(challenge + nonce));
+        if (leadingZeroBits(new Uint8Array(digest)) >= difficulty) {
+            return nonce.toString();
+        }
+    }
+}
+
+function leadingZeroBits(bytes) {
+    let zeros = 0;
+    for (const b of bytes) {
+        if (b !== 0) {
+            return zeros + Math.clz32(b) - 24;
+        }
+        zeros += 8;
     }
-}
\ No newline at end of file
+    return zeros;
+}
+
+function loadRemoteCaptcha(captcha, elems) {
+    elems["captcha-answer"].parentElement.style.display = "none";
+    const widget = document.createElement("div");
+    // class names understood by hCaptcha and Turnstile widgets
+    widget.className = "h-captcha cf-turnstile";
+    widget.dataset.sitekey = captcha.site_key;
+    widget.dataset.callback = "onRemoteCaptchaSolved";
+    elems["captcha-img"].after(widget);
+
+    const script = document.createElement("script");
+    script.src = captcha.script_url;
+    script.async = true;
+    document.head.appendChild(script);
+}
+
+function onRemoteCaptchaSolved(token) {
+    document.getElementById("captcha-answer").value = token;
+}
@@ -31,7 +31,7 @@ async function sendCancelDeletionForm(token) {
             window.location.href = window.location.origin + location;
             return;
         }
-    } else if (resp.status === 400) {
+    } else if (isAPIError(resp)) {
         const json = await resp.json();
         displayAPIError(json, "account deletion cancellation");
         return;
@@ -64,7 +64,7 @@ async function sendDeleteForm(deleteForm) {
             window.location.href = window.location.origin + location;
             return;
         }
-    } else if (resp.status === 400) {
+    } else if (isAPIError(resp)) {
         const json = await resp.json();
         displayAPIError(json, "account deletion");
         return;
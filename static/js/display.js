@@ -7,19 +7,25 @@ function displayAPIResult(result) {
     apiResultDiv.textContent = result;
 }
 
+// Error responses of the API are problem+json bodies (RFC 7807)
+function isAPIError(resp) {
+    const contentType = resp.headers.get("Content-Type");
+    return contentType !== null && contentType.startsWith("application/problem+json");
+}
+
 function displayAPIError(json, actionType) {
-    const error = Object.keys(json)[0];
-    if (error === "unknown") {
-        displayAPIError("Unexpected error happened during " + actionType);
+    console.error("Error code:", json.code, "| Correlation id:", json.correlation_id);
+    if (json.code === "invalid_session_cookie") {
+        window.location.href = window.location.origin + "/login";
         return;
     }
 
-    if (error === "session_error") {
-        console.error("Error type: session app_error | Error description", json[error]);
-        window.location.href = window.location.origin + "/login";
+    if (json.status >= 500) {
+        // the correlation id lets the support find the error in the server logs
+        displayAPIResult("Unexpected error happened during " + actionType
+            + " (reference: " + json.correlation_id + ")");
+        return;
     }
 
-    const errorDescription = json[error];
-    console.error("Error type:", error, "| Error description:", errorDescription);
-    displayAPIResult(errorDescription.replaceAll("_", " "));
-}
\ No newline at end of file
+    displayAPIResult(json.detail);
+}
@@ -45,8 +45,22 @@ async function sendLoginForm(loginForm, cancelBtn, isCancelForm) {
     } else if (resp.status === 409) {
         cancelBtn.style.display = "";
         return;
-    } else if (resp.status === 400) {
+    } else if (isAPIError(resp)) {
         const json = await resp.json();
+        if (json.code === "captcha_required") {
+            // too many failed attempts or a wrong answer, the next one must solve this captcha
+            const answered = loginFormData.get("captcha_answer");
+            const elems = getCaptchaElements(false);
+            if (elems) {
+                document.getElementById("captcha").style.display = "";
+                elems["captcha-answer"].value = "";
+                await displayCaptchaChallenge(json.captcha, elems);
+            }
+            displayAPIResult(answered
+                ? "The captcha answer is wrong, please solve the new one"
+                : "Please solve the captcha to continue");
+            return;
+        }
         displayAPIError(json, "authentication");
         return;
     }
@@ -48,12 +48,16 @@ function validateRegisterRequestForm(registerRequestForm) {
         return false;
     }
 
+    if (captchaKind === "remote") {
+        return true;
+    }
+
     if (!isValidCaptchaIDFmt(fields[1])) {
         displayAPIResult("Failed loading captcha");
         return false;
     }
 
-    if (!isValidCaptchaAnswerFmt(fields[2])) {
+    if (captchaKind === "image" && !isValidCaptchaAnswerFmt(fields[2])) {
         displayAPIResult("invalid captcha answer");
         return false;
     }
@@ -75,7 +79,7 @@ async function sendRegisterRequestForm(registerRequestForm) {
         displayAPIResult("An email has been sent to your address.\
             Please click on the link contained in this email to confirm your account creation.")
         return;
-    } else if (resp.status === 400) {
+    } else if (isAPIError(resp)) {
         const json = await resp.json();
         displayAPIError(json, "registration");
         return;
@@ -136,7 +140,7 @@ async function sendRegisterForm(registerForm) {
             window.location.href = window.location.origin + location;
             return;
         }
-    } else if (resp.status === 400) {
+    } else if (isAPIError(resp)) {
         const json = await resp.json();
         displayAPIError(json, "registration");
         return;
@@ -90,7 +90,7 @@ async function sendPasswordResetRequestForm(resetPasswordRequestForm) {
         displayAPIResult("An email has been sent to your address.\
             Please click on the link contained in this email verify your identity.")
         return;
-    } else if (resp.status === 400) {
+    } else if (isAPIError(resp)) {
         const json = await resp.json();
         displayAPIError(json, "password reset request");
         return;
@@ -116,7 +116,7 @@ async function sendPasswordResetForm(resetPasswordForm) {
             window.location.href = window.location.origin + location;
             return;
         }
-    } else if (resp.status === 400) {
+    } else if (isAPIError(resp)) {
         const json = await resp.json();
         displayAPIError(json, "password reset");
         return;
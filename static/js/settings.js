@@ -25,7 +25,7 @@ async function getSessionUserData() {
         return JSON.parse(sessionUserData);
     } else {
         const resp = await fetch('/api/v1/user/data');
-        if (resp.status === 400) {
+        if (isAPIError(resp)) {
             const json = await resp.json();
             displayAPIError(json, "update of credentials");
             return;
@@ -102,7 +102,7 @@ async function sendUpdateForm(updateForm, cachedUserData) {
         }
 
         updateSessionUserData(newCacheData);
-    } else if (resp.status === 400) {
+    } else if (isAPIError(resp)) {
         const json = await resp.json();
         displayAPIError(json, "update of credentials");
     }
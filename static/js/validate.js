@@ -38,7 +38,8 @@ function passwordConfirmMatchPassword(newPassword, newPasswordConfirm) {
 function isValidCaptchaAnswerFmt(answer) {
     const len = answer.length;
     const validChars = /^[a-zA-Z0-9]+$/;
-    return len > 3 && len < 7 && validChars.test(answer);
+    // length and charset are configured server side
+    return len > 0 && len <= 32 && validChars.test(answer);
 }
 
 function isValidCaptchaIDFmt(id) {
@@ -0,0 +1,502 @@
+use crate::utils::start_test_server;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use auth::config::{ImageCaptchaSettings, RemoteCaptchaSettings, Settings};
+use auth::logic::{
+    CaptchaFields, CaptchaID, CaptchaProvider, CaptchaSolution, ImageCaptchaProvider,
+    ProofOfWorkCaptchaProvider, RemoteCaptchaProvider,
+};
+use deadpool_redis::redis::AsyncCommands;
+use secrecy::Secret;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Deserialize)]
+struct CaptchaResponse {
+    id: String,
+    img: String,
+}
+
+fn wav_samples(wav: &[u8]) -> Vec<f64> {
+    hound::WavReader::new(wav)
+        .unwrap()
+        .samples::<i16>()
+        .map(|s| s.unwrap() as f64)
+        .collect()
+}
+
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let mean = |x: &[f64]| x.iter().sum::<f64>() / x.len() as f64;
+    let (ma, mb) = (mean(a), mean(b));
+    let (mut cov, mut va, mut vb) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - ma) * (y - mb);
+        va += (x - ma).powi(2);
+        vb += (y - mb).powi(2);
+    }
+
+    cov / (va * vb).sqrt()
+}
+
+/// Clip of the `captcha` crate for each character of `charset`, preceded by a half second
+/// of silence
+fn reference_clips(charset: &str) -> Vec<(char, Vec<f64>)> {
+    charset
+        .chars()
+        .map(|c| {
+            let mut spoken = captcha::Captcha::new();
+            spoken.set_chars(&[c]).add_char();
+            let clip = spoken.as_wav().pop().flatten().unwrap();
+            let mut samples = vec![0.0; 11025];
+            samples.extend(wav_samples(&clip));
+            (c, samples)
+        })
+        .collect()
+}
+
+/// Transcribe a spoken captcha by matching each segment against the reference clips
+fn transcribe(audio: &[f64], references: &[(char, Vec<f64>)]) -> String {
+    let mut transcription = String::new();
+    let mut offset = 0;
+    while let Some((c, len, _)) = references
+        .iter()
+        .filter(|(_, reference)| offset + reference.len() <= audio.len())
+        .map(|(c, reference)| {
+            let segment = &audio[offset..offset + reference.len()];
+            (*c, reference.len(), correlation(segment, reference))
+        })
+        .max_by(|a, b| a.2.total_cmp(&b.2))
+    {
+        transcription.push(c);
+        offset += len;
+    }
+
+    transcription
+}
+
+fn swap_case(s: &str) -> String {
+    s.chars()
+        .map(|c| match c.is_ascii_uppercase() {
+            true => c.to_ascii_lowercase(),
+            false => c.to_ascii_uppercase(),
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn audio_captcha_is_not_transcribed_by_matching_the_clips() {
+    let utils = start_test_server().await;
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    let references = reference_clips(&image_settings().audio_charset);
+    // The clips laid out one after another are transcribed
+    let plain: Vec<f64> = "2943"
+        .chars()
+        .flat_map(|c| references.iter().find(|(r, _)| *r == c).unwrap().1.clone())
+        .collect();
+    assert_eq!(transcribe(&plain, &references), "2943");
+
+    for _ in 0..3 {
+        let res = utils
+            .http_client
+            .get("https://127.0.0.1:8443/api/v1/captcha")
+            .send()
+            .await
+            .unwrap();
+        let captcha: CaptchaResponse = serde_json::from_slice(&res.bytes().await.unwrap()).unwrap();
+        assert!(!captcha.img.is_empty());
+
+        let res = utils
+            .http_client
+            .get(format!(
+                "https://127.0.0.1:8443/api/v1/captcha/audio?id={}",
+                captcha.id
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+        assert_eq!(res.headers()["content-type"], "audio/wav");
+        assert!(res.headers()["cache-control"]
+            .to_str()
+            .unwrap()
+            .starts_with("private, max-age="));
+        let audio = wav_samples(&res.bytes().await.unwrap());
+
+        let fields: CaptchaFields = redis_conn.hget("captcha", &captcha.id).await.unwrap();
+        let spoken = fields.audio_answer.unwrap();
+        assert_ne!(transcribe(&audio, &references), spoken.as_str());
+    }
+}
+
+#[actix_web::test]
+async fn audio_answer_solves_the_captcha() {
+    let utils = start_test_server().await;
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    let settings = image_settings();
+    let provider = ImageCaptchaProvider::new(settings.clone(), 3).unwrap();
+    let (id, _) = issue_image_captcha(&provider, &mut redis_conn, "10.0.0.1").await;
+
+    let fields: CaptchaFields = redis_conn.hget("captcha", &id).await.unwrap();
+    let spoken = fields.audio_answer.unwrap();
+    let len = spoken.as_str().len() as u32;
+    assert!((settings.min_length..=settings.max_length).contains(&len));
+    assert!(spoken
+        .as_str()
+        .chars()
+        .all(|c| settings.audio_charset.contains(c)));
+
+    let captcha_id = CaptchaID::parse(id.clone()).unwrap();
+    provider
+        .load_audio(&mut redis_conn, &captcha_id, "10.0.0.1")
+        .await
+        .unwrap();
+    let solution = CaptchaSolution {
+        id,
+        answer: spoken.as_str().into(),
+    };
+    assert!(provider
+        .verify_answer(&mut redis_conn, &solution, "10.0.0.1")
+        .await
+        .is_ok());
+}
+
+#[actix_web::test]
+async fn image_captcha_answer_is_case_sensitive_after_serving_the_audio() {
+    let utils = start_test_server().await;
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    let provider = ImageCaptchaProvider::new(image_settings(), 3).unwrap();
+    let (id, answer) = loop {
+        let (id, answer) = issue_image_captcha(&provider, &mut redis_conn, "10.0.0.1").await;
+        if answer.chars().any(|c| c.is_ascii_alphabetic()) {
+            break (id, answer);
+        }
+    };
+
+    let captcha_id = CaptchaID::parse(id.clone()).unwrap();
+    provider
+        .load_audio(&mut redis_conn, &captcha_id, "10.0.0.1")
+        .await
+        .unwrap();
+    let swapped = CaptchaSolution {
+        id: id.clone(),
+        answer: swap_case(&answer),
+    };
+    assert!(provider
+        .verify_answer(&mut redis_conn, &swapped, "10.0.0.1")
+        .await
+        .is_err());
+    let solution = CaptchaSolution { id, answer };
+    assert!(provider
+        .verify_answer(&mut redis_conn, &solution, "10.0.0.1")
+        .await
+        .is_ok());
+}
+
+#[actix_web::test]
+async fn reloaded_captcha_audio_is_no_longer_served() {
+    let utils = start_test_server().await;
+    let res = utils
+        .http_client
+        .get("https://127.0.0.1:8443/api/v1/captcha")
+        .send()
+        .await
+        .unwrap();
+    let captcha: CaptchaResponse = serde_json::from_slice(&res.bytes().await.unwrap()).unwrap();
+
+    utils
+        .http_client
+        .get(format!(
+            "https://127.0.0.1:8443/api/v1/captcha/reload?id={}",
+            captcha.id
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    let res = utils
+        .http_client
+        .get(format!(
+            "https://127.0.0.1:8443/api/v1/captcha/audio?id={}",
+            captcha.id
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_client_error());
+}
+
+#[actix_web::test]
+async fn proof_of_work_solution_is_single_use() {
+    let utils = start_test_server().await;
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    let provider = ProofOfWorkCaptchaProvider::new(8, 3);
+
+    let challenge = provider
+        .issue_challenge(&mut redis_conn, "127.0.0.1")
+        .await
+        .unwrap();
+    let challenge = serde_json::to_value(challenge).unwrap();
+    assert_eq!(challenge["kind"], "proof_of_work");
+    let id = challenge["id"].as_str().unwrap().to_string();
+    let prefix = challenge["challenge"].as_str().unwrap();
+
+    let nonce = (0u64..)
+        .map(|n| n.to_string())
+        .find(|n| ProofOfWorkCaptchaProvider::is_valid_nonce(prefix, n, 8))
+        .unwrap();
+    let wrong_nonce = (0u64..)
+        .map(|n| n.to_string())
+        .find(|n| !ProofOfWorkCaptchaProvider::is_valid_nonce(prefix, n, 8))
+        .unwrap();
+
+    let wrong = CaptchaSolution {
+        id: id.clone(),
+        answer: wrong_nonce,
+    };
+    assert!(provider
+        .verify_answer(&mut redis_conn, &wrong, "127.0.0.1")
+        .await
+        .is_err());
+
+    let solution = CaptchaSolution { id, answer: nonce };
+    assert!(provider
+        .verify_answer(&mut redis_conn, &solution, "127.0.0.1")
+        .await
+        .is_ok());
+    assert!(provider
+        .verify_answer(&mut redis_conn, &solution, "127.0.0.1")
+        .await
+        .is_err());
+}
+
+/// Mock hCaptcha/Turnstile siteverify endpoint accepting a single token
+async fn site_verify(web::Form(form): web::Form<HashMap<String, String>>) -> HttpResponse {
+    let success = form.get("secret").is_some_and(|s| s == "mock-secret")
+        && form.get("response").is_some_and(|r| r == "solved-token")
+        && form.get("remoteip").is_some_and(|ip| ip == "127.0.0.1");
+
+    HttpResponse::Ok().json(serde_json::json!({ "success": success }))
+}
+
+#[actix_web::test]
+async fn remote_captcha_token_is_checked_by_the_verification_server() {
+    let utils = start_test_server().await;
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+
+    let mock = HttpServer::new(|| App::new().route("/siteverify", web::post().to(site_verify)))
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+    let addr = mock.addrs()[0];
+    tokio::spawn(mock.run());
+
+    let provider = RemoteCaptchaProvider::new(&RemoteCaptchaSettings {
+        verify_url: format!("http://{addr}/siteverify"),
+        script_url: "https://captcha.example/api.js".into(),
+        site_key: "mock-site-key".into(),
+        secret: Secret::new("mock-secret".into()),
+    })
+    .unwrap();
+
+    let challenge = provider
+        .issue_challenge(&mut redis_conn, "127.0.0.1")
+        .await
+        .unwrap();
+    let challenge = serde_json::to_value(challenge).unwrap();
+    assert_eq!(challenge["kind"], "remote");
+    assert_eq!(challenge["site_key"], "mock-site-key");
+
+    for (token, valid) in [("solved-token", true), ("forged-token", false), ("", false)] {
+        let solution = CaptchaSolution {
+            id: "".into(),
+            answer: token.into(),
+        };
+        let ret = provider
+            .verify_answer(&mut redis_conn, &solution, "127.0.0.1")
+            .await;
+        assert_eq!(ret.is_ok(), valid, "token: {token}");
+    }
+}
+
+fn image_settings() -> ImageCaptchaSettings {
+    Settings::new("test").unwrap().captcha.image
+}
+
+/// Issue an image captcha to `client_ip` and return its id and answer
+async fn issue_image_captcha(
+    provider: &ImageCaptchaProvider,
+    redis_conn: &mut deadpool_redis::Connection,
+    client_ip: &str,
+) -> (String, String) {
+    let challenge = provider
+        .issue_challenge(redis_conn, client_ip)
+        .await
+        .unwrap();
+    let id = serde_json::to_value(challenge).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let fields: CaptchaFields = redis_conn.hget("captcha", &id).await.unwrap();
+
+    (id, fields.answer.as_str().to_string())
+}
+
+#[actix_web::test]
+async fn captcha_is_invalidated_after_too_many_wrong_answers() {
+    let utils = start_test_server().await;
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    let provider = ImageCaptchaProvider::new(image_settings(), 3).unwrap();
+    let (id, answer) = issue_image_captcha(&provider, &mut redis_conn, "10.0.0.1").await;
+
+    let wrong = CaptchaSolution {
+        id: id.clone(),
+        answer: if answer == "abcd" { "abce" } else { "abcd" }.into(),
+    };
+    for attempt in 1..=3 {
+        assert!(provider
+            .verify_answer(&mut redis_conn, &wrong, "10.0.0.1")
+            .await
+            .is_err());
+
+        let captcha_id = CaptchaID::parse(id.clone()).unwrap();
+        let failed_attempts = CaptchaFields::failed_attempts(&mut redis_conn, &captcha_id)
+            .await
+            .unwrap();
+        let exists: bool = redis_conn.hexists("captcha", &id).await.unwrap();
+        if attempt < 3 {
+            assert_eq!(failed_attempts, attempt);
+            assert!(exists);
+        } else {
+            assert!(!exists);
+        }
+    }
+
+    let solution = CaptchaSolution { id, answer };
+    assert!(provider
+        .verify_answer(&mut redis_conn, &solution, "10.0.0.1")
+        .await
+        .is_err());
+}
+
+#[actix_web::test]
+async fn concurrent_wrong_answers_are_all_counted() {
+    let utils = start_test_server().await;
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    let provider = ImageCaptchaProvider::new(image_settings(), 5).unwrap();
+    let (id, answer) = issue_image_captcha(&provider, &mut redis_conn, "10.0.0.1").await;
+
+    let wrong = CaptchaSolution {
+        id: id.clone(),
+        answer: if answer == "abcd" { "abce" } else { "abcd" }.into(),
+    };
+    let attempt = || async {
+        let mut redis_conn = utils.redis_pool.get().await.unwrap();
+        provider
+            .verify_answer(&mut redis_conn, &wrong, "10.0.0.1")
+            .await
+    };
+    let rets = tokio::join!(attempt(), attempt(), attempt(), attempt());
+    for ret in [rets.0, rets.1, rets.2, rets.3] {
+        assert!(ret.is_err());
+    }
+
+    let captcha_id = CaptchaID::parse(id.clone()).unwrap();
+    assert_eq!(
+        CaptchaFields::failed_attempts(&mut redis_conn, &captcha_id)
+            .await
+            .unwrap(),
+        4
+    );
+    // Still solvable, the concurrent failures didn't invalidate it
+    let solution = CaptchaSolution { id, answer };
+    assert!(provider
+        .verify_answer(&mut redis_conn, &solution, "10.0.0.1")
+        .await
+        .is_ok());
+    assert_eq!(
+        CaptchaFields::failed_attempts(&mut redis_conn, &captcha_id)
+            .await
+            .unwrap(),
+        0
+    );
+}
+
+#[actix_web::test]
+async fn captcha_can_only_be_used_by_the_client_it_was_issued_to() {
+    let utils = start_test_server().await;
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    let provider = ImageCaptchaProvider::new(image_settings(), 3).unwrap();
+    let (id, answer) = issue_image_captcha(&provider, &mut redis_conn, "10.0.0.1").await;
+
+    let captcha_id = CaptchaID::parse(id.clone()).unwrap();
+    assert!(provider
+        .load_audio(&mut redis_conn, &captcha_id, "10.0.0.2")
+        .await
+        .is_err());
+    assert!(provider
+        .reload_challenge(&mut redis_conn, captcha_id, "10.0.0.2")
+        .await
+        .is_err());
+
+    let solution = CaptchaSolution { id, answer };
+    assert!(provider
+        .verify_answer(&mut redis_conn, &solution, "10.0.0.2")
+        .await
+        .is_err());
+    assert!(provider
+        .verify_answer(&mut redis_conn, &solution, "10.0.0.1")
+        .await
+        .is_ok());
+}
+
+#[actix_web::test]
+async fn image_captcha_follows_the_configured_charset_and_length() {
+    let utils = start_test_server().await;
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    let mut settings = image_settings();
+    settings.charset = "ACEFHKMNPRTX".into();
+    settings.min_length = 5;
+    settings.max_length = 5;
+    settings.width = 180;
+    settings.height = 80;
+    settings.case_insensitive = true;
+    let provider = ImageCaptchaProvider::new(settings, 3).unwrap();
+
+    for _ in 0..5 {
+        let (id, answer) = issue_image_captcha(&provider, &mut redis_conn, "10.0.0.3").await;
+        assert_eq!(answer.len(), 5);
+        assert!(answer.chars().all(|c| "ACEFHKMNPRTX".contains(c)));
+
+        // Out of the charset, then rejected without counting as an attempt
+        let solution = CaptchaSolution {
+            id: id.clone(),
+            answer: "ABCDE".into(),
+        };
+        assert!(provider
+            .verify_answer(&mut redis_conn, &solution, "10.0.0.3")
+            .await
+            .is_err());
+
+        let solution = CaptchaSolution {
+            id,
+            answer: answer.to_lowercase(),
+        };
+        assert!(provider
+            .verify_answer(&mut redis_conn, &solution, "10.0.0.3")
+            .await
+            .is_ok());
+    }
+}
+
+#[test]
+fn invalid_image_captcha_settings_are_rejected() {
+    let mut settings = image_settings();
+    settings.charset = "abc0O!".into();
+    assert!(ImageCaptchaProvider::new(settings, 3).is_err());
+
+    let mut settings = image_settings();
+    settings.min_length = 7;
+    assert!(ImageCaptchaProvider::new(settings, 3).is_err());
+
+    let mut settings = image_settings();
+    settings.audio_charset = "abcABC".into();
+    assert!(ImageCaptchaProvider::new(settings, 3).is_err());
+}
@@ -0,0 +1,69 @@
+use crate::utils::start_test_server;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use auth::client_ip::TrustedProxies;
+use std::net::IpAddr;
+
+fn headers(name: &'static str, value: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        HeaderName::from_static(name),
+        HeaderValue::from_str(value).unwrap(),
+    );
+    headers
+}
+
+#[test]
+fn forwarded_ip_is_only_trusted_from_trusted_proxies() {
+    let proxies = TrustedProxies::new(&["10.0.0.0/8".into(), "192.168.1.1".into()]).unwrap();
+    let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+    let forged = headers("x-forwarded-for", "1.1.1.1");
+    assert_eq!(proxies.client_ip(ip("2.2.2.2"), &forged), ip("2.2.2.2"));
+
+    // Hops are walked back until the first untrusted one
+    let chain = headers("x-forwarded-for", "1.1.1.1, 3.3.3.3, 192.168.1.1");
+    assert_eq!(proxies.client_ip(ip("10.1.2.3"), &chain), ip("3.3.3.3"));
+
+    let forwarded = headers(
+        "forwarded",
+        r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.2"#,
+    );
+    assert_eq!(
+        proxies.client_ip(ip("10.0.0.1"), &forwarded),
+        ip("2001:db8::1")
+    );
+
+    let real_ip = headers("x-real-ip", "4.4.4.4");
+    assert_eq!(proxies.client_ip(ip("10.0.0.1"), &real_ip), ip("4.4.4.4"));
+
+    let unknown = headers("x-forwarded-for", "unknown");
+    assert_eq!(proxies.client_ip(ip("10.0.0.1"), &unknown), ip("10.0.0.1"));
+}
+
+#[actix_web::test]
+async fn requests_are_rate_limited_per_forwarded_ip() {
+    let utils = start_test_server().await;
+    // The test server trusts 127.0.0.1 as a proxy, the ip group allows 5 requests
+    let [a, b, c] = rand::random::<[u8; 3]>();
+    let (client, other_client) = (format!("10.{a}.{b}.{c}"), format!("11.{a}.{b}.{c}"));
+
+    for remaining in ["4", "3"] {
+        let res = utils
+            .http_client
+            .get("https://127.0.0.1:8443/rate-limited-ip")
+            .header("x-forwarded-for", &client)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.headers()["ratelimit-remaining"], remaining);
+    }
+
+    let res = utils
+        .http_client
+        .get("https://127.0.0.1:8443/rate-limited-ip")
+        .header("x-forwarded-for", &other_client)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["ratelimit-remaining"], "4");
+}
@@ -0,0 +1,53 @@
+use crate::utils::{create_user, solved_captcha, start_test_server, ApiTestUtils};
+use fake::faker::internet::en::SafeEmail;
+use fake::Fake;
+use reqwest::StatusCode;
+
+/// Status, content type and body of an emailed link request
+async fn request_link(
+    utils: &ApiTestUtils,
+    path: &str,
+    email: &str,
+) -> (StatusCode, String, String) {
+    let (captcha_id, captcha_answer) = solved_captcha(utils).await;
+    let res = utils
+        .http_client
+        .post(format!("https://127.0.0.1:8443{path}"))
+        .form(&[
+            ("email", email),
+            ("captcha_id", &captcha_id),
+            ("captcha_answer", &captcha_answer),
+        ])
+        .send()
+        .await
+        .unwrap();
+    let content_type = res
+        .headers()
+        .get("content-type")
+        .map(|v| v.to_str().unwrap().to_string())
+        .unwrap_or_default();
+
+    (res.status(), content_type, res.text().await.unwrap())
+}
+
+#[actix_web::test]
+async fn registration_request_doesnt_tell_whether_the_email_is_taken() {
+    let utils = start_test_server().await;
+    let path = "/api/v1/user/create/request";
+    let taken = request_link(&utils, path, &create_user(&utils).await).await;
+    let available = request_link(&utils, path, &SafeEmail().fake::<String>()).await;
+
+    assert_eq!(taken.0, StatusCode::ACCEPTED);
+    assert_eq!(taken, available);
+}
+
+#[actix_web::test]
+async fn password_reset_request_doesnt_tell_whether_the_email_is_registered() {
+    let utils = start_test_server().await;
+    let path = "/api/v1/reset-password/request";
+    let registered = request_link(&utils, path, &create_user(&utils).await).await;
+    let unknown = request_link(&utils, path, &SafeEmail().fake::<String>()).await;
+
+    assert_eq!(registered.0, StatusCode::ACCEPTED);
+    assert_eq!(registered, unknown);
+}
@@ -0,0 +1,89 @@
+use crate::utils::start_test_server;
+use anyhow::bail;
+use auth::health::{Readiness, ServerState};
+use auth::tasks::{RestartPolicy, SupervisorHealth, TaskHealth, TaskSupervisor};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+#[actix_web::test]
+async fn liveness_and_readiness_probes() {
+    let utils = start_test_server().await;
+
+    let res = utils
+        .http_client
+        .get("https://127.0.0.1:8443/healthz")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = utils
+        .http_client
+        .get("https://127.0.0.1:8443/readyz")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["state"], "running");
+    for dependency in ["postgres", "redis"] {
+        assert_eq!(report["dependencies"][dependency]["healthy"], true);
+        assert!(report["dependencies"][dependency]["latency_ms"].is_u64());
+    }
+}
+
+#[actix_web::test]
+async fn not_ready_while_starting_or_shutting_down() {
+    let utils = start_test_server().await;
+    let shutdown = CancellationToken::new();
+    let readiness = Readiness::new(shutdown.clone(), SupervisorHealth::default());
+
+    let report = readiness.check(&utils.pg_pool, &utils.redis_pool).await;
+    assert_eq!(report.state, ServerState::Starting);
+    assert!(!report.ready);
+
+    readiness.set_started();
+    assert!(
+        readiness
+            .check(&utils.pg_pool, &utils.redis_pool)
+            .await
+            .ready
+    );
+
+    shutdown.cancel();
+    let report = readiness.check(&utils.pg_pool, &utils.redis_pool).await;
+    assert_eq!(report.state, ServerState::ShuttingDown);
+    assert!(!report.ready);
+}
+
+#[actix_web::test]
+async fn not_ready_when_a_nested_task_failed() {
+    let utils = start_test_server().await;
+    let shutdown = CancellationToken::new();
+    let outer = TaskSupervisor::new(&shutdown);
+    let readiness = Readiness::new(shutdown.clone(), outer.health());
+    readiness.set_started();
+
+    // Like the leader's tasks, run under a supervisor nested in the outer one
+    let mut nested = TaskSupervisor::with_health(&shutdown, outer.health());
+    let policy = RestartPolicy {
+        max_restarts: 0,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(100),
+    };
+    nested.spawn("task1 (redis deletion: test)", policy, |_| async {
+        bail!("redis is gone")
+    });
+    nested.join().await;
+
+    let report = readiness.check(&utils.pg_pool, &utils.redis_pool).await;
+    assert!(!report.ready);
+    assert_eq!(
+        report.tasks.get("task1 (redis deletion: test)"),
+        Some(&TaskHealth::Failed {
+            last_error: "redis is gone".into()
+        })
+    );
+}
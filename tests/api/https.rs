@@ -0,0 +1,39 @@
+use crate::utils::start_test_server;
+use reqwest::redirect::Policy;
+use reqwest::StatusCode;
+
+#[actix_web::test]
+async fn http_requests_are_redirected_to_the_public_url() {
+    start_test_server().await;
+    let client = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()
+        .unwrap();
+
+    let res = client
+        .post("http://127.0.0.1:8080/api/v1/user/login?next=home")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        res.headers()["location"],
+        "https://127.0.0.1:8443/api/v1/user/login?next=home"
+    );
+    assert!(res.headers().get("strict-transport-security").is_none());
+}
+
+#[actix_web::test]
+async fn tls_responses_enable_hsts() {
+    let utils = start_test_server().await;
+    let res = utils
+        .http_client
+        .get("https://127.0.0.1:8443/login")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        res.headers()["strict-transport-security"],
+        "max-age=31536000"
+    );
+}
@@ -0,0 +1,252 @@
+use crate::utils::{
+    captcha_answer, create_user, solved_captcha, start_test_server, CapturedLogs, PASSWORD,
+};
+use auth::app_error::AppErrorType;
+use auth::config::Settings;
+use auth::logic::{AuthError, Email, Login, LoginFailureOutcome, LoginThrottle, Password};
+use deadpool_redis::redis::AsyncCommands;
+use fake::faker::internet::en::SafeEmail;
+use fake::Fake;
+use reqwest::header::LOCATION;
+use reqwest::StatusCode;
+use secrecy::Secret;
+use serde_json::Value;
+use std::time::Instant;
+
+#[actix_web::test]
+async fn login_requires_a_captcha_after_failed_attempts() {
+    let utils = start_test_server().await;
+    let email: String = SafeEmail().fake();
+    let login = |captcha: Option<(&str, &str)>| {
+        let mut form = vec![
+            ("email", email.as_str()),
+            ("password", "Wrong-password-1"),
+            ("cancel_deletion", "false"),
+        ];
+        if let Some((id, answer)) = captcha {
+            form.push(("captcha_id", id));
+            form.push(("captcha_answer", answer));
+        }
+
+        let req = utils
+            .http_client
+            .post("https://127.0.0.1:8443/api/v1/user/login")
+            .form(&form);
+        async move {
+            let res = req.send().await.unwrap();
+            assert_eq!(res.headers()["content-type"], "application/problem+json");
+            let problem: Value = res.json().await.unwrap();
+            assert!(problem["correlation_id"].is_string());
+            problem
+        }
+    };
+
+    // Below the threshold (2 in the test settings) credentials are checked directly
+    for _ in 0..2 {
+        let problem = login(None).await;
+        assert_eq!(problem["status"], 401);
+        assert_eq!(problem["code"], "invalid_credentials");
+    }
+
+    let problem = login(None).await;
+    assert_eq!(problem["status"], 401);
+    assert_eq!(problem["code"], "captcha_required");
+    let challenge = &problem["captcha"];
+    assert_eq!(challenge["kind"], "image");
+    let id = challenge["id"].as_str().unwrap();
+
+    let answer = captcha_answer(&utils, id).await;
+    let problem = login(Some((id, &answer))).await;
+    assert_eq!(problem["code"], "invalid_credentials");
+}
+
+#[actix_web::test]
+async fn wrong_captcha_answer_gets_a_new_challenge() {
+    let utils = start_test_server().await;
+    let email = create_user(&utils).await;
+    let login = |password: &str, captcha: Option<(&str, &str)>| {
+        let mut form = vec![
+            ("email", email.clone()),
+            ("password", password.to_string()),
+            ("cancel_deletion", "false".into()),
+        ];
+        if let Some((id, answer)) = captcha {
+            form.push(("captcha_id", id.into()));
+            form.push(("captcha_answer", answer.into()));
+        }
+
+        utils
+            .http_client
+            .post("https://127.0.0.1:8443/api/v1/user/login")
+            .form(&form)
+            .send()
+    };
+
+    for _ in 0..2 {
+        let res = login("Wrong-password-1", None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+    let problem: Value = login(PASSWORD, None).await.unwrap().json().await.unwrap();
+    assert_eq!(problem["code"], "captcha_required");
+    let first_id = problem["captcha"]["id"].as_str().unwrap().to_string();
+
+    // The wrong answer is answered with another challenge, not a dead end
+    let problem: Value = login(PASSWORD, Some((&first_id, "wrong0")))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(problem["status"], 401);
+    assert_eq!(problem["code"], "captcha_required");
+    let id = problem["captcha"]["id"].as_str().unwrap();
+    assert_ne!(id, first_id);
+
+    let answer = captcha_answer(&utils, id).await;
+    let res = login(PASSWORD, Some((id, &answer))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[LOCATION], "/home");
+}
+
+#[actix_web::test]
+async fn failures_back_off_then_lock_the_account() {
+    let utils = start_test_server().await;
+    let settings = Settings::new("test").unwrap().login_throttle;
+    let email = Email::parse(create_user(&utils).await).unwrap();
+    let throttle = LoginThrottle::new(&settings, &email, "10.1.0.1");
+    let backoff_key = format!("login_backoff:10.1.0.1:{}", email.as_str().to_lowercase());
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+
+    // No delay below the backoff threshold (3 in the base settings)
+    for _ in 1..settings.backoff_threshold {
+        let outcome = throttle.register_failure(&mut redis_conn).await.unwrap();
+        assert_eq!(outcome, LoginFailureOutcome::Counted);
+        throttle.check_allowed(&mut redis_conn).await.unwrap();
+    }
+
+    // Then the delay doubles on each failure, up to the max delay (4s in the test settings)
+    let mut delays = vec![];
+    for _ in settings.backoff_threshold..settings.lockout_threshold {
+        let outcome = throttle.register_failure(&mut redis_conn).await.unwrap();
+        assert_eq!(outcome, LoginFailureOutcome::Delayed);
+        assert!(throttle.check_allowed(&mut redis_conn).await.is_err());
+        let ttl: i64 = redis_conn.ttl(&backoff_key).await.unwrap();
+        delays.push(ttl);
+    }
+    assert_eq!(delays, [1, 2, 4]);
+
+    // The lockout threshold locks the account, whatever the ip, and emails its owner
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let _subscriber = tracing::subscriber::set_default(
+        tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish(),
+    );
+    let outcome = throttle.register_failure(&mut redis_conn).await.unwrap();
+    assert_eq!(outcome, LoginFailureOutcome::Locked);
+    throttle
+        .send_account_locked_email(&utils.pg_pool, &email)
+        .await
+        .unwrap();
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("account locked email sent"));
+
+    let other_ip = LoginThrottle::new(&settings, &email, "10.1.0.2");
+    assert!(other_ip.check_allowed(&mut redis_conn).await.is_err());
+}
+
+#[actix_web::test]
+async fn locked_account_is_refused_even_with_the_right_password() {
+    let utils = start_test_server().await;
+    let settings = Settings::new("test").unwrap().login_throttle;
+    let email = create_user(&utils).await;
+    let throttle = LoginThrottle::new(&settings, &Email::parse(email.clone()).unwrap(), "10.1.0.3");
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    while throttle.register_failure(&mut redis_conn).await.unwrap() != LoginFailureOutcome::Locked {
+    }
+
+    let res = utils
+        .http_client
+        .post("https://127.0.0.1:8443/api/v1/user/login")
+        .form(&[
+            ("email", email.as_str()),
+            ("password", PASSWORD),
+            ("cancel_deletion", "false"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    let problem: Value = res.json().await.unwrap();
+    assert_eq!(problem["code"], "too_many_attempts");
+}
+
+#[actix_web::test]
+async fn successful_login_resets_the_failure_counters() {
+    let utils = start_test_server().await;
+    let email = create_user(&utils).await;
+    let login = |password: &str, captcha: Option<(String, String)>| {
+        let mut form = vec![
+            ("email", email.clone()),
+            ("password", password.to_string()),
+            ("cancel_deletion", "false".into()),
+        ];
+        if let Some((id, answer)) = captcha {
+            form.push(("captcha_id", id));
+            form.push(("captcha_answer", answer));
+        }
+
+        utils
+            .http_client
+            .post("https://127.0.0.1:8443/api/v1/user/login")
+            .form(&form)
+            .send()
+    };
+    let code = |res: reqwest::Response| async move {
+        let problem: Value = res.json().await.unwrap();
+        problem["code"].as_str().unwrap().to_string()
+    };
+
+    // Up to the captcha threshold (2 in the test settings)
+    for _ in 0..2 {
+        let res = login("Wrong-password-1", None).await.unwrap();
+        assert_eq!(code(res).await, "invalid_credentials");
+    }
+    let (id, answer) = solved_captcha(&utils).await;
+    let res = login(PASSWORD, Some((id, answer))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // The account starts from scratch: the next failure doesn't require a captcha
+    let res = login("Wrong-password-1", None).await.unwrap();
+    assert_eq!(code(res).await, "invalid_credentials");
+}
+
+#[actix_web::test]
+async fn unknown_email_and_wrong_password_are_indistinguishable() {
+    let utils = start_test_server().await;
+    let attempt = |email: String| {
+        let pg_pool = utils.pg_pool.clone();
+        async move {
+            let login = Login {
+                email: Email::parse(email).unwrap(),
+                password: Password::parse(Secret::new("Wrong-password-1".into())).unwrap(),
+                cancel_deletion: false,
+                captcha: None,
+            };
+            let start = Instant::now();
+            let err = login.check_password_is_valid(&pg_pool).await.unwrap_err();
+            assert!(matches!(
+                err.error_type,
+                AppErrorType::AuthError(AuthError::InvalidCredentials)
+            ));
+            start.elapsed()
+        }
+    };
+
+    let wrong_password = attempt(create_user(&utils).await).await;
+    let unknown_email = attempt(SafeEmail().fake()).await;
+    // The unknown email goes through a password verification as well
+    assert!(unknown_email > wrong_password / 2);
+}
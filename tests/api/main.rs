@@ -1,2 +1,13 @@
+mod captcha;
+mod client_ip;
+mod hardened_mode;
+mod health;
+mod https;
+mod login;
+mod metrics;
+mod problem;
+mod rate_limit;
 mod register_user;
+mod session;
+mod tokens;
 mod utils;
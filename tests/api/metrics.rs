@@ -0,0 +1,47 @@
+use crate::utils::start_test_server;
+use fake::faker::internet::en::SafeEmail;
+use fake::Fake;
+use reqwest::StatusCode;
+
+#[actix_web::test]
+async fn metrics_are_served_on_the_admin_port() {
+    let utils = start_test_server().await;
+    let email: String = SafeEmail().fake();
+    utils
+        .http_client
+        .post("https://127.0.0.1:8443/api/v1/user/login")
+        .form(&[
+            ("email", email.as_str()),
+            ("password", "Wrong-password-1"),
+            ("cancel_deletion", "false"),
+        ])
+        .send()
+        .await
+        .unwrap();
+
+    // Admin port configured in the test settings
+    let metrics = reqwest::get("http://127.0.0.1:9090/metrics")
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    for expected in [
+        r#"http_requests_total{method="POST",route="/api/v1/user/login",status="401"}"#,
+        r#"http_request_duration_seconds_bucket{method="POST",route="/api/v1/user/login",status="401",le="#,
+        r#"auth_logins_total{outcome="failure",reason="invalid_credentials"}"#,
+        r#"argon2_duration_seconds_count{operation="verify"}"#,
+        r#"db_pool_connections{pool="postgres",state="idle"}"#,
+        r#"db_pool_max_connections{pool="redis"}"#,
+    ] {
+        assert!(metrics.contains(expected), "{expected} missing");
+    }
+
+    let res = utils
+        .http_client
+        .get("https://127.0.0.1:8443/metrics")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,181 @@
+use crate::utils::{start_test_server, CapturedLogs};
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_lab::middleware::from_fn;
+use anyhow::anyhow;
+use auth::app_error::{json_config, report_errors, AppError};
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use serde_json::Value;
+use tracing_actix_web::TracingLogger;
+
+async fn load_user() -> Result<HttpResponse, AppError> {
+    let err = anyhow!("connection to postgres://app:secret@db refused");
+    Err(err.context("failed loading the user"))?
+}
+
+async fn echo(web::Json(body): web::Json<Value>) -> HttpResponse {
+    HttpResponse::Ok().json(body)
+}
+
+#[actix_web::test]
+async fn requests_without_session_are_unauthorized() {
+    let utils = start_test_server().await;
+    let res = utils
+        .http_client
+        .get("https://127.0.0.1:8443/api/v1/user/data")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 401);
+    let correlation_id = res.headers()["x-correlation-id"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let problem: Value = res.json().await.unwrap();
+    assert_eq!(problem["code"], "invalid_session_cookie");
+    assert_eq!(problem["correlation_id"], correlation_id);
+}
+
+#[actix_web::test]
+async fn internal_errors_are_logged_but_not_leaked() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let _subscriber = tracing::subscriber::set_default(
+        tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish(),
+    );
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(report_errors))
+            .wrap(TracingLogger::default())
+            .route("/", web::get().to(load_user)),
+    )
+    .await;
+    let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+
+    assert_eq!(res.status(), 500);
+    assert_eq!(
+        res.headers().get("content-type").unwrap(),
+        "application/problem+json"
+    );
+    let problem: Value = read_body_json(res).await;
+    assert_eq!(problem["code"], "internal_error");
+    assert_eq!(problem["title"], "Internal Server Error");
+    assert!(!problem.to_string().contains("secret"));
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let report: Value = logs
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .find(|line| line["fields"]["message"] == "request failed with an unexpected error")
+        .expect("error logged");
+    let fields = &report["fields"];
+    assert_eq!(fields["correlation_id"], problem["correlation_id"]);
+    assert_eq!(
+        fields["error.chain"],
+        "failed loading the user: connection to postgres://app:secret@db refused"
+    );
+    assert!(fields["error.location"]
+        .as_str()
+        .unwrap()
+        .starts_with("tests/api/problem.rs:"));
+    assert!(!fields["error.backtrace"].as_str().unwrap().is_empty());
+    // Logged in the request span
+    assert!(report["span"]["request_id"].is_string());
+}
+
+#[actix_web::test]
+async fn malformed_json_body_gets_a_problem_body() {
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(report_errors))
+            .wrap(TracingLogger::default())
+            .app_data(json_config())
+            .route("/", web::post().to(echo)),
+    )
+    .await;
+    let req = TestRequest::post()
+        .uri("/")
+        .insert_header(("content-type", "application/json"))
+        .set_payload(r#"{"email": "#)
+        .to_request();
+    let res = call_service(&app, req).await;
+
+    assert_eq!(res.status(), 400);
+    assert_eq!(
+        res.headers().get("content-type").unwrap(),
+        "application/problem+json"
+    );
+    let correlation_id = res
+        .headers()
+        .get("x-correlation-id")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let problem: Value = read_body_json(res).await;
+    assert_eq!(problem["code"], "invalid_body");
+    assert_eq!(problem["correlation_id"], correlation_id);
+}
+
+#[actix_web::test]
+async fn rejected_requests_get_a_problem_body() {
+    let utils = start_test_server().await;
+    let client = &utils.http_client;
+    let login = "https://127.0.0.1:8443/api/v1/user/login";
+    let requests = [
+        (
+            client
+                .post(login)
+                .header(CONTENT_TYPE, "application/json")
+                .body(r#"{"email": "#),
+            415,
+            "unsupported_media_type",
+        ),
+        (
+            client.post(login).form(&[("email", "a@example.com")]),
+            400,
+            "invalid_body",
+        ),
+        (
+            client.get("https://127.0.0.1:8443/api/v1/captcha/audio"),
+            400,
+            "invalid_query",
+        ),
+        (
+            client.get("https://127.0.0.1:8443/api/v1/unknown"),
+            404,
+            "not_found",
+        ),
+    ];
+
+    for (req, status, code) in requests {
+        let res = req.send().await.unwrap();
+        assert_eq!(res.status(), status);
+        assert_eq!(res.headers()["content-type"], "application/problem+json");
+        let correlation_id = res.headers()["x-correlation-id"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let problem: Value = res.json().await.unwrap();
+        assert_eq!(problem["code"], code);
+        assert_eq!(problem["status"], status);
+        assert_eq!(problem["correlation_id"], correlation_id);
+    }
+
+    // Browsers still get the 404 page
+    let res = client
+        .get("https://127.0.0.1:8443/unknown")
+        .header(ACCEPT, "text/html")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+    assert!(res.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+}
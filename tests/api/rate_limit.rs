@@ -0,0 +1,102 @@
+use crate::utils::start_test_server;
+use rand::Rng;
+use reqwest::StatusCode;
+use std::net::Ipv4Addr;
+use uuid::Uuid;
+
+/// Client ip forwarded by the tests, which are a trusted proxy, so that each run starts with
+/// fresh per ip counters
+fn random_client_ip() -> String {
+    Ipv4Addr::from(rand::thread_rng().gen::<u32>()).to_string()
+}
+
+#[actix_web::test]
+async fn requests_over_the_limit_are_rejected() {
+    let utils = start_test_server().await;
+    let client_ip = random_client_ip();
+    // Limited to 5 requests per token in the test configuration
+    let url = format!(
+        "https://127.0.0.1:8443/rate-limited?token={}",
+        Uuid::new_v4()
+    );
+
+    for remaining in (0..5).rev() {
+        let res = utils
+            .http_client
+            .get(&url)
+            .header("x-forwarded-for", &client_ip)
+            .send()
+            .await
+            .unwrap();
+        assert_ne!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["ratelimit-limit"], "5");
+        assert_eq!(res.headers()["ratelimit-remaining"], remaining.to_string());
+        assert_eq!(res.headers()["ratelimit-policy"], "5;w=600");
+    }
+
+    let res = utils
+        .http_client
+        .get(&url)
+        .header("x-forwarded-for", &client_ip)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers().contains_key("x-correlation-id"));
+    let reset: u64 = res.headers()["ratelimit-reset"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=600).contains(&reset));
+    assert_eq!(res.headers()["retry-after"], reset.max(1).to_string());
+
+    // Other tokens have their own counter
+    let other_url = format!(
+        "https://127.0.0.1:8443/rate-limited?token={}",
+        Uuid::new_v4()
+    );
+    let res = utils
+        .http_client
+        .get(&other_url)
+        .header("x-forwarded-for", &client_ip)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["ratelimit-remaining"], "4");
+}
+
+#[actix_web::test]
+async fn rotating_tokens_is_limited_per_ip() {
+    let utils = start_test_server().await;
+    let client_ip = random_client_ip();
+    // 20 requests per ip whatever the tokens in the test configuration
+    for _ in 0..20 {
+        let url = format!(
+            "https://127.0.0.1:8443/rate-limited?token={}",
+            Uuid::new_v4()
+        );
+        let res = utils
+            .http_client
+            .get(&url)
+            .header("x-forwarded-for", &client_ip)
+            .send()
+            .await
+            .unwrap();
+        assert_ne!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let url = format!(
+        "https://127.0.0.1:8443/rate-limited?token={}",
+        Uuid::new_v4()
+    );
+    let res = utils
+        .http_client
+        .get(&url)
+        .header("x-forwarded-for", &client_ip)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()["ratelimit-policy"], "20;w=600");
+}
@@ -0,0 +1,92 @@
+use crate::utils::start_test_server;
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use auth::config::Settings;
+use auth::session::SESSION_COOKIE_NAME;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use deadpool_redis::redis::AsyncCommands;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use reqwest::header::{COOKIE, LOCATION, SET_COOKIE};
+use reqwest::redirect::Policy;
+use secrecy::ExposeSecret;
+use uuid::Uuid;
+
+/// Keys of the test key ring, primary first
+fn test_keys() -> Vec<Key> {
+    let settings = Settings::new("test").unwrap();
+    settings
+        .session
+        .keys
+        .expose_secret()
+        .lines()
+        .map(|line| {
+            let key = line.trim().split_once(' ').unwrap().1;
+            Key::from(&STANDARD.decode(key).unwrap())
+        })
+        .collect()
+}
+
+fn encrypt(key: &Key, session_key: &str) -> String {
+    let mut jar = CookieJar::new();
+    jar.private_mut(key)
+        .add(Cookie::new(SESSION_COOKIE_NAME, session_key.to_owned()));
+    jar.get(SESSION_COOKIE_NAME).unwrap().value().to_owned()
+}
+
+fn decrypt(key: &Key, value: &str) -> Option<String> {
+    let mut jar = CookieJar::new();
+    jar.add_original(Cookie::new(SESSION_COOKIE_NAME, value.to_owned()));
+    let cookie = jar.private(key).get(SESSION_COOKIE_NAME)?;
+    Some(cookie.value().to_owned())
+}
+
+#[actix_web::test]
+async fn session_cookie_encrypted_with_a_previous_key_is_accepted_and_rotated() {
+    let utils = start_test_server().await;
+    let keys = test_keys();
+    let (primary, previous) = (&keys[0], &keys[1]);
+
+    // Active session stored by the session middleware
+    let session_key: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect();
+    let state = serde_json::json!({ "id": format!("\"{}\"", Uuid::new_v4()) }).to_string();
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    redis_conn
+        .set_ex::<_, _, ()>(&session_key, state, 60)
+        .await
+        .unwrap();
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .redirect(Policy::none())
+        .build()
+        .unwrap();
+    let request_page = |key: &Key| {
+        let cookie = Cookie::new(SESSION_COOKIE_NAME, encrypt(key, &session_key));
+        client
+            .get("https://127.0.0.1:8443/register/request")
+            .header(COOKIE, cookie.encoded().to_string())
+            .send()
+    };
+
+    // Logged in users are redirected
+    let res = request_page(previous).await.unwrap();
+    assert_eq!(res.status(), 303);
+    assert_eq!(res.headers()[LOCATION], "/home");
+    let cookie =
+        Cookie::parse_encoded(res.headers()[SET_COOKIE].to_str().unwrap().to_owned()).unwrap();
+    assert_eq!(cookie.name(), SESSION_COOKIE_NAME);
+    assert_eq!(decrypt(primary, cookie.value()), Some(session_key.clone()));
+
+    let res = request_page(primary).await.unwrap();
+    assert_eq!(res.status(), 303);
+    assert!(res.headers().get(SET_COOKIE).is_none());
+
+    // Keys out of the key ring are rejected
+    let res = request_page(&Key::generate()).await.unwrap();
+    assert_eq!(res.status(), 200);
+}
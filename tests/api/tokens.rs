@@ -0,0 +1,142 @@
+use crate::utils::start_test_server;
+use auth::config::{Settings, TokenBackend};
+use auth::db::get_redis_connection;
+use auth::logic::{Email, TokenPurpose, TokenStore, URLToken};
+use auth::tasks::{expiry_index_complete_name, expiry_index_name};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_redis::redis::AsyncCommands;
+use fake::faker::internet::en::SafeEmail;
+use fake::Fake;
+use std::collections::HashSet;
+
+#[actix_web::test]
+async fn token_cannot_be_redeemed_for_another_purpose() {
+    let utils = start_test_server().await;
+    let email = Email::parse(SafeEmail().fake()).unwrap();
+
+    let hash_key = &utils.token_hash_key;
+
+    let redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let token = URLToken::store_user_fields_to_redis(
+        redis_conn,
+        TokenPurpose::Registration,
+        &email,
+        hash_key,
+    )
+    .await
+    .unwrap();
+
+    // Only the token hash is stored
+    let mut redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let hash_name = TokenPurpose::Registration.hash_name();
+    let raw_stored: bool = redis_conn.hexists(hash_name, token.as_str()).await.unwrap();
+    let hash_stored: bool = redis_conn
+        .hexists(hash_name, hash_key.hash(token.as_str()))
+        .await
+        .unwrap();
+    assert!(!raw_stored && hash_stored);
+
+    let redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let ret = token
+        .get_associated_redis_fields(redis_conn, TokenPurpose::PasswordReset, hash_key)
+        .await;
+    assert!(ret.is_err());
+
+    let redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let fields = token
+        .get_associated_redis_fields(redis_conn, TokenPurpose::Registration, hash_key)
+        .await
+        .unwrap();
+    assert_eq!(fields.purpose, TokenPurpose::Registration);
+    assert_eq!(fields.email.as_str(), email.as_str());
+}
+
+#[actix_web::test]
+async fn signed_token_is_single_use_and_bound_to_its_purpose() {
+    let utils = start_test_server().await;
+    let settings = Settings::new("test").unwrap();
+    let store = TokenStore::new(
+        TokenBackend::Signed,
+        (**utils.token_hash_key).clone(),
+        settings.task1_tokens.clone(),
+    );
+    let email = Email::parse(SafeEmail().fake()).unwrap();
+
+    let mut redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let keys_before: HashSet<String> = redis_conn.keys("*").await.unwrap();
+    let token = store
+        .issue(redis_conn, TokenPurpose::PasswordReset, &email)
+        .await
+        .unwrap();
+    // Signed tokens are validated against their own format
+    assert!(URLToken::parse(token.as_str().into()).is_err());
+    let token = store.parse(token.as_str().into()).unwrap();
+    let random = URLToken::generate();
+    assert!(store.parse(random.as_str().into()).is_err());
+    let (payload, signature) = token.as_str().split_once('.').unwrap();
+    let too_long = format!("{}.{signature}", payload.repeat(20));
+    assert!(store.parse(too_long).is_err());
+
+    // The claims are encrypted, they must not reveal the email
+    let claims = URL_SAFE_NO_PAD.decode(payload).unwrap();
+    let claims = String::from_utf8_lossy(&claims);
+    assert!(!claims.contains(email.as_str()));
+
+    let redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let ret = store
+        .redeem(redis_conn, &token, TokenPurpose::Registration)
+        .await;
+    assert!(ret.is_err());
+
+    let redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let fields = store
+        .redeem(redis_conn, &token, TokenPurpose::PasswordReset)
+        .await
+        .unwrap();
+    assert_eq!(fields.purpose, TokenPurpose::PasswordReset);
+    assert_eq!(fields.email.as_str(), email.as_str());
+
+    // Issuing and redeeming only wrote the deny-list entry. Other tests run concurrently, so
+    // the random tokens hashes and the rate limit counters are left out.
+    let shared_keys: Vec<String> = TokenPurpose::ALL
+        .iter()
+        .flat_map(|purpose| {
+            let hash_name = purpose.hash_name();
+            [
+                hash_name.to_string(),
+                expiry_index_name(hash_name),
+                expiry_index_complete_name(hash_name),
+            ]
+        })
+        .collect();
+    let mut redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let keys_after: HashSet<String> = redis_conn.keys("*").await.unwrap();
+    let written: Vec<_> = keys_after
+        .difference(&keys_before)
+        .filter(|key| !key.starts_with("rate_limit:") && !shared_keys.contains(key))
+        .filter(|key| key.contains("token") || key.contains(email.as_str()))
+        .collect();
+    assert_eq!(written.len(), 1, "{written:?}");
+    assert!(written[0].starts_with("redeemed_token:"));
+    let ttl: i64 = redis_conn.ttl(written[0]).await.unwrap();
+    assert!(ttl > 0 && ttl <= settings.task1_tokens.password_reset.expiry_time as i64);
+
+    let redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let ret = store
+        .redeem(redis_conn, &token, TokenPurpose::PasswordReset)
+        .await;
+    assert!(ret.is_err());
+
+    // Tampered payloads are rejected
+    let mut tampered = URL_SAFE_NO_PAD.decode(payload).unwrap();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    let tampered = format!("{}.{signature}", URL_SAFE_NO_PAD.encode(tampered));
+    let tampered = store.parse(tampered).unwrap();
+    let redis_conn = get_redis_connection(&utils.redis_pool).await.unwrap();
+    let ret = store
+        .redeem(redis_conn, &tampered, TokenPurpose::PasswordReset)
+        .await;
+    assert!(ret.is_err());
+}
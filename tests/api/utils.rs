@@ -1,27 +1,55 @@
 use actix_web::web::Data;
 use auth::config::Settings;
+use auth::logic::{CaptchaFields, Password, TokenHashKey};
 use auth::server::{start_server, ServerSetup};
+use auth::shutdown::ShutdownCoordinator;
+use auth::tasks::SupervisorHealth;
 use auth::telemetry::init_tracing;
+use deadpool_redis::redis::AsyncCommands;
 use deadpool_redis::Pool as RedisPool;
+use fake::faker::internet::en::SafeEmail;
+use fake::Fake;
 use once_cell::sync::Lazy;
+use secrecy::Secret;
+use serde_json::Value;
 use sqlx::PgPool;
+use std::io;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::time::sleep;
 use tracing::level_filters::LevelFilter;
+use uuid::Uuid;
 
 static SETTINGS_WITH_LOGS: Lazy<Settings> = Lazy::new(|| {
+    let settings = Settings::new("test").unwrap();
     if std::env::var("LOGS").is_ok() {
-        init_tracing(LevelFilter::INFO).unwrap();
+        init_tracing(LevelFilter::INFO, &settings.tracing).unwrap();
     } else {
-        init_tracing(LevelFilter::OFF).unwrap();
+        init_tracing(LevelFilter::OFF, &settings.tracing).unwrap();
     }
 
-    Settings::new("config/test").unwrap()
+    settings
 });
 
+/// Log lines written by the subscriber of the test thread
+#[derive(Clone, Default)]
+pub struct CapturedLogs(pub Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[allow(dead_code)]
 pub struct ApiTestUtils {
     pub redis_pool: Data<RedisPool>,
     pub pg_pool: Data<PgPool>,
+    pub token_hash_key: Data<TokenHashKey>,
     pub http_client: reqwest::Client,
 }
 
@@ -30,14 +58,35 @@ impl From<&ServerSetup> for ApiTestUtils {
         Self {
             redis_pool: value.redis_pool.clone(),
             pg_pool: value.pg_pool.clone(),
-            http_client: reqwest::Client::new(),
+            token_hash_key: value.token_hash_key.clone(),
+            http_client: reqwest::Client::builder()
+                .danger_accept_invalid_certs(true)
+                .build()
+                .unwrap(),
         }
     }
 }
 
+/// Each test has its own runtime, so the server shared by all tests runs on its own thread
+/// instead of dying with the first test which started it.
+static TEST_SERVER: Lazy<()> = Lazy::new(|| {
+    let settings = Lazy::force(&SETTINGS_WITH_LOGS).clone();
+    std::thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            let setup = ServerSetup::new(&settings).await.unwrap();
+            let shutdown = ShutdownCoordinator::new(&settings.shutdown);
+            start_server(settings, setup, shutdown, SupervisorHealth::default())
+                .await
+                .unwrap();
+        })
+    });
+    // give time for the server to start
+    std::thread::sleep(Duration::from_secs(1));
+});
+
 pub async fn start_test_server() -> ApiTestUtils {
     let settings = Lazy::force(&SETTINGS_WITH_LOGS);
-    let setup = ServerSetup::new(&settings).await.unwrap();
+    let setup = ServerSetup::new(settings).await.unwrap();
 
     let test_utils = ApiTestUtils::from(&setup);
     sqlx::migrate!("./migrations")
@@ -45,9 +94,53 @@ pub async fn start_test_server() -> ApiTestUtils {
         .await
         .unwrap();
 
-    let _ = tokio::spawn(start_server(settings.clone(), setup));
-    // give time for the server to start
-    sleep(Duration::from_secs(1)).await;
+    tokio::task::spawn_blocking(|| Lazy::force(&TEST_SERVER))
+        .await
+        .unwrap();
 
     test_utils
 }
+
+pub const PASSWORD: &str = "Right-password-1";
+
+/// Register a user with `PASSWORD` directly in the database, returns its email
+pub async fn create_user(utils: &ApiTestUtils) -> String {
+    let email: String = SafeEmail().fake();
+    let hash = Password::parse(Secret::new(PASSWORD.into()))
+        .unwrap()
+        .generate_argon2_hash()
+        .unwrap();
+    sqlx::query("INSERT INTO users (email, username, password_hash) VALUES ($1, $2, $3)")
+        .bind(&email)
+        .bind(Uuid::new_v4().simple().to_string())
+        .bind(hash.expose_as_str())
+        .execute(&**utils.pg_pool)
+        .await
+        .unwrap();
+
+    email
+}
+
+/// Answer of an image captcha, read from redis
+pub async fn captcha_answer(utils: &ApiTestUtils, id: &str) -> String {
+    let mut redis_conn = utils.redis_pool.get().await.unwrap();
+    let fields: CaptchaFields = redis_conn.hget("captcha", id).await.unwrap();
+    fields.answer.as_str().to_string()
+}
+
+/// Load an image captcha like the forms do, returns its id and answer
+pub async fn solved_captcha(utils: &ApiTestUtils) -> (String, String) {
+    let challenge: Value = utils
+        .http_client
+        .get("https://127.0.0.1:8443/api/v1/captcha")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let id = challenge["id"].as_str().unwrap().to_string();
+    let answer = captcha_answer(utils, &id).await;
+
+    (id, answer)
+}
@@ -0,0 +1,81 @@
+use auth::config::{Settings, SettingsError};
+use secrecy::ExposeSecret;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+fn load_with_env(env_vars: &[(&str, &str)]) -> Result<Settings, SettingsError> {
+    let env_vars: HashMap<String, String> = env_vars
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    Settings::load(Settings::CONFIG_DIR, "test", Some(env_vars))
+}
+
+#[test]
+fn environment_file_overrides_base_settings() {
+    let settings = load_with_env(&[]).unwrap();
+
+    // base
+    assert_eq!(settings.captcha.max_failed_attempts, 3);
+    // test
+    assert_eq!(settings.task1_captcha.expiry_time, 1);
+    assert_eq!(settings.captcha.proof_of_work_difficulty, 8);
+}
+
+#[test]
+fn env_vars_and_secret_files_override_settings() {
+    let secret_path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    let secret = "secret-from-a-mounted-file-0123456789abcdef";
+    std::fs::write(&secret_path, format!("{secret}\n")).unwrap();
+
+    let settings = load_with_env(&[
+        ("APP__APPLICATION_PORT", "9443"),
+        ("APP__POSTGRES__URL", "postgres://app:pw@db:5432/auth"),
+        (
+            "APP__SECURITY__TOKEN_SECRET_FILE",
+            secret_path.to_str().unwrap(),
+        ),
+    ])
+    .unwrap();
+    std::fs::remove_file(secret_path).unwrap();
+
+    assert_eq!(settings.application_port, 9443);
+    assert_eq!(
+        settings.postgres.url.expose_secret(),
+        "postgres://app:pw@db:5432/auth"
+    );
+    assert_eq!(settings.security.token_secret.expose_secret(), secret);
+}
+
+#[test]
+fn every_invalid_setting_is_reported() {
+    let ret = load_with_env(&[
+        ("APP__APPLICATION_PORT", "0"),
+        ("APP__REDIS__URL", "http://127.0.0.1:6379"),
+        ("APP__TASK1_CAPTCHA__EXPIRY_TIME", "0"),
+        ("APP__SECURITY__TOKEN_SECRET", "short"),
+    ]);
+
+    let Err(SettingsError::Invalid(problems)) = ret else {
+        panic!("invalid settings accepted");
+    };
+    assert_eq!(
+        problems,
+        [
+            "application_port: must not be 0",
+            "redis.url: must be a redis:// url",
+            "task1_captcha.expiry_time: must be positive",
+            "security.token_secret: must be at least 32 bytes long",
+        ]
+    );
+}
+
+#[test]
+fn missing_secret_file_is_reported() {
+    let ret = load_with_env(&[("APP__POSTGRES__URL_FILE", "/nonexistent/postgres_url")]);
+
+    assert!(matches!(
+        ret,
+        Err(SettingsError::SecretFile { key, .. }) if key == "postgres.url"
+    ));
+}
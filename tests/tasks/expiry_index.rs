@@ -0,0 +1,220 @@
+use auth::config::Settings;
+use auth::logic::{CaptchaAnswer, CaptchaFields, CaptchaID};
+use auth::tasks::{
+    expiry_index_complete_name, expiry_index_name, hset_nx_indexed, redis_fields_deletion_task,
+    remove_expired_fields, Task1Config, Timestampable,
+};
+use chrono::Utc;
+use deadpool_redis::redis::aio::MultiplexedConnection;
+use deadpool_redis::redis::{cmd, pipe, AsyncCommands, AsyncIter, Client};
+use std::time::Instant;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+const HASH_NAME: &str = "expiry_index_benchmark";
+const ENTRIES: usize = 1_000_000;
+const EXPIRED_ENTRIES: usize = 10_000;
+const EXPIRY_TIME: i64 = 60;
+const BULK_COUNT: usize = 500;
+
+/// Connection to redis, with `hash_name` cleared
+async fn redis_conn(hash_name: &str) -> MultiplexedConnection {
+    let settings = Settings::new("test").unwrap();
+    let mut redis_conn = Client::open(&*settings.redis.url)
+        .unwrap()
+        .get_multiplexed_tokio_connection()
+        .await
+        .unwrap();
+    cmd("DEL")
+        .arg(hash_name)
+        .arg(expiry_index_name(hash_name))
+        .arg(expiry_index_complete_name(hash_name))
+        .query_async::<_, ()>(&mut redis_conn)
+        .await
+        .unwrap();
+
+    redis_conn
+}
+
+fn captcha_fields(timestamp: i64) -> String {
+    CaptchaFields::json_string(
+        CaptchaAnswer::from_str("abcd".into()),
+        timestamp,
+        "127.0.0.1",
+    )
+    .unwrap()
+}
+
+/// One indexing and cleanup pass of task1
+async fn run_task1_pass(redis_conn: &MultiplexedConnection, hash_name: &str) {
+    let shutdown = CancellationToken::new();
+    shutdown.cancel();
+    let cfg = Task1Config::new(redis_conn.clone(), hash_name, 600, 100, shutdown);
+    redis_fields_deletion_task::<CaptchaID, CaptchaFields>(cfg)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn existing_field_keeps_its_expiry() {
+    let hash_name = "expiry_index_hset_nx";
+    let mut redis_conn = redis_conn(hash_name).await;
+    let id = CaptchaID::from_uuid(Uuid::new_v4());
+    let created = Utc::now().timestamp() - 100;
+
+    let inserted = hset_nx_indexed(
+        &mut redis_conn,
+        hash_name,
+        &id,
+        captcha_fields(created),
+        created,
+    )
+    .await
+    .unwrap();
+    assert!(inserted);
+    let inserted = hset_nx_indexed(
+        &mut redis_conn,
+        hash_name,
+        &id,
+        captcha_fields(created + 100),
+        created + 100,
+    )
+    .await
+    .unwrap();
+    assert!(!inserted);
+
+    let score: Option<i64> = redis_conn
+        .zscore(expiry_index_name(hash_name), &id)
+        .await
+        .unwrap();
+    assert_eq!(score, Some(created));
+}
+
+#[tokio::test]
+async fn unindexed_fields_are_only_scanned_until_a_pass_completes() {
+    let hash_name = "expiry_index_legacy_fields";
+    let mut redis_conn = redis_conn(hash_name).await;
+    let index_name = expiry_index_name(hash_name);
+    let now = Utc::now().timestamp();
+    let legacy = CaptchaID::from_uuid(Uuid::new_v4());
+    redis_conn
+        .hset::<_, _, _, ()>(hash_name, &legacy, captcha_fields(now))
+        .await
+        .unwrap();
+
+    run_task1_pass(&redis_conn, hash_name).await;
+    let score: Option<i64> = redis_conn.zscore(&index_name, &legacy).await.unwrap();
+    assert_eq!(score, Some(now));
+
+    // Fields are all indexed on insertion from then on, the hash isn't scanned anymore
+    let unindexed = CaptchaID::from_uuid(Uuid::new_v4());
+    redis_conn
+        .hset::<_, _, _, ()>(hash_name, &unindexed, captcha_fields(now))
+        .await
+        .unwrap();
+    run_task1_pass(&redis_conn, hash_name).await;
+    let score: Option<i64> = redis_conn.zscore(&index_name, &unindexed).await.unwrap();
+    assert_eq!(score, None);
+}
+
+/// Compare a cleanup pass on a hash of 1M fields, 1% of them expired, between the former
+/// HSCAN based sweep (scan and decode every field) and the expiry index.
+/// Run with `cargo test --test tasks -- --ignored --nocapture`.
+#[tokio::test]
+#[ignore = "benchmark, inserts 1M fields in redis"]
+async fn expiry_index_outperforms_hscan_at_1m_entries() {
+    let settings = Settings::new("test").unwrap();
+    let mut redis_conn = Client::open(&*settings.redis.url)
+        .unwrap()
+        .get_multiplexed_tokio_connection()
+        .await
+        .unwrap();
+
+    populate(&mut redis_conn).await;
+    let start = Instant::now();
+    let removed = hscan_sweep(&mut redis_conn).await;
+    let hscan_duration = start.elapsed();
+    assert_eq!(removed, EXPIRED_ENTRIES);
+
+    populate(&mut redis_conn).await;
+    let start = Instant::now();
+    let removed = remove_expired_fields::<_, CaptchaID>(
+        &mut redis_conn,
+        HASH_NAME,
+        EXPIRY_TIME as u64,
+        BULK_COUNT,
+        &CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+    let index_duration = start.elapsed();
+    assert_eq!(removed, EXPIRED_ENTRIES);
+
+    println!("hscan sweep: {hscan_duration:?}, expiry index: {index_duration:?}");
+    assert!(index_duration * 10 < hscan_duration);
+    clear(&mut redis_conn).await;
+}
+
+async fn clear(redis_conn: &mut MultiplexedConnection) {
+    cmd("DEL")
+        .arg(HASH_NAME)
+        .arg(expiry_index_name(HASH_NAME))
+        .query_async::<_, ()>(redis_conn)
+        .await
+        .unwrap();
+}
+
+async fn populate(redis_conn: &mut MultiplexedConnection) {
+    clear(redis_conn).await;
+
+    let now = Utc::now().timestamp();
+    let index_name = expiry_index_name(HASH_NAME);
+    for chunk_start in (0..ENTRIES).step_by(10_000) {
+        let mut p = pipe();
+        for i in chunk_start..(chunk_start + 10_000).min(ENTRIES) {
+            let timestamp = if i < EXPIRED_ENTRIES {
+                now - 2 * EXPIRY_TIME
+            } else {
+                now
+            };
+            let fields = CaptchaFields::json_string(
+                CaptchaAnswer::from_str("abcd".into()),
+                timestamp,
+                "127.0.0.1",
+            )
+            .unwrap();
+            let id = CaptchaID::from_uuid(Uuid::new_v4());
+            p.hset(HASH_NAME, &id, fields)
+                .ignore()
+                .zadd(&index_name, &id, timestamp)
+                .ignore();
+        }
+        p.query_async::<_, ()>(redis_conn).await.unwrap();
+    }
+}
+
+/// Former sweep: every field is scanned and decoded to find the expired ones
+async fn hscan_sweep(redis_conn: &mut MultiplexedConnection) -> usize {
+    let max_timestamp = Utc::now().timestamp() - EXPIRY_TIME;
+    let mut expired = Vec::new();
+    let mut scan_conn = redis_conn.clone();
+    {
+        let mut iter: AsyncIter<(CaptchaID, CaptchaFields)> =
+            scan_conn.hscan(HASH_NAME).await.unwrap();
+        while let Some((id, fields)) = iter.next_item().await {
+            if fields.get_timestamp() <= max_timestamp {
+                expired.push(id);
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for ids in expired.chunks(BULK_COUNT) {
+        removed += redis_conn
+            .hdel::<_, _, usize>(HASH_NAME, ids)
+            .await
+            .unwrap();
+    }
+
+    removed
+}
@@ -0,0 +1,179 @@
+use auth::config::Settings;
+use auth::tasks::{LeaderElection, RestartPolicy, TaskSupervisor};
+use deadpool_redis::redis::{cmd, Client};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+const LEASE_TTL: Duration = Duration::from_millis(600);
+const RENEW_INTERVAL: Duration = Duration::from_millis(150);
+
+/// Counts the instances currently running the background tasks
+#[derive(Clone, Default)]
+struct Leaders(Arc<AtomicU32>);
+
+impl Leaders {
+    fn count(&self) -> u32 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+fn redis_client() -> Client {
+    let settings = Settings::new("test").unwrap();
+    Client::open(&*settings.redis.url).unwrap()
+}
+
+async fn clear_lease(lease_key: &str) {
+    let mut conn = redis_client().get_async_connection().await.unwrap();
+    cmd("DEL")
+        .arg(lease_key)
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .unwrap();
+}
+
+fn election(lease_key: &str, instance_id: &str) -> LeaderElection {
+    LeaderElection::new(
+        redis_client(),
+        lease_key,
+        instance_id.into(),
+        LEASE_TTL,
+        RENEW_INTERVAL,
+    )
+    .unwrap()
+}
+
+fn no_restart() -> RestartPolicy {
+    RestartPolicy {
+        max_restarts: 0,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    }
+}
+
+/// Simulate an instance running its background tasks as leader
+fn start_instance(
+    lease_key: &str,
+    instance_id: &str,
+    leaders: &Leaders,
+    shutdown: &CancellationToken,
+) -> JoinHandle<()> {
+    let election = election(lease_key, instance_id);
+    let (leaders, shutdown) = (leaders.clone(), shutdown.clone());
+    tokio::spawn(async move {
+        election
+            .run(shutdown.clone(), move || {
+                let mut supervisor = TaskSupervisor::new(&shutdown);
+                let leaders = leaders.clone();
+                supervisor.spawn("task", no_restart(), move |shutdown| {
+                    let leaders = leaders.clone();
+                    async move {
+                        leaders.0.fetch_add(1, Ordering::SeqCst);
+                        shutdown.cancelled().await;
+                        leaders.0.fetch_sub(1, Ordering::SeqCst);
+                        Ok(())
+                    }
+                });
+                supervisor
+            })
+            .await
+            .unwrap();
+    })
+}
+
+#[tokio::test]
+async fn only_one_instance_runs_the_background_tasks() {
+    let lease_key = "leader_election_test_single_leader";
+    clear_lease(lease_key).await;
+    let leaders = Leaders::default();
+
+    let shutdown = CancellationToken::new();
+    let instance1 = start_instance(lease_key, "instance1", &leaders, &shutdown);
+    let instance2 = start_instance(lease_key, "instance2", &leaders, &shutdown);
+
+    // Several renewals happen in the meantime
+    for _ in 0..20 {
+        sleep(RENEW_INTERVAL / 2).await;
+        assert!(leaders.count() <= 1);
+    }
+    assert_eq!(leaders.count(), 1);
+
+    instance1.abort();
+    instance2.abort();
+}
+
+#[tokio::test]
+async fn another_instance_takes_over_when_the_leader_dies() {
+    let lease_key = "leader_election_test_takeover";
+    clear_lease(lease_key).await;
+    let leaders = Leaders::default();
+
+    let mut leader = election(lease_key, "instance1");
+    assert!(leader.try_acquire().await.unwrap());
+    let follower = start_instance(lease_key, "instance2", &leaders, &CancellationToken::new());
+
+    // The follower waits while the lease is renewed
+    for _ in 0..4 {
+        sleep(RENEW_INTERVAL).await;
+        assert!(leader.renew().await.unwrap());
+        assert_eq!(leaders.count(), 0);
+    }
+
+    // The leader dies without releasing the lease: the follower takes over once it expires
+    drop(leader);
+    sleep(LEASE_TTL + RENEW_INTERVAL * 2).await;
+    assert_eq!(leaders.count(), 1);
+
+    // The former leader can't renew a lease it lost
+    let mut former_leader = election(lease_key, "instance1");
+    assert!(!former_leader.renew().await.unwrap());
+    assert!(!former_leader.try_acquire().await.unwrap());
+
+    follower.abort();
+}
+
+#[tokio::test]
+async fn released_lease_is_taken_over_right_away() {
+    let lease_key = "leader_election_test_release";
+    clear_lease(lease_key).await;
+
+    let mut instance1 = election(lease_key, "instance1");
+    let mut instance2 = election(lease_key, "instance2");
+    assert!(instance1.try_acquire().await.unwrap());
+    assert!(!instance2.try_acquire().await.unwrap());
+    assert!(!instance2.release().await.unwrap());
+
+    assert!(instance1.release().await.unwrap());
+    assert!(instance2.try_acquire().await.unwrap());
+    assert!(!instance1.renew().await.unwrap());
+}
+
+#[tokio::test]
+async fn leader_hands_over_on_shutdown() {
+    let lease_key = "leader_election_test_shutdown";
+    clear_lease(lease_key).await;
+    let leaders = Leaders::default();
+
+    let shutdown1 = CancellationToken::new();
+    let instance1 = start_instance(lease_key, "instance1", &leaders, &shutdown1);
+    sleep(RENEW_INTERVAL).await;
+    let shutdown2 = CancellationToken::new();
+    let instance2 = start_instance(lease_key, "instance2", &leaders, &shutdown2);
+    sleep(RENEW_INTERVAL * 2).await;
+    assert_eq!(leaders.count(), 1);
+
+    // Returns once its tasks stopped and the lease is released
+    shutdown1.cancel();
+    instance1.await.unwrap();
+
+    // Taken over before the lease would have expired
+    sleep(RENEW_INTERVAL * 2).await;
+    assert_eq!(leaders.count(), 1);
+
+    shutdown2.cancel();
+    instance2.await.unwrap();
+    assert_eq!(leaders.count(), 0);
+}
@@ -1,2 +1,5 @@
+mod expiry_index;
+mod leader_election;
+mod supervisor;
 mod task1;
 mod utils;
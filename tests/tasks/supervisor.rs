@@ -0,0 +1,122 @@
+use anyhow::bail;
+use auth::config::ShutdownSettings;
+use auth::shutdown::ShutdownCoordinator;
+use auth::tasks::{RestartPolicy, TaskHealth, TaskSupervisor};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+fn policy(max_restarts: u32) -> RestartPolicy {
+    RestartPolicy {
+        max_restarts,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(100),
+    }
+}
+
+#[tokio::test]
+async fn failing_task_is_restarted_until_it_succeeds() {
+    let mut supervisor = TaskSupervisor::new(&CancellationToken::new());
+    let health = supervisor.health();
+    let runs = Arc::new(AtomicU32::new(0));
+    let runs_c = runs.clone();
+    supervisor.spawn("flaky", policy(5), move |_| {
+        let runs = runs_c.clone();
+        async move {
+            match runs.fetch_add(1, Ordering::SeqCst) {
+                0 => bail!("transient error"),
+                1 => panic!("transient panic"),
+                _ => Ok(()),
+            }
+        }
+    });
+    // Another task failing doesn't affect it
+    supervisor.spawn("failing", policy(0), |_| async { bail!("permanent error") });
+
+    supervisor.join().await;
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+    assert_eq!(health.get("flaky"), Some(TaskHealth::Stopped));
+    assert_eq!(
+        health.get("failing"),
+        Some(TaskHealth::Failed {
+            last_error: "permanent error".into()
+        })
+    );
+}
+
+#[tokio::test]
+async fn task_is_given_up_after_max_restarts() {
+    let mut supervisor = TaskSupervisor::new(&CancellationToken::new());
+    let health = supervisor.health();
+    let runs = Arc::new(AtomicU32::new(0));
+    let runs_c = runs.clone();
+    supervisor.spawn("failing", policy(3), move |_| {
+        let runs = runs_c.clone();
+        async move {
+            runs.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            bail!("error")
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(15)).await;
+    assert!(matches!(
+        health.get("failing"),
+        Some(TaskHealth::Restarting { restarts: 1, .. })
+    ));
+
+    supervisor.join().await;
+    // First run + 3 restarts
+    assert_eq!(runs.load(Ordering::SeqCst), 4);
+    assert!(matches!(
+        health.get("failing"),
+        Some(TaskHealth::Failed { .. })
+    ));
+}
+
+#[tokio::test]
+async fn tasks_finish_their_current_batch_on_shutdown() {
+    let shutdown = CancellationToken::new();
+    let mut supervisor = TaskSupervisor::new(&shutdown);
+    let health = supervisor.health();
+    let batches = Arc::new(AtomicU32::new(0));
+    let batches_c = batches.clone();
+    supervisor.spawn("batches", policy(3), move |shutdown| {
+        let batches = batches_c.clone();
+        async move {
+            while !shutdown.is_cancelled() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                batches.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+    });
+    // Restarting tasks are not restarted anymore
+    supervisor.spawn("failing", policy(3), |_| async { bail!("error") });
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    shutdown.cancel();
+    supervisor.join().await;
+
+    // The batch in progress when the shutdown started has been completed
+    assert_eq!(batches.load(Ordering::SeqCst), 1);
+    assert_eq!(health.get("batches"), Some(TaskHealth::Stopped));
+    assert_eq!(health.get("failing"), Some(TaskHealth::Stopped));
+}
+
+#[tokio::test]
+async fn drain_deadline_is_set_when_the_shutdown_starts() {
+    let settings = ShutdownSettings { drain_timeout: 1 };
+    let coordinator = ShutdownCoordinator::new(&settings);
+    coordinator.shutdown();
+    let deadline = coordinator.deadline();
+
+    // Phases draining one after the other share the same deadline
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    coordinator.shutdown();
+    assert_eq!(coordinator.deadline(), deadline);
+    assert!(coordinator.token().is_cancelled());
+    assert!(deadline <= Instant::now() + coordinator.drain_timeout());
+}
@@ -1,17 +1,28 @@
 use crate::utils::start_task1;
-use auth::logic::{CaptchaAnswer, CaptchaFields, CaptchaID, ConfirmEmail, Email, URLToken};
+use auth::config::Settings;
+use auth::logic::{
+    CaptchaAnswer, CaptchaFields, CaptchaID, ConfirmEmail, Email, HashedToken, TokenHashKey,
+    TokenPurpose, URLToken,
+};
+use auth::server::start_redis_fields_deletion_task;
+use auth::tasks::{expiry_index_complete_name, expiry_index_name};
 use chrono::Utc;
-use deadpool_redis::redis::{Commands, ToRedisArgs};
+use deadpool_redis::redis::{AsyncCommands, Client, Commands, ToRedisArgs};
 use fake::faker::internet::en::SafeEmail;
 use fake::Fake;
+use secrecy::Secret;
 use std::thread::sleep;
 use std::time::Duration;
 use tokio::sync::oneshot;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 #[test]
 fn email_confirmation_entries_are_successfully_removed_from_redis() {
-    redis_fields_deletion::<ConfirmEmailEntry>("email");
+    for purpose in TokenPurpose::ALL {
+        redis_fields_deletion::<ConfirmEmailEntry>(purpose.hash_name());
+    }
 }
 
 #[test]
@@ -30,7 +41,7 @@ where
     let insertion_cnt = cfg.deletion_bulk_count * 3 - 1;
     let mut entries = Vec::with_capacity(insertion_cnt);
     for _ in 0..insertion_cnt {
-        let (id, fields) = G::generate_random_entry();
+        let (id, fields) = G::generate_random_entry(hash_name);
         entries.push((id, fields));
     }
 
@@ -40,6 +51,16 @@ where
     let inserted_cnt: usize = cfg.redis_conn.hlen(hash_name).unwrap();
     assert_eq!(inserted_cnt, insertion_cnt);
 
+    // Only index half of the entries, the others are indexed by the task on startup like
+    // fields stored before the expiry index existed
+    let index_name = expiry_index_name(hash_name);
+    let timestamp = Utc::now().timestamp();
+    for (id, _) in entries.iter().step_by(2) {
+        cfg.redis_conn
+            .zadd::<_, _, _, ()>(&index_name, id, timestamp)
+            .unwrap();
+    }
+
     // Test that entries have been inserted. We then send a signal to start task1 (deletion task)
     tx.send(()).unwrap();
     sleep(Duration::from_secs(cfg.expiry_time) * 3);
@@ -48,23 +69,32 @@ where
     // 0 entry should then be left in redis.
     let entries_cnt: usize = cfg.redis_conn.hlen(hash_name).unwrap();
     assert_eq!(entries_cnt, 0);
+    let indexed_cnt: usize = cfg.redis_conn.zcard(&index_name).unwrap();
+    assert_eq!(indexed_cnt, 0);
+    let complete: bool = cfg
+        .redis_conn
+        .exists(expiry_index_complete_name(hash_name))
+        .unwrap();
+    assert!(complete);
 }
 
 trait RedisEntryGenerator {
     type Key: ToRedisArgs;
 
-    fn generate_random_entry() -> (Self::Key, String);
+    fn generate_random_entry(hash_name: &str) -> (Self::Key, String);
 }
 
 struct ConfirmEmailEntry;
 impl RedisEntryGenerator for ConfirmEmailEntry {
-    type Key = URLToken;
+    type Key = HashedToken;
 
-    fn generate_random_entry() -> (Self::Key, String) {
+    fn generate_random_entry(hash_name: &str) -> (Self::Key, String) {
         let timestamp = Utc::now().timestamp();
+        let purpose = TokenPurpose::from_hash_name(hash_name).unwrap();
         let email = Email::parse(SafeEmail().fake()).unwrap();
-        let fields = ConfirmEmail::json_string(email, timestamp).unwrap();
-        let token = URLToken::generate();
+        let fields = ConfirmEmail::json_string(purpose, email, timestamp).unwrap();
+        let hash_key = TokenHashKey::new(Secret::new("0".repeat(32))).unwrap();
+        let token = hash_key.hash(URLToken::generate().as_str());
         (token, fields)
     }
 }
@@ -73,12 +103,53 @@ struct CaptchaEntry;
 impl RedisEntryGenerator for CaptchaEntry {
     type Key = CaptchaID;
 
-    fn generate_random_entry() -> (Self::Key, String) {
+    fn generate_random_entry(_hash_name: &str) -> (Self::Key, String) {
         let timestamp = Utc::now().timestamp();
-        let captcha_answer = CaptchaAnswer::parse((4..=6).fake()).unwrap();
-        let fields = CaptchaFields::json_string(captcha_answer, timestamp).unwrap();
+        let captcha_answer = CaptchaAnswer::from_str((4..=6).fake());
+        let fields = CaptchaFields::json_string(captcha_answer, timestamp, "127.0.0.1").unwrap();
 
         let id = CaptchaID::from_uuid(Uuid::new_v4());
         (id, fields)
     }
 }
+
+#[tokio::test]
+async fn legacy_token_hash_is_swept_until_empty() {
+    let settings = Settings::new("test").unwrap();
+    let hash_name = TokenPurpose::LEGACY_HASH_NAME;
+    let mut redis_conn = Client::open(&*settings.redis.url)
+        .unwrap()
+        .get_multiplexed_tokio_connection()
+        .await
+        .unwrap();
+
+    // Tokens stored before they had a purpose, all expired
+    let timestamp = Utc::now().timestamp() - 100_000;
+    for _ in 0..3 {
+        let fields = serde_json::json!({
+            "email": SafeEmail().fake::<String>(),
+            "timestamp": timestamp,
+        });
+        redis_conn
+            .hset::<_, _, _, ()>(hash_name, URLToken::generate().as_str(), fields.to_string())
+            .await
+            .unwrap();
+    }
+
+    // The task stops by itself once the hash is empty
+    timeout(
+        Duration::from_secs(10),
+        start_redis_fields_deletion_task(settings, hash_name, CancellationToken::new()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    let exists: bool = redis_conn.exists(hash_name).await.unwrap();
+    assert!(!exists);
+    let indexed: bool = redis_conn
+        .exists(expiry_index_name(hash_name))
+        .await
+        .unwrap();
+    assert!(!indexed);
+}
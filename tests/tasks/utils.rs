@@ -1,9 +1,12 @@
 use auth::config::Settings;
-use auth::server::start_fields_deletion_task;
+use auth::logic::TokenPurpose;
+use auth::server::start_redis_fields_deletion_task;
+use auth::tasks::{expiry_index_complete_name, expiry_index_name};
 use deadpool_redis::redis;
 use deadpool_redis::redis::{Client, Connection};
 use tokio::runtime::Runtime;
 use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
 
 pub struct Task1TestSettings {
     pub redis_conn: Connection,
@@ -14,17 +17,15 @@ pub struct Task1TestSettings {
 impl Task1TestSettings {
     fn get_settings_from_hash(settings: &Settings, hash_name: &str) -> Self {
         let client = Client::open(&*settings.redis.url).unwrap();
-        let (expiry_time, deletion_bulk_count) = match hash_name {
-            "email" => (
-                settings.task1_email_confirm.expiry_time,
-                settings.task1_email_confirm.deletion_bulk_count,
-            ),
-            "captcha" => (
-                settings.task1_captcha.expiry_time,
-                settings.task1_captcha.deletion_bulk_count,
-            ),
-            _ => panic!("invalid hash name"),
+        let task1_settings = match TokenPurpose::from_hash_name(hash_name) {
+            Some(purpose) => purpose.task1_settings(settings),
+            None if hash_name == "captcha" => &settings.task1_captcha,
+            None => panic!("invalid hash name"),
         };
+        let (expiry_time, deletion_bulk_count) = (
+            task1_settings.expiry_time,
+            task1_settings.deletion_bulk_count,
+        );
 
         Self {
             redis_conn: client.get_connection().unwrap(),
@@ -35,16 +36,20 @@ impl Task1TestSettings {
 }
 
 pub fn start_task1(rx: oneshot::Receiver<()>, hash_name: String) -> (Task1TestSettings, Runtime) {
-    let settings = Settings::new("config/test").unwrap();
+    let settings = Settings::new("test").unwrap();
     let mut test_utils = Task1TestSettings::get_settings_from_hash(&settings, &hash_name);
-    let _: bool = redis::cmd("FLUSHDB")
+    // Only clear the tested hash so that tests can run concurrently
+    let _: usize = redis::cmd("DEL")
+        .arg(&hash_name)
+        .arg(expiry_index_name(&hash_name))
+        .arg(expiry_index_complete_name(&hash_name))
         .query(&mut test_utils.redis_conn)
         .unwrap();
 
     let rt = Runtime::new().unwrap();
     rt.spawn(async move {
         rx.await.unwrap();
-        start_fields_deletion_task(settings, &hash_name)
+        start_redis_fields_deletion_task(settings, &hash_name, CancellationToken::new())
             .await
             .unwrap();
     });
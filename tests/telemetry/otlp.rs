@@ -0,0 +1,109 @@
+use auth::config::Settings;
+use auth::server::{start_server, ServerSetup};
+use auth::shutdown::ShutdownCoordinator;
+use auth::tasks::SupervisorHealth;
+use auth::telemetry::{init_tracing, shutdown_tracing};
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+    TraceService, TraceServiceServer,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::{
+    ExportTraceServiceRequest, ExportTraceServiceResponse,
+};
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::trace::v1::ResourceSpans;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{Request, Response, Status};
+use tracing::level_filters::LevelFilter;
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const PARENT_SPAN_ID: &str = "00f067aa0ba902b7";
+
+/// OTLP/gRPC collector keeping the exported spans
+#[derive(Clone, Default)]
+struct CollectorStub {
+    exported: Arc<Mutex<Vec<ResourceSpans>>>,
+}
+
+#[tonic::async_trait]
+impl TraceService for CollectorStub {
+    async fn export(
+        &self,
+        request: Request<ExportTraceServiceRequest>,
+    ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+        let mut exported = self.exported.lock().unwrap();
+        exported.extend(request.into_inner().resource_spans);
+        Ok(Response::new(ExportTraceServiceResponse::default()))
+    }
+}
+
+async fn start_collector(collector: CollectorStub) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(TraceServiceServer::new(collector))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    endpoint
+}
+
+#[tokio::test]
+async fn request_spans_are_exported_in_the_incoming_trace() {
+    let collector = CollectorStub::default();
+    let mut settings = Settings::new("test").unwrap();
+    settings.tracing.otlp_endpoint = Some(start_collector(collector.clone()).await);
+    init_tracing(LevelFilter::OFF, &settings.tracing).unwrap();
+
+    std::thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            let setup = ServerSetup::new(&settings).await.unwrap();
+            let shutdown = ShutdownCoordinator::new(&settings.shutdown);
+            start_server(settings, setup, shutdown, SupervisorHealth::default())
+                .await
+                .unwrap();
+        })
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let res = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get("https://127.0.0.1:8443/home")
+        .header("traceparent", format!("00-{TRACE_ID}-{PARENT_SPAN_ID}-01"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 303);
+    shutdown_tracing().await;
+
+    let exported = collector.exported.lock().unwrap();
+    let resource = exported[0].resource.as_ref().unwrap();
+    let service_name = resource
+        .attributes
+        .iter()
+        .find(|attr| attr.key == "service.name")
+        .and_then(|attr| attr.value.clone()?.value);
+    assert_eq!(service_name, Some(Value::StringValue("auth".into())));
+
+    let spans: Vec<_> = exported
+        .iter()
+        .flat_map(|resource_spans| &resource_spans.scope_spans)
+        .flat_map(|scope_spans| &scope_spans.spans)
+        .filter(|span| hex::encode(&span.trace_id) == TRACE_ID)
+        .collect();
+    let root = spans
+        .iter()
+        .find(|span| span.name == "HTTP GET /home")
+        .expect("request span exported");
+    assert_eq!(hex::encode(&root.parent_span_id), PARENT_SPAN_ID);
+    let handler = spans
+        .iter()
+        .find(|span| span.name == "get_home_page")
+        .expect("handler span exported");
+    assert_eq!(handler.parent_span_id, root.span_id);
+}
@@ -0,0 +1,165 @@
+use auth::config::{ClientAuth, TlsSettings};
+use auth::tls::{load_certified_key, server_config, CertificateReloader};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig,
+    ServerConnection, ServerName,
+};
+use rustls_pemfile::Item;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+const FIXTURES: &str = "tests/tls/fixtures";
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(FIXTURES).join(name)
+}
+
+fn settings(dir: &Path) -> TlsSettings {
+    TlsSettings {
+        enabled: true,
+        redirect_port: None,
+        hsts_max_age: 0,
+        cert_path: dir.join("cert.pem").to_string_lossy().into(),
+        key_path: dir.join("key.pem").to_string_lossy().into(),
+        watch_interval: 1,
+        client_auth: ClientAuth::None,
+        client_ca_path: None,
+    }
+}
+
+fn install(dir: &Path, cert: &str, key: &str) {
+    fs::copy(fixture(cert), dir.join("cert.pem")).unwrap();
+    fs::copy(fixture(key), dir.join("key.pem")).unwrap();
+}
+
+#[test]
+fn pkcs8_rsa_and_sec1_keys_are_loaded() {
+    load_certified_key(Path::new("tls/cert.pem"), Path::new("tls/key.pem")).unwrap();
+    load_certified_key(&fixture("rsa_cert.pem"), &fixture("rsa_key.pem")).unwrap();
+    load_certified_key(&fixture("server_cert.pem"), &fixture("server_key.pem")).unwrap();
+
+    assert!(load_certified_key(&fixture("server_cert.pem"), &fixture("server_cert.pem")).is_err());
+}
+
+#[test]
+fn key_of_another_certificate_is_rejected() {
+    assert!(load_certified_key(&fixture("server_cert.pem"), &fixture("rsa_key.pem")).is_err());
+    assert!(load_certified_key(&fixture("rsa_cert.pem"), &fixture("server_key.pem")).is_err());
+    assert!(load_certified_key(&fixture("server_cert.pem"), &fixture("client_key.pem")).is_err());
+}
+
+#[tokio::test]
+async fn certificate_is_reloaded_when_its_files_change() {
+    let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    fs::create_dir(&dir).unwrap();
+    install(&dir, "server_cert.pem", "server_key.pem");
+    let settings = settings(&dir);
+    let reloader = Arc::new(CertificateReloader::new(&settings).unwrap());
+    let shutdown = CancellationToken::new();
+    let watch = tokio::spawn(reloader.clone().watch(shutdown.clone()));
+
+    let rsa_cert = load_certified_key(&fixture("rsa_cert.pem"), &fixture("rsa_key.pem"))
+        .unwrap()
+        .cert;
+    assert_ne!(reloader.certificate().cert, rsa_cert);
+
+    install(&dir, "rsa_cert.pem", "rsa_key.pem");
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(reloader.certificate().cert, rsa_cert);
+
+    // Broken files are reported, the previous certificate is still served
+    fs::write(dir.join("key.pem"), "not a key").unwrap();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(reloader.certificate().cert, rsa_cert);
+
+    // So is a certificate replaced without its key
+    fs::copy(fixture("server_cert.pem"), dir.join("cert.pem")).unwrap();
+    fs::copy(fixture("rsa_key.pem"), dir.join("key.pem")).unwrap();
+    assert!(reloader.reload().is_err());
+    assert_eq!(reloader.certificate().cert, rsa_cert);
+
+    shutdown.cancel();
+    watch.await.unwrap().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Run the handshake in memory, fails with the first error of either side
+fn handshake(server: ServerConfig, client: ClientConfig) -> Result<(), rustls::Error> {
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut client = ClientConnection::new(Arc::new(client), server_name)?;
+    let mut server = ServerConnection::new(Arc::new(server))?;
+
+    for _ in 0..10 {
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+        server.read_tls(&mut buf.as_slice()).unwrap();
+        server.process_new_packets()?;
+
+        let mut buf = Vec::new();
+        server.write_tls(&mut buf).unwrap();
+        client.read_tls(&mut buf.as_slice()).unwrap();
+        client.process_new_packets()?;
+
+        if !client.is_handshaking() && !server.is_handshaking() {
+            return Ok(());
+        }
+    }
+    panic!("the handshake didn't complete");
+}
+
+fn read_pem(name: &str) -> Vec<Item> {
+    let pem = fs::read(fixture(name)).unwrap();
+    rustls_pemfile::read_all(&mut pem.as_slice()).unwrap()
+}
+
+fn client_config(with_cert: bool) -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    for item in read_pem("ca_cert.pem") {
+        if let Item::X509Certificate(cert) = item {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+    }
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    if !with_cert {
+        return builder.with_no_client_auth();
+    }
+
+    let client = load_certified_key(&fixture("client_cert.pem"), &fixture("client_key.pem"));
+    let Some(Item::ECKey(key)) = read_pem("client_key.pem").pop() else {
+        panic!("client_key.pem must hold a SEC1 key");
+    };
+    builder
+        .with_client_auth_cert(client.unwrap().cert, PrivateKey(key))
+        .unwrap()
+}
+
+#[test]
+fn client_certificates_are_verified_against_the_ca_bundle() {
+    let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    fs::create_dir(&dir).unwrap();
+    install(&dir, "server_cert.pem", "server_key.pem");
+    let mut settings = settings(&dir);
+    let reloader = Arc::new(CertificateReloader::new(&settings).unwrap());
+    let server = |settings: &TlsSettings| server_config(settings, reloader.clone());
+
+    handshake(server(&settings).unwrap(), client_config(false)).unwrap();
+
+    settings.client_auth = ClientAuth::Required;
+    assert!(server(&settings).is_err());
+
+    settings.client_ca_path = Some(fixture("ca_cert.pem").to_string_lossy().into());
+    handshake(server(&settings).unwrap(), client_config(true)).unwrap();
+    assert!(handshake(server(&settings).unwrap(), client_config(false)).is_err());
+
+    settings.client_auth = ClientAuth::Optional;
+    handshake(server(&settings).unwrap(), client_config(true)).unwrap();
+    handshake(server(&settings).unwrap(), client_config(false)).unwrap();
+
+    fs::remove_dir_all(dir).unwrap();
+}